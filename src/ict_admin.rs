@@ -0,0 +1,358 @@
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Instant;
+use uuid::Uuid;
+
+use crate::ict_db::{Db, DeviceStatus};
+use crate::ict_errors::ICTError;
+use crate::ict_inputs::{self, InputState};
+use crate::ict_operations::{
+    add_group, add_group_member, add_schedule, associate_relay, authorize_between, clear_quota, clear_relays,
+    delete_device, delete_group, describe_client, grant_group_relay, list_clients, remove_group_member,
+    remove_schedule, review_client, revoke, revoke_group_relay, set_client_info, set_quota, suspend, ClientFilter,
+    ReviewDecision,
+};
+use crate::ict_relays;
+
+// One JSON request per line on the control socket, answered by one JSON response line
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "kebab-case")]
+pub enum AdminRequest {
+    Authorize {
+        uuid: String,
+        from: Option<String>,
+        until: Option<String>,
+        reason: Option<String>,
+    },
+    Suspend {
+        uuid: String,
+        reason: Option<String>,
+    },
+    Revoke {
+        uuid: String,
+        reason: Option<String>,
+    },
+    Delete {
+        uuid: String,
+    },
+    AssociateRelay {
+        uuid: String,
+        relay: u8,
+    },
+    SetClientInfo {
+        uuid: String,
+        name: Option<String>,
+        owner: Option<String>,
+        notes: Option<String>,
+    },
+    ClearRelays {
+        uuid: String,
+    },
+    AddSchedule {
+        uuid: String,
+        relay: Option<u8>,
+        days: String,
+        from: String,
+        until: String,
+        timezone: String,
+    },
+    RemoveSchedule {
+        id: i64,
+    },
+    SetQuota {
+        uuid: String,
+        per_hour: Option<u32>,
+        per_day: Option<u32>,
+        total: Option<u32>,
+        min_interval: Option<u64>,
+    },
+    ClearQuota {
+        uuid: String,
+    },
+    AddGroup {
+        name: String,
+    },
+    DeleteGroup {
+        name: String,
+    },
+    AddGroupMember {
+        name: String,
+        uuid: String,
+    },
+    RemoveGroupMember {
+        name: String,
+        uuid: String,
+    },
+    GrantGroupRelay {
+        name: String,
+        relay: u8,
+    },
+    RevokeGroupRelay {
+        name: String,
+        relay: u8,
+    },
+    ListClients {
+        status: Option<DeviceStatus>,
+        relay: Option<u8>,
+    },
+    DescribeClient {
+        uuid: String,
+    },
+    Review {
+        uuid: String,
+        decision: ReviewDecision,
+    },
+    Snapshot {
+        path: PathBuf,
+    },
+    Status,
+    Lockout {
+        on: bool,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AdminResponse {
+    // same meaning as the exit code of the command line
+    pub exit_code: i32,
+    pub error: Option<String>,
+    #[serde(default)]
+    pub data: Value,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ServerStatus {
+    pub pid: u32,
+    pub uptime: u64,
+    pub clients: u32,
+    #[serde(default)]
+    pub inputs: Vec<InputState>,
+    #[serde(default)]
+    pub locked_out: bool,
+}
+
+// Listens until the process exits, the socket file is removed when dropped
+pub struct AdminServer {
+    path: PathBuf,
+}
+
+impl Drop for AdminServer {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+// Binds the socket, readable and writable by the owner of the server only,
+// snapshots can only be written to backup_dir
+pub fn start(path: &Path, db_path: Option<String>, backup_dir: PathBuf) -> Result<AdminServer, ICTError> {
+    if path.exists() {
+        if UnixStream::connect(path).is_ok() {
+            return Err(ICTError::Custom(format!("A server already listens on {}", path.display())));
+        }
+        // left behind by a server that did not stop cleanly
+        std::fs::remove_file(path)?;
+    }
+    // the socket is created with the owner only permissions, it is never reachable with the umask ones
+    let umask = unsafe { libc::umask(0o177) };
+    let listener = UnixListener::bind(path);
+    unsafe { libc::umask(umask) };
+    let listener = listener?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    info!("Admin socket listening on {}", path.display());
+
+    let started = Instant::now();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let (db_path, backup_dir) = (db_path.clone(), backup_dir.clone());
+                    thread::spawn(move || serve_connection(stream, db_path, &backup_dir, started));
+                }
+                Err(e) => error!("Failed accepting admin connection with {}", e),
+            }
+        }
+    });
+    Ok(AdminServer { path: path.to_path_buf() })
+}
+
+fn serve_connection(stream: UnixStream, db_path: Option<String>, backup_dir: &Path, started: Instant) {
+    let mut writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(e) => {
+            error!("Failed admin connection with {}", e);
+            return;
+        }
+    };
+    for line in BufReader::new(stream).lines() {
+        let response = match line {
+            Ok(line) => respond(&line, db_path.clone(), backup_dir, started),
+            Err(e) => {
+                error!("Failed reading admin request with {}", e);
+                return;
+            }
+        };
+        let written = serde_json::to_string(&response)
+            .map_err(ICTError::from)
+            .and_then(|json| Ok(writeln!(writer, "{}", json)?));
+        if let Err(e) = written {
+            error!("Failed writing admin response with {}", e);
+            return;
+        }
+    }
+}
+
+fn respond(line: &str, db_path: Option<String>, backup_dir: &Path, started: Instant) -> AdminResponse {
+    let result = serde_json::from_str::<AdminRequest>(line)
+        .map_err(ICTError::from)
+        .and_then(|request| {
+            info!("Admin request {:?}", request);
+            let db = Db::newg(db_path)?;
+            handle(&db, request, backup_dir, started)
+        });
+    match result {
+        Ok(data) => AdminResponse { exit_code: 0, error: None, data },
+        Err(e) => {
+            error!("Failed admin request with {}", e);
+            AdminResponse { exit_code: e.exit_code(), error: Some(e.to_string()), data: Value::Null }
+        }
+    }
+}
+
+fn handle(db: &Db, request: AdminRequest, backup_dir: &Path, started: Instant) -> Result<Value, ICTError> {
+    match request {
+        AdminRequest::Authorize { uuid, from, until, reason } => {
+            authorize_between(db, &uuid, from.as_deref(), until.as_deref(), reason.as_deref())?;
+            Ok(Value::Null)
+        }
+        AdminRequest::Suspend { uuid, reason } => {
+            suspend(db, &uuid, reason.as_deref())?;
+            cancel_pulses(&uuid)
+        }
+        AdminRequest::Revoke { uuid, reason } => {
+            revoke(db, &uuid, reason.as_deref())?;
+            cancel_pulses(&uuid)
+        }
+        AdminRequest::Delete { uuid } => {
+            delete_device(db, &uuid)?;
+            cancel_pulses(&uuid)
+        }
+        AdminRequest::AssociateRelay { uuid, relay } => {
+            associate_relay(db, &uuid, &relay)?;
+            Ok(Value::Null)
+        }
+        AdminRequest::SetClientInfo { uuid, name, owner, notes } => {
+            set_client_info(db, &uuid, name.as_deref(), owner.as_deref(), notes.as_deref())?;
+            Ok(Value::Null)
+        }
+        AdminRequest::ClearRelays { uuid } => {
+            clear_relays(db, &uuid)?;
+            Ok(Value::Null)
+        }
+        AdminRequest::AddSchedule { uuid, relay, days, from, until, timezone } => {
+            let id = add_schedule(db, &uuid, relay, &days, &from, &until, &timezone)?;
+            Ok(serde_json::json!({ "id": id }))
+        }
+        AdminRequest::RemoveSchedule { id } => {
+            remove_schedule(db, id)?;
+            Ok(Value::Null)
+        }
+        AdminRequest::SetQuota { uuid, per_hour, per_day, total, min_interval } => {
+            set_quota(db, &uuid, per_hour, per_day, total, min_interval)?;
+            Ok(Value::Null)
+        }
+        AdminRequest::ClearQuota { uuid } => {
+            clear_quota(db, &uuid)?;
+            Ok(Value::Null)
+        }
+        AdminRequest::AddGroup { name } => {
+            add_group(db, &name)?;
+            Ok(Value::Null)
+        }
+        AdminRequest::DeleteGroup { name } => {
+            delete_group(db, &name)?;
+            Ok(Value::Null)
+        }
+        AdminRequest::AddGroupMember { name, uuid } => {
+            add_group_member(db, &name, &uuid)?;
+            Ok(Value::Null)
+        }
+        AdminRequest::RemoveGroupMember { name, uuid } => {
+            remove_group_member(db, &name, &uuid)?;
+            Ok(Value::Null)
+        }
+        AdminRequest::GrantGroupRelay { name, relay } => {
+            grant_group_relay(db, &name, &relay)?;
+            Ok(Value::Null)
+        }
+        AdminRequest::RevokeGroupRelay { name, relay } => {
+            revoke_group_relay(db, &name, &relay)?;
+            Ok(Value::Null)
+        }
+        AdminRequest::ListClients { status, relay } => {
+            Ok(serde_json::to_value(list_clients(db, &ClientFilter { status, relay })?)?)
+        }
+        AdminRequest::DescribeClient { uuid } => Ok(serde_json::to_value(describe_client(db, &uuid)?)?),
+        AdminRequest::Review { uuid, decision } => {
+            review_client(db, &uuid, &decision)?;
+            Ok(Value::Null)
+        }
+        AdminRequest::Snapshot { path } => {
+            db.snapshot(&backup_path(backup_dir, &path)?)?;
+            Ok(Value::Null)
+        }
+        AdminRequest::Status => Ok(serde_json::to_value(ServerStatus {
+            pid: std::process::id(),
+            uptime: started.elapsed().as_secs(),
+            clients: db.count_devices()?,
+            inputs: ict_inputs::monitor().states(),
+            locked_out: ict_inputs::monitor().locked_out(),
+        })?),
+        AdminRequest::Lockout { on } => {
+            ict_inputs::set_lockout(db, on)?;
+            Ok(serde_json::json!({ "locked_out": on }))
+        }
+    }
+}
+
+// The path of a snapshot requested through the socket, which must be in the backup directory
+fn backup_path(backup_dir: &Path, path: &Path) -> Result<PathBuf, ICTError> {
+    std::fs::DirBuilder::new().recursive(true).mode(0o700).create(backup_dir)?;
+    let backup_dir = backup_dir.canonicalize()?;
+    let outside = || ICTError::Custom(format!("{} is not in the backup directory {}", path.display(), backup_dir.display()));
+    let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+        return Err(outside());
+    };
+    let parent = parent.canonicalize().map_err(|_| outside())?;
+    if parent != backup_dir {
+        return Err(outside());
+    }
+    Ok(parent.join(name))
+}
+
+// A client that lost its authorization does not keep a relay open until the pulse ends
+fn cancel_pulses(uuid: &str) -> Result<Value, ICTError> {
+    let cancelled = ict_relays::controller().cancel(&Uuid::parse_str(uuid)?.to_string());
+    Ok(serde_json::json!({ "cancelled": cancelled }))
+}
+
+// The running server's socket, None when no server listens on it
+pub fn connect(path: &Path) -> Option<UnixStream> {
+    UnixStream::connect(path).ok()
+}
+
+pub fn send(stream: &UnixStream, request: &AdminRequest) -> Result<AdminResponse, ICTError> {
+    let mut writer = stream;
+    writeln!(writer, "{}", serde_json::to_string(request)?)?;
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    if line.is_empty() {
+        return Err(ICTError::Custom("The server closed the admin socket".to_string()));
+    }
+    Ok(serde_json::from_str(&line)?)
+}
@@ -1,4 +1,5 @@
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
+use ict_server::ict_db::DeviceStatus;
 
 #[derive(Parser, Debug)]
 #[command(name = "ict_server")]
@@ -24,16 +25,44 @@ pub enum Operation {
         uuid: String,
         #[arg(short, long, value_name = "PEM public key of client")]
         public_key: String,
+        #[arg(short, long, value_name = "display name of client")]
+        name: Option<String>,
     },
     #[command(about = "Authorize a previously registered client")]
     Authorize {
         #[arg(short, long, value_name = "UUID of client")]
         uuid: String,
+        #[arg(long, value_name = "start of authorization, RFC 3339 or local YYYY-MM-DD HH:MM")]
+        from: Option<String>,
+        #[arg(long, value_name = "end of authorization, RFC 3339 or local YYYY-MM-DD HH:MM")]
+        until: Option<String>,
+        #[arg(long, value_name = "reason recorded with the status change")]
+        reason: Option<String>,
     },
-    #[command(about = "Temporarily un-authorize a client (can be re-authorized)")]
-    Unauthorize {
+    #[command(about = "Suspend an authorized client (can be re-authorized)", visible_alias = "unauthorize")]
+    Suspend {
         #[arg(short, long, value_name = "UUID of client")]
         uuid: String,
+        #[arg(long, value_name = "reason recorded with the status change")]
+        reason: Option<String>,
+    },
+    #[command(about = "Permanently revoke a client, it has to register again to be authorized")]
+    Revoke {
+        #[arg(short, long, value_name = "UUID of client")]
+        uuid: String,
+        #[arg(long, value_name = "reason recorded with the status change")]
+        reason: Option<String>,
+    },
+    #[command(about = "Edits the name, owner and notes of a client, an empty value clears them")]
+    SetClientInfo {
+        #[arg(short, long, value_name = "UUID of client")]
+        uuid: String,
+        #[arg(short, long, value_name = "display name of client")]
+        name: Option<String>,
+        #[arg(long, value_name = "owner of client")]
+        owner: Option<String>,
+        #[arg(long, value_name = "free-form notes")]
+        notes: Option<String>,
     },
     #[command(about = "Permanently delete a client")]
     Delete {
@@ -50,12 +79,23 @@ pub enum Operation {
         signature: String,
     },
     #[command(about = "Lists all clients")]
-    ListClients,
+    ListClients {
+        #[arg(long, value_enum, default_value_t = OutputFormat::Table)]
+        format: OutputFormat,
+        #[arg(long, value_name = "only clients in this status", value_parser = parse_status)]
+        status: Option<DeviceStatus>,
+        #[arg(long, value_name = "only clients granted this relay")]
+        relay: Option<u8>,
+    },
     #[command(about = "Displays info and status of a client")]
     DescribeClient {
         #[arg(short, long, value_name = "UUID of client")]
         uuid: String,
+        #[arg(long, value_enum, default_value_t = OutputFormat::Table)]
+        format: OutputFormat,
     },
+    #[command(about = "Walks through pending registrations to approve, reject or skip them")]
+    Review,
     #[command(about = "Associates a relay with a client")]
     AssociateRelay {
         #[arg(short, long, value_name = "UUID of client")]
@@ -68,11 +108,165 @@ pub enum Operation {
         #[arg(short, long, value_name = "UUID of client")]
         uuid: String,
     },
+    #[command(about = "Restricts a client, or one of its relays, to a weekly time window")]
+    AddSchedule {
+        #[arg(short, long, value_name = "UUID of client")]
+        uuid: String,
+        #[arg(short, long, value_name = "id of relay, whole client if omitted")]
+        relay: Option<u8>,
+        #[arg(long, value_name = "days of the week, ie mon-fri or sat,sun")]
+        days: String,
+        #[arg(long, value_name = "start time HH:MM")]
+        from: String,
+        #[arg(long, value_name = "end time HH:MM")]
+        until: String,
+        #[arg(long, value_name = "timezone, ie Europe/Paris", default_value = "UTC")]
+        timezone: String,
+    },
+    #[command(about = "Lists schedules, of all clients or of one client")]
+    ListSchedules {
+        #[arg(short, long, value_name = "UUID of client")]
+        uuid: Option<String>,
+        #[arg(long, value_enum, default_value_t = OutputFormat::Table)]
+        format: OutputFormat,
+    },
+    #[command(about = "Removes a schedule")]
+    RemoveSchedule {
+        #[arg(long, value_name = "id of schedule")]
+        id: i64,
+    },
+    #[command(about = "Limits how often a client may operate, restarts the total count")]
+    SetQuota {
+        #[arg(short, long, value_name = "UUID of client")]
+        uuid: String,
+        #[arg(long, value_name = "maximum operations in the last hour")]
+        per_hour: Option<u32>,
+        #[arg(long, value_name = "maximum operations in the last 24 hours")]
+        per_day: Option<u32>,
+        #[arg(long, value_name = "maximum operations from now on")]
+        total: Option<u32>,
+        #[arg(long, value_name = "minimum seconds between operations")]
+        min_interval: Option<u64>,
+    },
+    #[command(about = "Removes the operation limits of a client")]
+    ClearQuota {
+        #[arg(short, long, value_name = "UUID of client")]
+        uuid: String,
+    },
+    #[command(about = "Creates a group of clients")]
+    AddGroup {
+        #[arg(short, long, value_name = "name of group")]
+        name: String,
+    },
+    #[command(about = "Deletes a group, its members keep their direct relays")]
+    DeleteGroup {
+        #[arg(short, long, value_name = "name of group")]
+        name: String,
+    },
+    #[command(about = "Lists groups with their relays and members")]
+    ListGroups {
+        #[arg(long, value_enum, default_value_t = OutputFormat::Table)]
+        format: OutputFormat,
+    },
+    #[command(about = "Adds a client to a group")]
+    AddGroupMember {
+        #[arg(short, long, value_name = "name of group")]
+        name: String,
+        #[arg(short, long, value_name = "UUID of client")]
+        uuid: String,
+    },
+    #[command(about = "Removes a client from a group")]
+    RemoveGroupMember {
+        #[arg(short, long, value_name = "name of group")]
+        name: String,
+        #[arg(short, long, value_name = "UUID of client")]
+        uuid: String,
+    },
+    #[command(about = "Grants a relay to all clients of a group")]
+    GrantGroupRelay {
+        #[arg(short, long, value_name = "name of group")]
+        name: String,
+        #[arg(short, long, value_name = "id of relay")]
+        relay: u8,
+    },
+    #[command(about = "Revokes a relay from a group")]
+    RevokeGroupRelay {
+        #[arg(short, long, value_name = "name of group")]
+        name: String,
+        #[arg(short, long, value_name = "id of relay")]
+        relay: u8,
+    },
+    #[command(about = "Writes clients, relays, groups, schedules and quotas to a JSON bundle")]
+    Export {
+        #[arg(short, long, value_name = "bundle file to write")]
+        output: String,
+        #[arg(long, help = "Encrypts the bundle with a passphrase (ICT_BUNDLE_PASSPHRASE or asked)")]
+        encrypt: bool,
+    },
+    #[command(about = "Reads a JSON bundle written by export")]
+    Import {
+        #[arg(short, long, value_name = "bundle file to read")]
+        input: String,
+        #[arg(long, value_enum, default_value_t = ImportModeArg::Merge)]
+        mode: ImportModeArg,
+    },
+    #[command(about = "Copies the database to a new file, safe while a server runs")]
+    Snapshot {
+        #[arg(short, long, value_name = "database file to create")]
+        output: String,
+    },
+    #[command(about = "Shows the status of the running server")]
+    Status,
+    #[command(about = "Disables or re-enables the local trigger inputs of the running server")]
+    Lockout {
+        #[arg(value_enum)]
+        state: LockoutArg,
+    },
+    #[command(about = "Validates the configuration, checks its paths and prints it with secrets redacted")]
+    CheckConfig,
     #[command(about = "Starts Web Server listening for clients")]
     Serve {
         #[arg(short, long, value_name = "listening port")]
         port: u32,
     },
+    #[command(about = "Database maintenance")]
+    Db {
+        #[command(subcommand)]
+        command: DbCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum DbCommand {
+    #[command(about = "Applies pending schema migrations")]
+    Migrate {
+        #[arg(long, help = "Only lists and checks the pending migrations, nothing is changed")]
+        dry_run: bool,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum OutputFormat {
+    Table,
+    Json,
+    Csv,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum LockoutArg {
+    On,
+    Off,
+}
+
+// merge keeps the clients missing from the bundle, replace removes them
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum ImportModeArg {
+    Merge,
+    Replace,
+}
+
+fn parse_status(status: &str) -> Result<DeviceStatus, String> {
+    status.parse().map_err(|e: ict_server::ict_errors::ICTError| e.to_string())
 }
 
 pub fn load_args() -> Args {
@@ -0,0 +1,350 @@
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use argon2::Argon2;
+use base64::{engine::general_purpose, Engine as _};
+use chrono::{DateTime, Utc};
+use rand::RngCore;
+use rsa::pkcs8::{DecodePublicKey, EncodePublicKey, LineEnding};
+use rsa::RsaPublicKey;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::Path;
+use totp_rs::Secret;
+use uuid::Uuid;
+
+use crate::ict_config::Settings;
+use crate::ict_db::{create_private, Db, Device, DeviceStatus, Quota};
+use crate::ict_errors::ICTError;
+use crate::ict_schedules::{Schedule, Weekdays};
+
+const BUNDLE_FORMAT: &str = "ict-bundle";
+const ENCRYPTED_FORMAT: &str = "ict-bundle-encrypted";
+// bumped whenever a field is removed or changes meaning, new optional fields keep the version
+pub const BUNDLE_VERSION: u32 = 1;
+
+// Everything needed to rebuild a deployment, TOTP secrets included
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Bundle {
+    pub format: String,
+    pub version: u32,
+    pub exported_at: DateTime<Utc>,
+    pub schema_version: u32,
+    pub devices: Vec<DeviceRecord>,
+    pub groups: Vec<GroupRecord>,
+    // effective configuration at export time with its secrets redacted, for reference:
+    // import does not apply it, the configuration is only ever read from its files
+    #[serde(default)]
+    pub settings: Option<Value>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeviceRecord {
+    pub id: Uuid,
+    pub public_key: String,
+    // base32, as handed to the client
+    pub totp_secret: String,
+    pub status: DeviceStatus,
+    pub status_reason: Option<String>,
+    pub status_changed_at: Option<DateTime<Utc>>,
+    pub valid_from: Option<DateTime<Utc>>,
+    pub valid_until: Option<DateTime<Utc>>,
+    pub display_name: Option<String>,
+    pub owner: Option<String>,
+    pub notes: Option<String>,
+    pub created_at: Option<DateTime<Utc>>,
+    pub last_seen_at: Option<DateTime<Utc>>,
+    pub last_remote_addr: Option<String>,
+    #[serde(default)]
+    pub operation_count: u64,
+    // direct grants, group grants are in the groups
+    #[serde(default)]
+    pub relays: Vec<u8>,
+    #[serde(default)]
+    pub schedules: Vec<ScheduleRecord>,
+    pub quota: Option<QuotaRecord>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ScheduleRecord {
+    pub relay: Option<u8>,
+    pub days: String,
+    pub from: String,
+    pub until: String,
+    pub timezone: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct QuotaRecord {
+    pub max_per_hour: Option<u32>,
+    pub max_per_day: Option<u32>,
+    pub max_total: Option<u32>,
+    pub min_interval: Option<u64>,
+    pub since: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GroupRecord {
+    pub name: String,
+    pub relays: Vec<u8>,
+    pub members: Vec<Uuid>,
+}
+
+// A bundle encrypted with AES-256-GCM under a key derived from a passphrase with Argon2id
+#[derive(Debug, Serialize, Deserialize)]
+struct EncryptedBundle {
+    format: String,
+    version: u32,
+    kdf: String,
+    salt: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImportMode {
+    // devices of the bundle overwrite the ones with the same id, the others are kept
+    Merge,
+    // the database ends up with exactly the devices and groups of the bundle
+    Replace,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ImportSummary {
+    pub devices: usize,
+    pub groups: usize,
+}
+
+pub fn export(db: &Db, settings: &Settings) -> Result<Bundle, ICTError> {
+    // a single read transaction so the bundle is consistent while a server runs
+    db.transaction(|db| {
+        let mut devices = Vec::new();
+        for device in db.get_devices()? {
+            devices.push(device_record(db, device)?);
+        }
+        let mut groups = Vec::new();
+        for group in db.get_groups()? {
+            groups.push(GroupRecord {
+                relays: db.get_group_relays(group.id)?,
+                members: db.get_group_members(group.id)?,
+                name: group.name,
+            });
+        }
+        Ok(Bundle {
+            format: BUNDLE_FORMAT.to_string(),
+            version: BUNDLE_VERSION,
+            exported_at: Utc::now(),
+            schema_version: db.schema_version()?,
+            devices,
+            groups,
+            settings: Some(settings.redacted()),
+        })
+    })
+}
+
+fn device_record(db: &Db, device: Device) -> Result<DeviceRecord, ICTError> {
+    let schedules = db
+        .get_schedules(Some(device.id))?
+        .into_iter()
+        .map(|s| ScheduleRecord {
+            relay: s.relay_id,
+            days: s.weekdays.to_string(),
+            from: s.start_as_string(),
+            until: s.end_as_string(),
+            timezone: s.timezone.to_string(),
+        })
+        .collect();
+    let quota = db.get_quota(device.id)?.map(|q| QuotaRecord {
+        max_per_hour: q.max_per_hour,
+        max_per_day: q.max_per_day,
+        max_total: q.max_total,
+        min_interval: q.min_interval,
+        since: q.since,
+    });
+    Ok(DeviceRecord {
+        id: device.id,
+        public_key: device.wrapped_pk.to_public_key_pem(LineEnding::LF)?,
+        totp_secret: device.totp_secret.to_encoded().to_string(),
+        status: device.status,
+        status_reason: device.status_reason,
+        status_changed_at: device.status_changed_at,
+        valid_from: device.valid_from,
+        valid_until: device.valid_until,
+        display_name: device.display_name,
+        owner: device.owner,
+        notes: device.notes,
+        created_at: device.created_at,
+        last_seen_at: device.last_seen_at,
+        last_remote_addr: device.last_remote_addr,
+        operation_count: device.operation_count,
+        relays: db.get_relays(device.id)?,
+        schedules,
+        quota,
+    })
+}
+
+// Applies a bundle in one transaction, nothing changes if any part of it is invalid
+pub fn import(db: &Db, bundle: &Bundle, mode: ImportMode) -> Result<ImportSummary, ICTError> {
+    if bundle.format != BUNDLE_FORMAT {
+        return Err(ICTError::Custom(format!("Not an export bundle ({})", bundle.format)));
+    }
+    if bundle.version > BUNDLE_VERSION {
+        return Err(ICTError::Custom(format!(
+            "Bundle version {} is newer than the supported version {}",
+            bundle.version, BUNDLE_VERSION
+        )));
+    }
+    db.transaction(|db| {
+        // an import never brings back a device revoked or suspended since the export
+        let held: HashMap<Uuid, Device> = db
+            .get_devices()?
+            .into_iter()
+            .filter(|device| matches!(device.status, DeviceStatus::Revoked | DeviceStatus::Suspended))
+            .map(|device| (device.id, device))
+            .collect();
+        if mode == ImportMode::Replace {
+            db.clear_all()?;
+        }
+        for record in &bundle.devices {
+            import_device(db, record, held.get(&record.id))?;
+        }
+        for record in &bundle.groups {
+            let group_id = match db.get_group(&record.name)? {
+                Some(group) => group.id,
+                None => db.add_group(&record.name)?,
+            };
+            for relay in &record.relays {
+                db.add_group_relay(group_id, *relay)?;
+            }
+            for member in &record.members {
+                db.add_group_member(group_id, *member)?;
+            }
+        }
+        let detail = format!(
+            "{:?} of {} devices and {} groups exported at {}",
+            mode,
+            bundle.devices.len(),
+            bundle.groups.len(),
+            bundle.exported_at
+        );
+        db.add_audit(Utc::now(), None, "import", &detail)?;
+        Ok(ImportSummary { devices: bundle.devices.len(), groups: bundle.groups.len() })
+    })
+}
+
+// A device held keeps its status and credentials, the rest comes from the record
+fn import_device(db: &Db, record: &DeviceRecord, held: Option<&Device>) -> Result<(), ICTError> {
+    let mut device = Device::new(
+        record.id,
+        RsaPublicKey::from_public_key_pem(&record.public_key)?,
+        Secret::Encoded(record.totp_secret.clone()).to_raw()?,
+    );
+    device.status = record.status;
+    device.status_reason = record.status_reason.clone();
+    device.status_changed_at = record.status_changed_at;
+    device.valid_from = record.valid_from;
+    device.valid_until = record.valid_until;
+    device.display_name = record.display_name.clone();
+    device.owner = record.owner.clone();
+    device.notes = record.notes.clone();
+    device.created_at = record.created_at;
+    device.last_seen_at = record.last_seen_at;
+    device.last_remote_addr = record.last_remote_addr.clone();
+    device.operation_count = record.operation_count;
+    if let Some(held) = held {
+        warn!("Import keeps device {} {} instead of {}", device.id, held.status, record.status);
+        device.wrapped_pk = held.wrapped_pk.clone();
+        device.totp_secret = held.totp_secret.clone();
+        device.status = held.status;
+        device.status_reason = held.status_reason.clone();
+        device.status_changed_at = held.status_changed_at;
+    }
+    db.upsert_device(&device)?;
+
+    db.remove_relays(device.id)?;
+    for relay in &record.relays {
+        db.add_relay(device.id, *relay)?;
+    }
+    db.remove_schedules(device.id)?;
+    for s in &record.schedules {
+        let weekdays = Weekdays::parse(&s.days)?;
+        db.add_schedule(&Schedule::new(device.id, s.relay, weekdays, &s.from, &s.until, &s.timezone)?)?;
+    }
+    match &record.quota {
+        Some(q) => db.set_quota(&Quota {
+            device_id: device.id,
+            max_per_hour: q.max_per_hour,
+            max_per_day: q.max_per_day,
+            max_total: q.max_total,
+            min_interval: q.min_interval,
+            since: q.since,
+        })?,
+        None => db.remove_quota(device.id)?,
+    }
+    Ok(())
+}
+
+// Serializes the bundle, encrypted when a passphrase is given
+pub fn to_json(bundle: &Bundle, passphrase: Option<&str>) -> Result<String, ICTError> {
+    let json = serde_json::to_string_pretty(bundle)?;
+    let Some(passphrase) = passphrase else {
+        return Ok(json);
+    };
+    let mut salt = [0u8; 16];
+    OsRng.fill_bytes(&mut salt);
+    let cipher = Aes256Gcm::new(&derive_key(passphrase, &salt)?);
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, json.as_bytes())
+        .map_err(|_| ICTError::Custom("Failed to encrypt the bundle".to_string()))?;
+    Ok(serde_json::to_string_pretty(&EncryptedBundle {
+        format: ENCRYPTED_FORMAT.to_string(),
+        version: BUNDLE_VERSION,
+        kdf: "argon2id".to_string(),
+        salt: general_purpose::STANDARD.encode(salt),
+        nonce: general_purpose::STANDARD.encode(nonce),
+        ciphertext: general_purpose::STANDARD.encode(ciphertext),
+    })?)
+}
+
+// Writes a bundle readable by its owner only, never over an existing file
+pub fn write(path: &Path, json: &str) -> Result<(), ICTError> {
+    create_private(path)?.write_all(json.as_bytes())?;
+    Ok(())
+}
+
+pub fn is_encrypted(json: &str) -> bool {
+    serde_json::from_str::<EncryptedBundle>(json).is_ok_and(|e| e.format == ENCRYPTED_FORMAT)
+}
+
+pub fn from_json(json: &str, passphrase: Option<&str>) -> Result<Bundle, ICTError> {
+    if !is_encrypted(json) {
+        return Ok(serde_json::from_str(json)?);
+    }
+    let encrypted: EncryptedBundle = serde_json::from_str(json)?;
+    let passphrase =
+        passphrase.ok_or(ICTError::Custom("The bundle is encrypted, a passphrase is needed".to_string()))?;
+    if encrypted.kdf != "argon2id" {
+        return Err(ICTError::Custom(format!("Unsupported key derivation {}", encrypted.kdf)));
+    }
+    let salt = general_purpose::STANDARD.decode(&encrypted.salt)?;
+    let nonce = general_purpose::STANDARD.decode(&encrypted.nonce)?;
+    if nonce.len() != 12 {
+        return Err(ICTError::Custom("Invalid nonce in the bundle".to_string()));
+    }
+    let cipher = Aes256Gcm::new(&derive_key(passphrase, &salt)?);
+    let json = cipher
+        .decrypt(Nonce::from_slice(&nonce), general_purpose::STANDARD.decode(&encrypted.ciphertext)?.as_slice())
+        .map_err(|_| ICTError::Custom("Wrong passphrase or corrupted bundle".to_string()))?;
+    Ok(serde_json::from_slice(&json)?)
+}
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> Result<Key<Aes256Gcm>, ICTError> {
+    let mut key = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| ICTError::Custom(format!("Failed to derive the key with {}", e)))?;
+    Ok(key.into())
+}
@@ -1,38 +1,797 @@
-use serde::Deserialize;
-use config::{Config, ConfigError, File};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use config::{Config, ConfigError, Environment, File, Map, Source, ValueKind};
+use log::LevelFilter;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::fs::OpenOptions;
+use std::path::{Path, PathBuf};
 
-#[derive(Debug, Deserialize, Clone)]
+use crate::ict_events;
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct Settings {
     pub database: Database,
+    #[serde(default)]
+    pub web: Web,
     pub totp: Totp,
     pub logs: Logs,
     pub pi: Pi,
+    #[serde(default)]
+    pub relays: Vec<RelayConfig>,
+    #[serde(default)]
+    pub interlocks: Vec<Interlock>,
+    #[serde(default)]
+    pub inputs: Vec<InputConfig>,
+    #[serde(default)]
+    pub admin: Admin,
+    #[serde(default)]
+    pub metrics: Metrics,
+    #[serde(default)]
+    pub events: Events,
+    #[serde(default)]
+    pub webhooks: Vec<Webhook>,
+    #[serde(default)]
+    pub mqtt: Mqtt,
+    // dotted key -> file or environment variable it was taken from
+    #[serde(skip)]
+    pub sources: BTreeMap<String, String>,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct Database {
     pub path: String,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+// Directory holding cert.pem and key.pem, currently not used with rouille
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Web {
+    pub tls_path: Option<String>,
+    // how long (seconds) a stopping server waits for the requests and pulses in flight
+    #[serde(default = "default_shutdown_timeout")]
+    pub shutdown_timeout: u64,
+}
+
+impl Default for Web {
+    fn default() -> Self {
+        Web { tls_path: None, shutdown_timeout: default_shutdown_timeout() }
+    }
+}
+
+fn default_shutdown_timeout() -> u64 {
+    10
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct Totp {
-    pub sha: String,
+    pub sha: TotpAlgorithm,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub enum TotpAlgorithm {
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+impl TryFrom<String> for TotpAlgorithm {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match value.to_lowercase().as_str() {
+            "sha1" => Ok(TotpAlgorithm::Sha1),
+            "sha256" => Ok(TotpAlgorithm::Sha256),
+            "sha512" => Ok(TotpAlgorithm::Sha512),
+            _ => Err(format!("totp.sha \"{}\" is not supported, expected sha1, sha256 or sha512", value)),
+        }
+    }
+}
+
+impl From<TotpAlgorithm> for String {
+    fn from(algorithm: TotpAlgorithm) -> Self {
+        algorithm.to_string()
+    }
+}
+
+impl fmt::Display for TotpAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            TotpAlgorithm::Sha1 => "sha1",
+            TotpAlgorithm::Sha256 => "sha256",
+            TotpAlgorithm::Sha512 => "sha512",
+        })
+    }
 }
 
-#[derive(Debug, Deserialize, Clone)]
+impl From<TotpAlgorithm> for totp_rs::Algorithm {
+    fn from(algorithm: TotpAlgorithm) -> Self {
+        match algorithm {
+            TotpAlgorithm::Sha1 => totp_rs::Algorithm::SHA1,
+            TotpAlgorithm::Sha256 => totp_rs::Algorithm::SHA256,
+            TotpAlgorithm::Sha512 => totp_rs::Algorithm::SHA512,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct Logs {
-    pub level: String,
+    pub level: LogLevel,
+    #[serde(default)]
+    pub format: LogFormat,
+    #[serde(default)]
+    pub output: LogOutput,
+    // required with the file output, rotated files get a .1, .2... suffix, .1 being the newest
+    pub file: Option<String>,
+    // the file is rotated when it reaches max_size_mb or is older than max_age_hours
+    #[serde(default = "default_max_size_mb")]
+    pub max_size_mb: u64,
+    #[serde(default = "default_max_age_hours")]
+    pub max_age_hours: u64,
+    // number of rotated files kept, older ones are deleted
+    #[serde(default = "default_keep")]
+    pub keep: usize,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    // one JSON object per line with the structured fields of the entry
+    Json,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogOutput {
+    #[default]
+    Stderr,
+    File,
+}
+
+fn default_max_size_mb() -> u64 {
+    10
+}
+
+fn default_max_age_hours() -> u64 {
+    24
+}
+
+fn default_keep() -> usize {
+    7
+}
+
+// Any case is accepted, ie "INFO" or "info"
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct LogLevel(LevelFilter);
+
+impl LogLevel {
+    pub fn filter(&self) -> LevelFilter {
+        self.0
+    }
+}
+
+impl TryFrom<String> for LogLevel {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse().map(LogLevel).map_err(|_| {
+            format!("logs.level \"{}\" is not supported, expected off, error, warn, info, debug or trace", value)
+        })
+    }
+}
+
+impl From<LogLevel> for String {
+    fn from(level: LogLevel) -> Self {
+        level.0.as_str().to_lowercase()
+    }
+}
+
+// Control socket of a running server, next to the database unless configured
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Admin {
+    pub socket: Option<String>,
+    // where snapshots requested through the socket may be written, a backups directory next to the database by default
+    pub backup_dir: Option<String>,
+}
+
+// Address of a listener serving only /metrics, ie "127.0.0.1:9100", otherwise /metrics is served with the API
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Metrics {
+    pub listen: Option<String>,
+}
+
+// When repeated operate failures of a device become an operate_failures event
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Events {
+    #[serde(default = "default_failure_threshold")]
+    pub failure_threshold: u32,
+    // seconds within which the failures must happen
+    #[serde(default = "default_failure_window")]
+    pub failure_window: u64,
+    // how long (seconds) a stopping server keeps posting to the webhooks, after the requests drained
+    #[serde(default = "default_flush_timeout")]
+    pub flush_timeout: u64,
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        Events {
+            failure_threshold: default_failure_threshold(),
+            failure_window: default_failure_window(),
+            flush_timeout: default_flush_timeout(),
+        }
+    }
+}
+
+fn default_flush_timeout() -> u64 {
+    5
+}
+
+fn default_failure_threshold() -> u32 {
+    5
+}
+
+fn default_failure_window() -> u64 {
+    600
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    Register,
+    Operate,
+    OperateFailures,
+    StatusChange,
+    InputChange,
+}
+
+impl EventKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventKind::Register => "register",
+            EventKind::Operate => "operate",
+            EventKind::OperateFailures => "operate_failures",
+            EventKind::StatusChange => "status_change",
+            EventKind::InputChange => "input_change",
+        }
+    }
+}
+
+impl fmt::Display for EventKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// An endpoint the events are posted to, as JSON signed with the secret
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Webhook {
+    pub name: String,
+    pub url: String,
+    // every event when empty
+    #[serde(default)]
+    pub events: Vec<EventKind>,
+    pub secret: Option<String>,
+    // JSON body with {{placeholders}}, the event itself is posted without one
+    pub template: Option<String>,
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+}
+
+impl Webhook {
+    pub fn wants(&self, kind: EventKind) -> bool {
+        self.events.is_empty() || self.events.contains(&kind)
+    }
+}
+
+fn default_max_attempts() -> u32 {
+    10
+}
+
+// MQTT broker for home automation, disabled unless host is set, needs the mqtt feature
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Mqtt {
+    pub host: Option<String>,
+    #[serde(default = "default_mqtt_port")]
+    pub port: u16,
+    #[serde(default = "default_client_id")]
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    // every topic starts with it, ie ict/relay/16/state
+    #[serde(default = "default_topic_prefix")]
+    pub topic_prefix: String,
+    // events published under <topic_prefix>/events/, every event when empty
+    #[serde(default)]
+    pub events: Vec<EventKind>,
+    // accepts signed operate commands on <topic_prefix>/command/operate
+    #[serde(default)]
+    pub commands: bool,
+    // Home Assistant discovery messages for the named relays, ie "homeassistant"
+    pub discovery_prefix: Option<String>,
+    // seconds
+    #[serde(default = "default_keep_alive")]
+    pub keep_alive: u64,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+impl Default for Mqtt {
+    fn default() -> Self {
+        Mqtt {
+            host: None,
+            port: default_mqtt_port(),
+            client_id: default_client_id(),
+            username: None,
+            password: None,
+            topic_prefix: default_topic_prefix(),
+            events: Vec::new(),
+            commands: false,
+            discovery_prefix: None,
+            keep_alive: default_keep_alive(),
+        }
+    }
+}
+
+impl Mqtt {
+    pub fn wants(&self, kind: EventKind) -> bool {
+        self.events.is_empty() || self.events.contains(&kind)
+    }
+
+    fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.host.as_ref().is_some_and(|host| host.trim().is_empty()) {
+            problems.push("mqtt.host is empty".to_string());
+        }
+        if self.client_id.trim().is_empty() {
+            problems.push("mqtt.client_id is empty".to_string());
+        }
+        if self.password.is_some() && self.username.is_none() {
+            problems.push("mqtt.password needs mqtt.username".to_string());
+        }
+        for (key, topic) in [("topic_prefix", Some(&self.topic_prefix)), ("discovery_prefix", self.discovery_prefix.as_ref())] {
+            if let Some(topic) = topic {
+                if topic.is_empty() || topic.contains(['+', '#']) || topic.starts_with('/') || topic.ends_with('/') {
+                    problems.push(format!("mqtt.{} \"{}\" is not a topic like ict or home/ict", key, topic));
+                }
+            }
+        }
+        if self.keep_alive < 5 {
+            problems.push("mqtt.keep_alive must be at least 5 s".to_string());
+        }
+        problems
+    }
+}
+
+fn default_mqtt_port() -> u16 {
+    1883
+}
+
+fn default_client_id() -> String {
+    "ict_server".to_string()
+}
+
+fn default_topic_prefix() -> String {
+    "ict".to_string()
+}
+
+fn default_keep_alive() -> u64 {
+    30
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct Pi {
     pub close_duration: u64,
+    // watchdog device such as /dev/watchdog, the board reboots if the server stops petting it
+    #[serde(default)]
+    pub watchdog_device: Option<String>,
+}
+
+// Optional settings of a relay, relays without a definition use the defaults
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RelayConfig {
+    pub id: u8,
+    #[serde(default)]
+    pub name: Option<String>,
+    // number of distinct authorized devices that must operate the relay before it actuates
+    #[serde(default = "default_quorum")]
+    pub quorum: u32,
+    // how long (seconds) an approval counts toward the quorum
+    #[serde(default = "default_quorum_window")]
+    pub quorum_window: u64,
+    // level that energizes the relay, the other one is its idle level
+    #[serde(default)]
+    pub active: Level,
+    // longest time (ms) the relay may stay energized before the watchdog re-opens it
+    #[serde(default = "default_max_on")]
+    pub max_on: u64,
+}
+
+impl RelayConfig {
+    pub fn new(id: u8) -> Self {
+        RelayConfig {
+            id,
+            name: None,
+            quorum: default_quorum(),
+            quorum_window: default_quorum_window(),
+            active: Level::default(),
+            max_on: default_max_on(),
+        }
+    }
+}
+
+// A set of relays of which at most one may be energized at any time
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Interlock {
+    pub name: String,
+    pub relays: Vec<u8>,
+    #[serde(default)]
+    pub policy: InterlockPolicy,
+    // minimum time (ms) between releasing a relay of the group and energizing another one
+    #[serde(default)]
+    pub dead_time: u64,
+    // how long (ms) a queued operate waits for the group to be free
+    #[serde(default = "default_queue_timeout")]
+    pub queue_timeout: u64,
+}
+
+// A GPIO input such as the reed switch of a door
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct InputConfig {
+    pub name: String,
+    pub pin: u8,
+    #[serde(default)]
+    pub pull: Pull,
+    // level at which the input is active, ie the door is open
+    #[serde(default)]
+    pub active: Level,
+    // how long (ms) a new level must hold before it counts
+    #[serde(default = "default_debounce")]
+    pub debounce: u64,
+    // the relay whose operate responses report this input
+    #[serde(default)]
+    pub relay: Option<u8>,
+    // relays pulsed when the input goes active, ie an exit button
+    #[serde(default)]
+    pub triggers: Vec<u8>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Pull {
+    Up,
+    Down,
+    #[default]
+    None,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    #[default]
+    High,
+    Low,
+}
+
+fn default_debounce() -> u64 {
+    50
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum InterlockPolicy {
+    #[default]
+    Reject,
+    Queue,
+}
+
+fn default_queue_timeout() -> u64 {
+    10000
+}
+
+fn default_quorum() -> u32 {
+    1
+}
+
+fn default_max_on() -> u64 {
+    30_000
+}
+
+fn default_quorum_window() -> u64 {
+    60
+}
+
+impl Settings {
+    pub fn admin_socket(&self) -> PathBuf {
+        match &self.admin.socket {
+            Some(socket) => PathBuf::from(socket),
+            None => Path::new(&self.database.path).with_extension("sock"),
+        }
+    }
+
+    pub fn backup_dir(&self) -> PathBuf {
+        match &self.admin.backup_dir {
+            Some(dir) => PathBuf::from(dir),
+            None => Path::new(&self.database.path).parent().unwrap_or(Path::new("")).join("backups"),
+        }
+    }
+
+    // Checks what the types alone cannot, all the problems are reported at once
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+        if self.database.path.trim().is_empty() {
+            problems.push("database.path is empty".to_string());
+        }
+        if self.web.tls_path.as_ref().is_some_and(|path| path.trim().is_empty()) {
+            problems.push("web.tls_path is empty".to_string());
+        }
+        if self.admin.socket.as_ref().is_some_and(|path| path.trim().is_empty()) {
+            problems.push("admin.socket is empty".to_string());
+        }
+        if self.admin.backup_dir.as_ref().is_some_and(|path| path.trim().is_empty()) {
+            problems.push("admin.backup_dir is empty".to_string());
+        }
+        if let Some(listen) = &self.metrics.listen {
+            if listen.parse::<std::net::SocketAddr>().is_err() {
+                problems.push(format!("metrics.listen \"{}\" is not an address like 127.0.0.1:9100", listen));
+            }
+        }
+        if self.logs.output == LogOutput::File && self.logs.file.as_ref().is_none_or(|file| file.trim().is_empty()) {
+            problems.push("logs.file is required with the file output".to_string());
+        }
+        if self.logs.max_size_mb == 0 {
+            problems.push("logs.max_size_mb must be at least 1".to_string());
+        }
+        if self.logs.max_age_hours == 0 {
+            problems.push("logs.max_age_hours must be at least 1".to_string());
+        }
+        if self.pi.close_duration == 0 {
+            problems.push("pi.close_duration must be at least 1 ms".to_string());
+        }
+        if self.pi.watchdog_device.as_ref().is_some_and(|path| path.trim().is_empty()) {
+            problems.push("pi.watchdog_device is empty".to_string());
+        }
+        let mut relay_ids = HashSet::new();
+        for relay in &self.relays {
+            if !relay_ids.insert(relay.id) {
+                problems.push(format!("relay {} is defined more than once", relay.id));
+            }
+            if relay.quorum == 0 {
+                problems.push(format!("relay {}: quorum must be at least 1", relay.id));
+            }
+            if relay.quorum > 1 && relay.quorum_window == 0 {
+                problems.push(format!("relay {}: quorum_window must be at least 1 s with a quorum", relay.id));
+            }
+            if relay.max_on < self.pi.close_duration {
+                problems.push(format!("relay {}: max_on must be at least pi.close_duration", relay.id));
+            }
+        }
+        let mut interlock_names = HashSet::new();
+        for interlock in &self.interlocks {
+            if !interlock_names.insert(interlock.name.as_str()) {
+                problems.push(format!("interlock {} is defined more than once", interlock.name));
+            }
+            if interlock.relays.iter().collect::<HashSet<_>>().len() < 2 {
+                problems.push(format!("interlock {}: needs at least two distinct relays", interlock.name));
+            }
+            if interlock.policy == InterlockPolicy::Queue && interlock.queue_timeout == 0 {
+                problems.push(format!("interlock {}: queue_timeout must be at least 1 ms with the queue policy", interlock.name));
+            }
+        }
+        let mut input_names = HashSet::new();
+        let mut input_pins = HashSet::new();
+        for input in &self.inputs {
+            if input.name.trim().is_empty() {
+                problems.push(format!("input on pin {}: name is empty", input.pin));
+            }
+            if !input_names.insert(input.name.as_str()) {
+                problems.push(format!("input {} is defined more than once", input.name));
+            }
+            if !input_pins.insert(input.pin) {
+                problems.push(format!("input {}: pin {} is used by another input", input.name, input.pin));
+            }
+            if relay_ids.contains(&input.pin) {
+                problems.push(format!("input {}: pin {} is a relay", input.name, input.pin));
+            }
+            if let Some(pin) = input.triggers.iter().find(|relay| self.inputs.iter().any(|other| other.pin == **relay)) {
+                problems.push(format!("input {}: triggers pin {} which is an input", input.name, pin));
+            }
+        }
+        if self.events.failure_threshold == 0 {
+            problems.push("events.failure_threshold must be at least 1".to_string());
+        }
+        if self.events.failure_window == 0 {
+            problems.push("events.failure_window must be at least 1 s".to_string());
+        }
+        let mut webhook_names = HashSet::new();
+        for webhook in &self.webhooks {
+            if !webhook_names.insert(webhook.name.as_str()) {
+                problems.push(format!("webhook {} is defined more than once", webhook.name));
+            }
+            if !webhook.url.starts_with("http://") && !webhook.url.starts_with("https://") {
+                problems.push(format!("webhook {}: url \"{}\" is not an http or https URL", webhook.name, webhook.url));
+            }
+            if webhook.max_attempts == 0 {
+                problems.push(format!("webhook {}: max_attempts must be at least 1", webhook.name));
+            }
+            if let Some(template) = &webhook.template {
+                if let Err(e) = ict_events::check_template(template) {
+                    problems.push(format!("webhook {}: template {}", webhook.name, e));
+                }
+            }
+        }
+        if self.mqtt.host.is_some() {
+            problems.extend(self.mqtt.problems());
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Message(format!("invalid configuration: {}", problems.join("; "))))
+        }
+    }
+
+    // The effective configuration with the values of secret looking keys replaced
+    pub fn redacted(&self) -> Value {
+        let mut value = serde_json::to_value(self).unwrap_or(Value::Null);
+        redact(&mut value);
+        value
+    }
+}
+
+const SECRET_KEYS: [&str; 4] = ["password", "secret", "token", "passphrase"];
+
+fn redact(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                if SECRET_KEYS.iter().any(|secret| key.to_lowercase().contains(secret)) && !value.is_null() {
+                    *value = Value::String("<redacted>".to_string());
+                } else {
+                    redact(value);
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(redact),
+        _ => (),
+    }
 }
 
+const ENV_PREFIX: &str = "ICT_";
+const ENV_SEPARATOR: &str = "__";
+const ENV_ORIGIN: &str = "the environment";
+
+// Sources from lowest to highest precedence: the config file, the *.toml files of
+// the conf.d directory next to it in name order, then ICT_<SECTION>__<KEY> variables
 pub fn load_config(config_file_path: &str) -> Result<Settings,ConfigError> {
-    let builder = Config::builder()
-        .add_source(File::with_name(config_file_path))
+    let mut builder = Config::builder().add_source(File::with_name(config_file_path));
+    for file in conf_d_files(config_file_path)? {
+        builder = builder.add_source(File::from(file));
+    }
+    let config = builder
+        .add_source(
+            Environment::with_prefix(ENV_PREFIX.trim_end_matches('_'))
+                .prefix_separator("_")
+                .separator(ENV_SEPARATOR)
+                .source(Some(env_overrides())),
+        )
         .build()?;
 
-    builder.try_deserialize()
+    let mut sources = BTreeMap::new();
+    collect_sources("", &config.collect()?, &mut sources);
+    let mut settings: Settings = config.try_deserialize()?;
+    settings.sources = sources;
+    settings.validate()?;
+    Ok(settings)
+}
+
+pub(crate) fn conf_d_files(config_file_path: &str) -> Result<Vec<PathBuf>, ConfigError> {
+    let dir = Path::new(config_file_path).parent().unwrap_or(Path::new("")).join("conf.d");
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let entries = std::fs::read_dir(&dir).map_err(|e| ConfigError::Foreign(Box::new(e)))?;
+    let mut files: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_file() && path.extension().is_some_and(|ext| ext == "toml"))
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+// Only variables naming a key of a section, ICT_BUNDLE_PASSPHRASE and the like are not settings
+fn env_overrides() -> Map<String, String> {
+    std::env::vars()
+        .filter(|(name, _)| name.strip_prefix(ENV_PREFIX).is_some_and(|key| key.contains(ENV_SEPARATOR)))
+        .collect()
+}
+
+fn collect_sources(prefix: &str, table: &Map<String, config::Value>, sources: &mut BTreeMap<String, String>) {
+    for (key, value) in table {
+        let key = if prefix.is_empty() { key.clone() } else { format!("{}.{}", prefix, key) };
+        match &value.kind {
+            ValueKind::Table(table) => collect_sources(&key, table, sources),
+            _ => {
+                let source = match value.origin() {
+                    Some(ENV_ORIGIN) => format!("{}{}", ENV_PREFIX, key.to_uppercase().replace('.', ENV_SEPARATOR)),
+                    Some(origin) => origin.to_string(),
+                    None => "default".to_string(),
+                };
+                sources.insert(key, source);
+            }
+        }
+    }
+}
+
+// A file or directory the server needs, with the reason it is not usable
+#[derive(Debug, Serialize)]
+pub struct PathCheck {
+    pub name: &'static str,
+    pub path: PathBuf,
+    pub problem: Option<String>,
+}
+
+// Resolves the paths of the configuration and verifies the server can use them
+pub fn check_paths(settings: &Settings) -> Vec<PathCheck> {
+    let mut checks = vec![check("database", Path::new(&settings.database.path), check_writable_file)];
+    if let Some(tls_path) = &settings.web.tls_path {
+        let tls_path = Path::new(tls_path);
+        checks.push(check("tls certificate", &tls_path.join("cert.pem"), check_readable_file));
+        checks.push(check("tls key", &tls_path.join("key.pem"), check_readable_file));
+    }
+    if let (LogOutput::File, Some(file)) = (settings.logs.output, &settings.logs.file) {
+        checks.push(check("log file", Path::new(file), check_writable_file));
+    }
+    checks.push(check("admin socket", &settings.admin_socket(), |path| {
+        if path.exists() && !std::os::unix::fs::FileTypeExt::is_socket(&std::fs::metadata(path)?.file_type()) {
+            return Err(std::io::Error::other("exists and is not a socket"));
+        }
+        check_writable_dir(path)
+    }));
+    checks
+}
+
+fn check(name: &'static str, path: &Path, verify: impl Fn(&Path) -> std::io::Result<()>) -> PathCheck {
+    let path = std::path::absolute(path).unwrap_or(path.to_path_buf());
+    let problem = verify(&path).err().map(|e| e.to_string());
+    PathCheck { name, path, problem }
+}
+
+fn check_readable_file(path: &Path) -> std::io::Result<()> {
+    OpenOptions::new().read(true).open(path)?;
+    Ok(())
+}
+
+// An existing file must be writable, a missing one must be creatable
+fn check_writable_file(path: &Path) -> std::io::Result<()> {
+    if path.exists() {
+        OpenOptions::new().read(true).write(true).open(path)?;
+        Ok(())
+    } else {
+        check_writable_dir(path)
+    }
+}
+
+fn check_writable_dir(path: &Path) -> std::io::Result<()> {
+    let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    if !dir.is_dir() {
+        return Err(std::io::Error::other(format!("directory {} does not exist", dir.display())));
+    }
+    let probe = dir.join(format!(".ict_check_{}", std::process::id()));
+    OpenOptions::new().write(true).create_new(true).open(&probe)?;
+    std::fs::remove_file(&probe)
 }
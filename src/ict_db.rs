@@ -2,18 +2,69 @@ use rsa::{
     pkcs8::{DecodePublicKey, EncodePublicKey},
     RsaPublicKey,
 };
-use rusqlite::{params, Connection, Result};
+use base64::{engine::general_purpose, Engine as _};
+use chrono::{DateTime, Utc};
+use rsa::sha2::{Digest, Sha256};
+use rusqlite::{backup::Backup, params, Connection, OpenFlags, Result, Row, Transaction, TransactionBehavior};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
 use totp_rs::Secret;
 use uuid::Uuid;
 
 use crate::ict_errors::ICTError;
+use crate::ict_migrations::{self, Migration};
+use crate::ict_schedules::{Schedule, Weekdays};
+
+// Creates an empty file only its owner can read, fails if it exists
+pub fn create_private(path: &Path) -> Result<std::fs::File, ICTError> {
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::AlreadyExists => ICTError::Custom(format!("{} already exists", path.display())),
+            _ => e.into(),
+        })
+}
+
+// How long a connection waits for the write lock held by another one
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[derive(Debug)]
 pub struct Device {
     pub id: Uuid,
     pub wrapped_pk: RsaPublicKey,
     pub totp_secret: Secret,
-    pub authorized: u8,
+    pub status: DeviceStatus,
+    // why and when the status last changed
+    pub status_reason: Option<String>,
+    pub status_changed_at: Option<DateTime<Utc>>,
+    // optional window outside of which the authorization does not apply
+    pub valid_from: Option<DateTime<Utc>>,
+    pub valid_until: Option<DateTime<Utc>>,
+    pub display_name: Option<String>,
+    pub owner: Option<String>,
+    pub notes: Option<String>,
+    // unknown for devices registered before it was tracked
+    pub created_at: Option<DateTime<Utc>>,
+    // last time the device proved its identity, and from where
+    pub last_seen_at: Option<DateTime<Utc>>,
+    pub last_remote_addr: Option<String>,
+    pub operation_count: u64,
+}
+
+// Lifecycle of a device, see DeviceStatus::can_become for the allowed transitions
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeviceStatus {
+    Pending,
+    Authorized,
+    Suspended,
+    Revoked,
+    Expired,
 }
 
 #[derive(Debug)]
@@ -22,21 +73,188 @@ pub struct Relay {
     pub relay_id: u8,
 }
 
+// An event waiting to be posted to a webhook, or the record of its delivery
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutboxEntry {
+    pub id: i64,
+    pub webhook: String,
+    // JSON document of the event
+    pub event: String,
+    pub created_at: DateTime<Utc>,
+    pub status: String,
+    pub attempts: u32,
+    pub next_attempt_at: DateTime<Utc>,
+    pub last_error: Option<String>,
+}
+
+// An entry of the audit trail, device_id is kept after the device is deleted
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuditEntry {
+    pub id: i64,
+    pub at: DateTime<Utc>,
+    pub device_id: Option<Uuid>,
+    pub event: String,
+    pub detail: String,
+}
+
 impl Device {
-    pub fn new(
-        id_blob: &Vec<u8>,
-        wrapped_pk: &Vec<u8>,
-        secret: &Vec<u8>,
-        authorized: u8,
-    ) -> Result<Device, ICTError> {
-        Ok(Device {
-            id: Uuid::from_slice(&id_blob)?,
-            wrapped_pk: RsaPublicKey::from_public_key_der(&wrapped_pk)?,
-            totp_secret: Secret::Raw(secret.clone()),
-            authorized: authorized,
-        })
+    // A freshly registered device, pending until an admin authorizes it
+    pub fn new(id: Uuid, wrapped_pk: RsaPublicKey, totp_secret: Secret) -> Device {
+        Device {
+            id,
+            wrapped_pk,
+            totp_secret,
+            status: DeviceStatus::Pending,
+            status_reason: None,
+            status_changed_at: None,
+            valid_from: None,
+            valid_until: None,
+            display_name: None,
+            owner: None,
+            notes: None,
+            created_at: None,
+            last_seen_at: None,
+            last_remote_addr: None,
+            operation_count: 0,
+        }
+    }
+
+    fn from_row(row: &Row) -> Result<Device, ICTError> {
+        let mut device = Device::new(
+            Uuid::from_slice(&row.get::<_, Vec<u8>>(0)?)?,
+            RsaPublicKey::from_public_key_der(&row.get::<_, Vec<u8>>(1)?)?,
+            Secret::Raw(row.get::<_, Vec<u8>>(2)?),
+        );
+        device.status = row.get::<_, String>(3)?.parse()?;
+        device.status_reason = row.get(4)?;
+        device.status_changed_at = timestamp(row.get(5)?);
+        device.valid_from = timestamp(row.get(6)?);
+        device.valid_until = timestamp(row.get(7)?);
+        device.display_name = row.get(8)?;
+        device.owner = row.get(9)?;
+        device.notes = row.get(10)?;
+        device.created_at = timestamp(row.get(11)?);
+        device.last_seen_at = timestamp(row.get(12)?);
+        device.last_remote_addr = row.get(13)?;
+        device.operation_count = row.get(14)?;
+        Ok(device)
+    }
+
+    // SHA-256 of the public key, printed the way ssh prints key fingerprints
+    pub fn fingerprint(&self) -> Result<String, ICTError> {
+        let der = self.wrapped_pk.to_public_key_der()?;
+        Ok(format!("SHA256:{}", general_purpose::STANDARD_NO_PAD.encode(Sha256::digest(der.as_bytes()))))
+    }
+
+    // Status at a given time, an authorization whose window ended is expired
+    // even before the change is recorded
+    pub fn status_at(&self, at: DateTime<Utc>) -> DeviceStatus {
+        if self.status == DeviceStatus::Authorized && self.valid_until.is_some_and(|until| at >= until) {
+            DeviceStatus::Expired
+        } else {
+            self.status
+        }
+    }
+}
+
+impl DeviceStatus {
+    // pending -> authorized <-> suspended, authorized -> expired -> authorized,
+    // anything -> revoked, and nothing ever leaves revoked
+    pub fn can_become(&self, next: DeviceStatus) -> bool {
+        use DeviceStatus::*;
+        matches!(
+            (self, next),
+            (Pending, Authorized)
+                | (Authorized, Authorized)
+                | (Authorized, Suspended)
+                | (Authorized, Expired)
+                | (Suspended, Authorized)
+                | (Expired, Authorized)
+                | (Pending | Authorized | Suspended | Expired, Revoked)
+        )
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeviceStatus::Pending => "pending",
+            DeviceStatus::Authorized => "authorized",
+            DeviceStatus::Suspended => "suspended",
+            DeviceStatus::Revoked => "revoked",
+            DeviceStatus::Expired => "expired",
+        }
+    }
+}
+
+impl std::fmt::Display for DeviceStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
     }
 }
+
+impl std::str::FromStr for DeviceStatus {
+    type Err = ICTError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pending" => Ok(DeviceStatus::Pending),
+            "authorized" => Ok(DeviceStatus::Authorized),
+            "suspended" => Ok(DeviceStatus::Suspended),
+            "revoked" => Ok(DeviceStatus::Revoked),
+            "expired" => Ok(DeviceStatus::Expired),
+            _ => Err(ICTError::Custom(format!("Unknown device status {}", s))),
+        }
+    }
+}
+
+fn timestamp(seconds: Option<i64>) -> Option<DateTime<Utc>> {
+    seconds.and_then(|ts| DateTime::from_timestamp(ts, 0))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Group {
+    pub id: i64,
+    pub name: String,
+}
+
+// Where a device gets a relay from
+#[derive(Debug, Clone, PartialEq)]
+pub enum GrantSource {
+    Direct,
+    Group(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RelayGrant {
+    pub relay_id: u8,
+    pub source: GrantSource,
+}
+
+impl std::fmt::Display for GrantSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GrantSource::Direct => write!(f, "direct"),
+            GrantSource::Group(name) => write!(f, "group {}", name),
+        }
+    }
+}
+
+// Limits on how often a device may operate, None means unlimited
+#[derive(Debug, Clone, PartialEq)]
+pub struct Quota {
+    pub device_id: Uuid,
+    pub max_per_hour: Option<u32>,
+    pub max_per_day: Option<u32>,
+    // counted from when the quota was set
+    pub max_total: Option<u32>,
+    // seconds between two operations
+    pub min_interval: Option<u64>,
+    pub since: DateTime<Utc>,
+}
+
+const DEVICE_COLUMNS: &str =
+    "id, wrapped_pk, totp_secret, status, status_reason, status_changed_at, valid_from, valid_until,
+     display_name, owner, notes, created_at, last_seen_at, last_remote_addr, operation_count";
+
 pub struct Db {
     pub path: Option<String>,
     conn: Connection,
@@ -52,48 +270,147 @@ impl Db {
     }
 
     pub fn new(db_path: &str) -> Result<Self, ICTError> {
-        let conn = Connection::open(db_path)?;
-        let db = Db { path: Some(db_path.to_string()), conn};
+        let db = Self::open(db_path)?;
         db.init()?;
         Ok(db)
     }
 
     pub fn new_test_db() -> Result<Self, ICTError> {
         let conn = Connection::open_in_memory()?;
+        conn.execute_batch("PRAGMA foreign_keys = ON")?;
         let db = Db { path: None, conn};
         db.init()?;
         Ok(db)
     }
 
     fn init(&self) -> Result<(), ICTError> {
+        ict_migrations::migrate(&self.conn, false)?;
+        Ok(())
+    }
+
+    // Opens a database without touching its schema
+    pub fn open(db_path: &str) -> Result<Self, ICTError> {
+        let conn = Connection::open(db_path)?;
+        conn.execute_batch("PRAGMA foreign_keys = ON")?;
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        Ok(Db { path: Some(db_path.to_string()), conn })
+    }
+
+    // Like open but fails instead of creating a missing database file
+    pub fn open_existing(db_path: &str) -> Result<Self, ICTError> {
+        let conn = Connection::open_with_flags(db_path, OpenFlags::default() & !OpenFlags::SQLITE_OPEN_CREATE)?;
+        conn.execute_batch("PRAGMA foreign_keys = ON")?;
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        Ok(Db { path: Some(db_path.to_string()), conn })
+    }
+
+    // "ok" when SQLite finds no corruption, the first problems found otherwise
+    pub fn quick_check(&self) -> Result<String, ICTError> {
+        let mut stmt = self.conn.prepare("PRAGMA quick_check(5)")?;
+        let problems = stmt
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<String>, _>>()?;
+        Ok(problems.join("; "))
+    }
+
+    // Runs f in a transaction, committed only if f succeeds
+    pub fn transaction<T>(&self, f: impl FnOnce(&Db) -> Result<T, ICTError>) -> Result<T, ICTError> {
+        let tx = self.conn.unchecked_transaction()?;
+        let result = f(self)?;
+        tx.commit()?;
+        Ok(result)
+    }
+
+    // Like transaction but takes the write lock first, so that what f reads cannot change
+    // in another connection before f writes
+    pub fn immediate_transaction<T>(&self, f: impl FnOnce(&Db) -> Result<T, ICTError>) -> Result<T, ICTError> {
+        let tx = Transaction::new_unchecked(&self.conn, TransactionBehavior::Immediate)?;
+        let result = f(self)?;
+        tx.commit()?;
+        Ok(result)
+    }
+
+    // Consistent copy of the database taken through the SQLite backup API, safe while serving
+    pub fn snapshot(&self, path: &Path) -> Result<(), ICTError> {
+        // the copy holds the TOTP secrets, it is readable by its owner only
+        create_private(path)?;
+        let mut destination = Connection::open(path)?;
+        let backup = Backup::new(&self.conn, &mut destination)?;
+        backup.run_to_completion(100, Duration::from_millis(50), None)?;
+        Ok(())
+    }
+
+    // Removes every device and group along with what hangs off them, the audit trail is kept
+    pub fn clear_all(&self) -> Result<(), ICTError> {
+        self.conn.execute_batch("DELETE FROM registered_devices; DELETE FROM device_groups;")?;
+        Ok(())
+    }
+
+    pub fn schema_version(&self) -> Result<u32, ICTError> {
+        ict_migrations::schema_version(&self.conn)
+    }
+
+    pub fn pending_migrations(&self) -> Result<Vec<&'static Migration>, ICTError> {
+        ict_migrations::pending(&self.conn)
+    }
+
+    pub fn migrate(&self, dry_run: bool) -> Result<Vec<&'static Migration>, ICTError> {
+        ict_migrations::migrate(&self.conn, dry_run)
+    }
+
+    pub fn add_device(&self, device: &Device) -> Result<(), ICTError> {
         self.conn.execute(
-            "CREATE TABLE IF NOT EXISTS registered_devices (
-                id BLOB PRIMARY KEY,
-                wrapped_pk BLOB NOT NULL,
-                totp_secret BLOB NOT NULL,
-                authorized INTEGER NOT NULL
-            )",
-            [],
-        )?;
-        self.conn.execute(
-            "CREATE TABLE IF NOT EXISTS relays (
-                device_id BLOB NOT NULL,
-                relay_id INTEGER NOT NULL,
-                FOREIGN KEY(device_id) REFERENCES registered_devices(id))",
-            [],
+            "INSERT INTO registered_devices (id, wrapped_pk, totp_secret, status, status_reason, status_changed_at, valid_from, valid_until,
+             display_name, owner, notes, created_at, last_seen_at, last_remote_addr, operation_count)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15)",
+            params![
+                device.id.as_bytes(),
+                device.wrapped_pk.to_public_key_der()?.as_bytes().to_vec(),
+                device.totp_secret.to_bytes()?,
+                device.status.as_str(),
+                device.status_reason,
+                device.status_changed_at.map(|t| t.timestamp()),
+                device.valid_from.map(|t| t.timestamp()),
+                device.valid_until.map(|t| t.timestamp()),
+                device.display_name,
+                device.owner,
+                device.notes,
+                device.created_at.map(|t| t.timestamp()),
+                device.last_seen_at.map(|t| t.timestamp()),
+                device.last_remote_addr,
+                device.operation_count,
+            ],
         )?;
         Ok(())
     }
 
-    pub fn add_device(&self, device: &Device) -> Result<(), ICTError> {
+    // Inserts the device or overwrites every field of the existing one, keeping what hangs off it
+    pub fn upsert_device(&self, device: &Device) -> Result<(), ICTError> {
         self.conn.execute(
-            "INSERT INTO registered_devices (id, wrapped_pk, totp_secret, authorized)
-             VALUES (?1, ?2, ?3, ?4)",
+            "INSERT INTO registered_devices (id, wrapped_pk, totp_secret, status, status_reason, status_changed_at, valid_from, valid_until,
+             display_name, owner, notes, created_at, last_seen_at, last_remote_addr, operation_count)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15)
+             ON CONFLICT(id) DO UPDATE SET wrapped_pk = excluded.wrapped_pk, totp_secret = excluded.totp_secret,
+             status = excluded.status, status_reason = excluded.status_reason, status_changed_at = excluded.status_changed_at,
+             valid_from = excluded.valid_from, valid_until = excluded.valid_until, display_name = excluded.display_name,
+             owner = excluded.owner, notes = excluded.notes, created_at = excluded.created_at, last_seen_at = excluded.last_seen_at,
+             last_remote_addr = excluded.last_remote_addr, operation_count = excluded.operation_count",
             params![
                 device.id.as_bytes(),
                 device.wrapped_pk.to_public_key_der()?.as_bytes().to_vec(),
                 device.totp_secret.to_bytes()?,
-                device.authorized,
+                device.status.as_str(),
+                device.status_reason,
+                device.status_changed_at.map(|t| t.timestamp()),
+                device.valid_from.map(|t| t.timestamp()),
+                device.valid_until.map(|t| t.timestamp()),
+                device.display_name,
+                device.owner,
+                device.notes,
+                device.created_at.map(|t| t.timestamp()),
+                device.last_seen_at.map(|t| t.timestamp()),
+                device.last_remote_addr,
+                device.operation_count,
             ],
         )?;
         Ok(())
@@ -101,37 +418,35 @@ impl Db {
 
     pub fn add_relay(&self, device_id: Uuid, relay_id: u8) -> Result<(), ICTError> {
         self.conn.execute(
-            "INSERT INTO relays (device_id, relay_id) VALUES (?1, ?2)",
+            "INSERT OR IGNORE INTO relays (device_id, relay_id) VALUES (?1, ?2)",
             params![device_id.as_bytes(), relay_id],
         )?;
         Ok(())
     }
 
     pub fn get_device(&self, id: Uuid) -> Result<Option<Device>, ICTError> {
-        let mut stmt = self.conn.prepare(
-            "SELECT id, wrapped_pk, totp_secret, authorized FROM registered_devices WHERE id = ?1",
-        )?;
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {} FROM registered_devices WHERE id = ?1",
+            DEVICE_COLUMNS
+        ))?;
 
         let mut rows = stmt.query(params![id.as_bytes()])?;
         if let Some(row) = rows.next()? {
-            Device::new(&row.get(0)?, &row.get(1)?, &row.get(2)?, row.get(3)?).map(Some)
+            Device::from_row(row).map(Some)
         } else {
             Ok(None)
         }
     }
 
     pub fn get_devices(&self) -> Result<Vec<Device>, ICTError> {
-        let mut stmt = self.conn.prepare("SELECT * FROM registered_devices")?;
+        let mut stmt = self.conn.prepare(&format!("SELECT {} FROM registered_devices", DEVICE_COLUMNS))?;
         let rows = stmt.query_map([], |row| {
-            Device::new(&row.get(0)?, &row.get(1)?, &row.get(2)?, row.get(3)?)
+            Device::from_row(row)
                 .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))
         })?;
 
         let devices = rows
-            .filter_map(|res| match res {
-                Ok(dev) => Some(Ok(dev)),
-                Err(e) => Some(Err(e.into())),
-            })
+            .map(|res| res.map_err(ICTError::from))
             .collect::<Result<Vec<Device>, ICTError>>()?;
 
         Ok(devices)
@@ -145,18 +460,101 @@ impl Db {
         Ok(rows.collect::<Result<Vec<u8>, _>>()?)
     }
 
+    // Relays granted directly and through groups, each relay once, direct grants first
+    pub fn get_effective_relays(&self, device_id: Uuid) -> Result<Vec<u8>, ICTError> {
+        let mut relays: Vec<u8> = Vec::new();
+        for grant in self.get_relay_grants(device_id)? {
+            if !relays.contains(&grant.relay_id) {
+                relays.push(grant.relay_id);
+            }
+        }
+        Ok(relays)
+    }
+
+    pub fn get_relay_grants(&self, device_id: Uuid) -> Result<Vec<RelayGrant>, ICTError> {
+        let mut grants: Vec<RelayGrant> = self
+            .get_relays(device_id)?
+            .into_iter()
+            .map(|relay_id| RelayGrant { relay_id, source: GrantSource::Direct })
+            .collect();
+        let mut stmt = self.conn.prepare(
+            "SELECT gr.relay_id, g.name FROM group_relays gr
+             JOIN device_groups g ON g.id = gr.group_id
+             JOIN group_members gm ON gm.group_id = gr.group_id
+             WHERE gm.device_id = ?1 ORDER BY g.name, gr.relay_id",
+        )?;
+        let rows = stmt.query_map(params![device_id.as_bytes()], |row| {
+            Ok(RelayGrant { relay_id: row.get(0)?, source: GrantSource::Group(row.get(1)?) })
+        })?;
+        grants.extend(rows.collect::<Result<Vec<RelayGrant>, _>>()?);
+        Ok(grants)
+    }
+
     pub fn update_device(&self, device: &Device) -> Result<(), ICTError> {
         self.conn.execute(
-            "UPDATE registered_devices SET wrapped_pk = ?2, totp_secret = ?3, authorized = ?4 WHERE id = ?1",
-            params![device.id.as_bytes(), device.wrapped_pk.to_public_key_der()?.as_bytes().to_vec(), device.totp_secret.to_bytes()?, device.authorized],
+            "UPDATE registered_devices SET wrapped_pk = ?2, totp_secret = ?3, status = ?4, status_reason = ?5, status_changed_at = ?6,
+             valid_from = ?7, valid_until = ?8, display_name = ?9, owner = ?10, notes = ?11 WHERE id = ?1",
+            params![device.id.as_bytes(), device.wrapped_pk.to_public_key_der()?.as_bytes().to_vec(), device.totp_secret.to_bytes()?,
+                device.status.as_str(), device.status_reason, device.status_changed_at.map(|t| t.timestamp()),
+                device.valid_from.map(|t| t.timestamp()), device.valid_until.map(|t| t.timestamp()),
+                device.display_name, device.owner, device.notes],
+        )?;
+        Ok(())
+    }
+
+    // Records a status change, transitions are checked by the operations layer
+    pub fn set_device_status(
+        &self,
+        id: Uuid,
+        status: DeviceStatus,
+        reason: Option<&str>,
+        at: DateTime<Utc>,
+    ) -> Result<(), ICTError> {
+        self.conn.execute(
+            "UPDATE registered_devices SET status = ?2, status_reason = ?3, status_changed_at = ?4 WHERE id = ?1",
+            params![id.as_bytes(), status.as_str(), reason, at.timestamp()],
+        )?;
+        Ok(())
+    }
+
+    // Admin maintained information, written as a whole
+    pub fn set_device_info(
+        &self,
+        id: Uuid,
+        display_name: Option<&str>,
+        owner: Option<&str>,
+        notes: Option<&str>,
+    ) -> Result<(), ICTError> {
+        self.conn.execute(
+            "UPDATE registered_devices SET display_name = ?2, owner = ?3, notes = ?4 WHERE id = ?1",
+            params![id.as_bytes(), display_name, owner, notes],
         )?;
         Ok(())
     }
 
-    pub fn set_authorization_on_device(&self, id: Uuid, auth: u8) -> Result<(), ICTError> {
+    // The device proved its identity, whether or not it went on to operate
+    pub fn record_contact(&self, id: Uuid, at: DateTime<Utc>, remote_addr: Option<&str>) -> Result<(), ICTError> {
         self.conn.execute(
-            "UPDATE registered_devices SET authorized = ?2 WHERE id = ?1",
-            params![id.as_bytes(), auth],
+            "UPDATE registered_devices SET last_seen_at = ?2, last_remote_addr = COALESCE(?3, last_remote_addr) WHERE id = ?1",
+            params![id.as_bytes(), at.timestamp(), remote_addr],
+        )?;
+        Ok(())
+    }
+
+    // Window within which an authorization applies, either end may be open
+    pub fn set_validity_on_device(
+        &self,
+        id: Uuid,
+        valid_from: Option<DateTime<Utc>>,
+        valid_until: Option<DateTime<Utc>>,
+    ) -> Result<(), ICTError> {
+        self.conn.execute(
+            "UPDATE registered_devices SET valid_from = ?2, valid_until = ?3 WHERE id = ?1",
+            params![
+                id.as_bytes(),
+                valid_from.map(|t| t.timestamp()),
+                valid_until.map(|t| t.timestamp())
+            ],
         )?;
         Ok(())
     }
@@ -177,6 +575,416 @@ impl Db {
         Ok(())
     }
 
+    pub fn add_schedule(&self, schedule: &Schedule) -> Result<i64, ICTError> {
+        self.conn.execute(
+            "INSERT INTO schedules (device_id, relay_id, weekdays, start_time, end_time, timezone)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                schedule.device_id.as_bytes(),
+                schedule.relay_id,
+                schedule.weekdays.0,
+                schedule.start_as_string(),
+                schedule.end_as_string(),
+                schedule.timezone.name(),
+            ],
+        )?;
+        Ok(self.conn.last_insert_rowid())
+    }
+
+    // All schedules, or only the ones attached to a device and its relays
+    pub fn get_schedules(&self, device_id: Option<Uuid>) -> Result<Vec<Schedule>, ICTError> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, device_id, relay_id, weekdays, start_time, end_time, timezone FROM schedules
+             WHERE ?1 IS NULL OR device_id = ?1 ORDER BY id",
+        )?;
+        let rows = stmt.query_map(params![device_id.map(|id| id.as_bytes().to_vec())], |row| {
+            let device_id: Vec<u8> = row.get(1)?;
+            let weekdays: u8 = row.get(3)?;
+            let start: String = row.get(4)?;
+            let end: String = row.get(5)?;
+            let timezone: String = row.get(6)?;
+            let mut schedule = Uuid::from_slice(&device_id)
+                .map_err(ICTError::from)
+                .and_then(|device_id| {
+                    Schedule::new(device_id, row.get(2)?, Weekdays(weekdays), &start, &end, &timezone)
+                })
+                .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+            schedule.id = row.get(0)?;
+            Ok(schedule)
+        })?;
+        Ok(rows.collect::<Result<Vec<Schedule>, _>>()?)
+    }
+
+    pub fn remove_schedules(&self, device_id: Uuid) -> Result<(), ICTError> {
+        self.conn.execute("DELETE FROM schedules WHERE device_id = ?1", params![device_id.as_bytes()])?;
+        Ok(())
+    }
+
+    pub fn remove_schedule(&self, id: i64) -> Result<bool, ICTError> {
+        let count = self.conn.execute("DELETE FROM schedules WHERE id = ?1", params![id])?;
+        Ok(count > 0)
+    }
+
+    pub fn set_quota(&self, quota: &Quota) -> Result<(), ICTError> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO quotas (device_id, max_per_hour, max_per_day, max_total, min_interval, since)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                quota.device_id.as_bytes(),
+                quota.max_per_hour,
+                quota.max_per_day,
+                quota.max_total,
+                quota.min_interval,
+                quota.since.timestamp(),
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn get_quota(&self, device_id: Uuid) -> Result<Option<Quota>, ICTError> {
+        let mut stmt = self.conn.prepare(
+            "SELECT max_per_hour, max_per_day, max_total, min_interval, since FROM quotas WHERE device_id = ?1",
+        )?;
+        let mut rows = stmt.query(params![device_id.as_bytes()])?;
+        if let Some(row) = rows.next()? {
+            Ok(Some(Quota {
+                device_id,
+                max_per_hour: row.get(0)?,
+                max_per_day: row.get(1)?,
+                max_total: row.get(2)?,
+                min_interval: row.get(3)?,
+                since: DateTime::from_timestamp(row.get(4)?, 0).unwrap_or_default(),
+            }))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn remove_quota(&self, device_id: Uuid) -> Result<()> {
+        self.conn.execute(
+            "DELETE FROM quotas WHERE device_id = ?1",
+            params![device_id.as_bytes()],
+        )?;
+        Ok(())
+    }
+
+    // Returns the id of the operation, for cancel_operation
+    pub fn record_operation(&self, device_id: Uuid, at: DateTime<Utc>) -> Result<i64> {
+        self.conn.execute(
+            "INSERT INTO operations (device_id, operated_at) VALUES (?1, ?2)",
+            params![device_id.as_bytes(), at.timestamp()],
+        )?;
+        let id = self.conn.last_insert_rowid();
+        self.conn.execute(
+            "UPDATE registered_devices SET operation_count = operation_count + 1 WHERE id = ?1",
+            params![device_id.as_bytes()],
+        )?;
+        Ok(id)
+    }
+
+    // Takes back an operation recorded before the relays failed to actuate
+    pub fn cancel_operation(&self, device_id: Uuid, id: i64) -> Result<()> {
+        if self.conn.execute("DELETE FROM operations WHERE rowid = ?1", params![id])? > 0 {
+            self.conn.execute(
+                "UPDATE registered_devices SET operation_count = operation_count - 1 WHERE id = ?1",
+                params![device_id.as_bytes()],
+            )?;
+        }
+        Ok(())
+    }
+
+    // Drops the operations older than before that no quota looks at any more: the latest one of
+    // each device is kept for min_interval and those since a max_total quota was set are kept
+    pub fn prune_operations(&self, before: DateTime<Utc>) -> Result<usize> {
+        self.conn.execute(
+            "DELETE FROM operations WHERE operated_at < ?1
+                AND operated_at < (SELECT MAX(o.operated_at) FROM operations o WHERE o.device_id = operations.device_id)
+                AND NOT EXISTS (SELECT 1 FROM quotas q WHERE q.device_id = operations.device_id
+                    AND q.max_total IS NOT NULL AND operations.operated_at >= q.since)",
+            params![before.timestamp()],
+        )
+    }
+
+    // A value of the running server, such as the lockout, that a restart must find again
+    pub fn get_state(&self, key: &str) -> Result<Option<String>> {
+        let mut stmt = self.conn.prepare("SELECT value FROM server_state WHERE key = ?1")?;
+        let mut rows = stmt.query(params![key])?;
+        match rows.next()? {
+            Some(row) => row.get(0).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub fn set_state(&self, key: &str, value: &str) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO server_state (key, value) VALUES (?1, ?2) ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+            params![key, value],
+        )?;
+        Ok(())
+    }
+
+    // Records the signed message of a command, false when the device already used it
+    pub fn use_command(&self, device_id: Uuid, message: &str, at: DateTime<Utc>) -> Result<bool> {
+        let inserted = self.conn.execute(
+            "INSERT OR IGNORE INTO used_commands (device_id, message, used_at) VALUES (?1, ?2, ?3)",
+            params![device_id.as_bytes(), message, at.timestamp()],
+        )?;
+        Ok(inserted == 1)
+    }
+
+    // Drops the used messages older than before, their TOTP token is no longer accepted anyway
+    pub fn prune_used_commands(&self, before: DateTime<Utc>) -> Result<usize> {
+        self.conn.execute("DELETE FROM used_commands WHERE used_at < ?1", params![before.timestamp()])
+    }
+
+    // Number of operations of a device since a given time
+    pub fn count_operations_since(&self, device_id: Uuid, since: DateTime<Utc>) -> Result<u32> {
+        self.conn.query_row(
+            "SELECT COUNT(*) FROM operations WHERE device_id = ?1 AND operated_at >= ?2",
+            params![device_id.as_bytes(), since.timestamp()],
+            |row| row.get(0),
+        )
+    }
+
+    pub fn last_operation(&self, device_id: Uuid) -> Result<Option<DateTime<Utc>>> {
+        let last: Option<i64> = self.conn.query_row(
+            "SELECT MAX(operated_at) FROM operations WHERE device_id = ?1",
+            params![device_id.as_bytes()],
+            |row| row.get(0),
+        )?;
+        Ok(last.and_then(|ts| DateTime::from_timestamp(ts, 0)))
+    }
+
+    // Records the approval of a device for a relay, dropping approvals older than since,
+    // and returns the number of distinct devices currently approving the relay
+    pub fn approve_relay(&self, relay_id: u8, device_id: Uuid, at: DateTime<Utc>, since: DateTime<Utc>) -> Result<u32> {
+        // approvals of devices suspended, revoked or expired since do not count
+        self.conn.execute(
+            "DELETE FROM quorum_approvals WHERE relay_id = ?1 AND (approved_at < ?2
+                OR device_id NOT IN (SELECT id FROM registered_devices WHERE status = 'authorized'))",
+            params![relay_id, since.timestamp()],
+        )?;
+        self.conn.execute(
+            "INSERT OR REPLACE INTO quorum_approvals (relay_id, device_id, approved_at) VALUES (?1, ?2, ?3)",
+            params![relay_id, device_id.as_bytes(), at.timestamp()],
+        )?;
+        self.conn.query_row(
+            "SELECT COUNT(*) FROM quorum_approvals WHERE relay_id = ?1",
+            params![relay_id],
+            |row| row.get(0),
+        )
+    }
+
+    // Oldest approval still pending for a relay
+    pub fn first_approval(&self, relay_id: u8) -> Result<Option<DateTime<Utc>>> {
+        let first: Option<i64> = self.conn.query_row(
+            "SELECT MIN(approved_at) FROM quorum_approvals WHERE relay_id = ?1",
+            params![relay_id],
+            |row| row.get(0),
+        )?;
+        Ok(first.and_then(|ts| DateTime::from_timestamp(ts, 0)))
+    }
+
+    // Removes and returns the approvals of a relay, (device, approved at)
+    pub fn take_approvals(&self, relay_id: u8) -> Result<Vec<(Uuid, DateTime<Utc>)>, ICTError> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT device_id, approved_at FROM quorum_approvals WHERE relay_id = ?1")?;
+        let rows = stmt.query_map(params![relay_id], |row| Ok((row.get::<_, Vec<u8>>(0)?, row.get::<_, i64>(1)?)))?;
+        let approvals = rows
+            .map(|row| {
+                let (id, at) = row?;
+                Ok((Uuid::from_slice(&id)?, DateTime::from_timestamp(at, 0).unwrap_or_default()))
+            })
+            .collect::<Result<Vec<_>, ICTError>>()?;
+        self.conn.execute("DELETE FROM quorum_approvals WHERE relay_id = ?1", params![relay_id])?;
+        Ok(approvals)
+    }
+
+    // Puts back approvals taken by take_approvals, newer approvals of the same devices are kept
+    pub fn restore_approvals(&self, relay_id: u8, approvals: &[(Uuid, DateTime<Utc>)]) -> Result<()> {
+        for (device_id, at) in approvals {
+            self.conn.execute(
+                "INSERT OR IGNORE INTO quorum_approvals (relay_id, device_id, approved_at) VALUES (?1, ?2, ?3)",
+                params![relay_id, device_id.as_bytes(), at.timestamp()],
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn add_group(&self, name: &str) -> Result<i64, ICTError> {
+        self.conn.execute("INSERT INTO device_groups (name) VALUES (?1)", params![name])?;
+        Ok(self.conn.last_insert_rowid())
+    }
+
+    pub fn get_group(&self, name: &str) -> Result<Option<Group>, ICTError> {
+        let mut stmt = self.conn.prepare("SELECT id, name FROM device_groups WHERE name = ?1")?;
+        let mut rows = stmt.query(params![name])?;
+        if let Some(row) = rows.next()? {
+            Ok(Some(Group { id: row.get(0)?, name: row.get(1)? }))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn get_groups(&self) -> Result<Vec<Group>, ICTError> {
+        let mut stmt = self.conn.prepare("SELECT id, name FROM device_groups ORDER BY name")?;
+        let rows = stmt.query_map([], |row| Ok(Group { id: row.get(0)?, name: row.get(1)? }))?;
+        Ok(rows.collect::<Result<Vec<Group>, _>>()?)
+    }
+
+    // Deletes a group along with its memberships and relay grants
+    pub fn delete_group(&self, group_id: i64) -> Result<()> {
+        self.conn.execute("DELETE FROM device_groups WHERE id = ?1", params![group_id])?;
+        Ok(())
+    }
+
+    pub fn add_group_member(&self, group_id: i64, device_id: Uuid) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR IGNORE INTO group_members (group_id, device_id) VALUES (?1, ?2)",
+            params![group_id, device_id.as_bytes()],
+        )?;
+        Ok(())
+    }
+
+    pub fn remove_group_member(&self, group_id: i64, device_id: Uuid) -> Result<bool> {
+        let count = self.conn.execute(
+            "DELETE FROM group_members WHERE group_id = ?1 AND device_id = ?2",
+            params![group_id, device_id.as_bytes()],
+        )?;
+        Ok(count > 0)
+    }
+
+    pub fn get_group_members(&self, group_id: i64) -> Result<Vec<Uuid>, ICTError> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT device_id FROM group_members WHERE group_id = ?1")?;
+        let rows = stmt.query_map(params![group_id], |row| row.get::<_, Vec<u8>>(0))?;
+        rows.map(|id| Ok(Uuid::from_slice(&id?)?)).collect()
+    }
+
+    pub fn add_group_relay(&self, group_id: i64, relay_id: u8) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR IGNORE INTO group_relays (group_id, relay_id) VALUES (?1, ?2)",
+            params![group_id, relay_id],
+        )?;
+        Ok(())
+    }
+
+    pub fn remove_group_relay(&self, group_id: i64, relay_id: u8) -> Result<bool> {
+        let count = self.conn.execute(
+            "DELETE FROM group_relays WHERE group_id = ?1 AND relay_id = ?2",
+            params![group_id, relay_id],
+        )?;
+        Ok(count > 0)
+    }
+
+    pub fn get_group_relays(&self, group_id: i64) -> Result<Vec<u8>, ICTError> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT relay_id FROM group_relays WHERE group_id = ?1 ORDER BY relay_id")?;
+        let rows = stmt.query_map(params![group_id], |row| row.get(0))?;
+        Ok(rows.collect::<Result<Vec<u8>, _>>()?)
+    }
+
+    pub fn add_audit(&self, at: DateTime<Utc>, device_id: Option<Uuid>, event: &str, detail: &str) -> Result<(), ICTError> {
+        self.conn.execute(
+            "INSERT INTO audit (at, device_id, event, detail) VALUES (?1, ?2, ?3, ?4)",
+            params![at.timestamp(), device_id.map(|id| id.as_bytes().to_vec()), event, detail],
+        )?;
+        Ok(())
+    }
+
+    pub fn add_outbox(&self, webhook: &str, event: &str, at: DateTime<Utc>) -> Result<(), ICTError> {
+        self.conn.execute(
+            "INSERT INTO outbox (webhook, event, created_at, next_attempt_at) VALUES (?1, ?2, ?3, ?3)",
+            params![webhook, event, at.timestamp()],
+        )?;
+        Ok(())
+    }
+
+    // Pending entries of every webhook, or of one, oldest first
+    pub fn get_outbox(&self, due_at: Option<DateTime<Utc>>, limit: u32) -> Result<Vec<OutboxEntry>, ICTError> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, webhook, event, created_at, status, attempts, next_attempt_at, last_error FROM outbox
+             WHERE status = 'pending' AND (?1 IS NULL OR next_attempt_at <= ?1) ORDER BY id LIMIT ?2",
+        )?;
+        let rows = stmt.query_map(params![due_at.map(|at| at.timestamp()), limit], |row| {
+            Ok(OutboxEntry {
+                id: row.get(0)?,
+                webhook: row.get(1)?,
+                event: row.get(2)?,
+                created_at: timestamp(Some(row.get(3)?)).unwrap_or_default(),
+                status: row.get(4)?,
+                attempts: row.get(5)?,
+                next_attempt_at: timestamp(Some(row.get(6)?)).unwrap_or_default(),
+                last_error: row.get(7)?,
+            })
+        })?;
+        Ok(rows.collect::<Result<Vec<OutboxEntry>, _>>()?)
+    }
+
+    pub fn set_outbox_delivered(&self, id: i64, attempts: u32, at: DateTime<Utc>) -> Result<(), ICTError> {
+        self.conn.execute(
+            "UPDATE outbox SET status = 'delivered', attempts = ?2, done_at = ?3, last_error = NULL WHERE id = ?1",
+            params![id, attempts, at.timestamp()],
+        )?;
+        Ok(())
+    }
+
+    // Another attempt at next_attempt_at, or none if next_attempt_at is None
+    pub fn set_outbox_failed(
+        &self,
+        id: i64,
+        attempts: u32,
+        error: &str,
+        next_attempt_at: Option<DateTime<Utc>>,
+        at: DateTime<Utc>,
+    ) -> Result<(), ICTError> {
+        match next_attempt_at {
+            Some(next) => self.conn.execute(
+                "UPDATE outbox SET attempts = ?2, last_error = ?3, next_attempt_at = ?4 WHERE id = ?1",
+                params![id, attempts, error, next.timestamp()],
+            )?,
+            None => self.conn.execute(
+                "UPDATE outbox SET status = 'failed', attempts = ?2, last_error = ?3, done_at = ?4 WHERE id = ?1",
+                params![id, attempts, error, at.timestamp()],
+            )?,
+        };
+        Ok(())
+    }
+
+    // Forgets deliveries that ended before the given time, pending ones are kept
+    pub fn prune_outbox(&self, before: DateTime<Utc>) -> Result<usize, ICTError> {
+        Ok(self.conn.execute(
+            "DELETE FROM outbox WHERE status != 'pending' AND done_at < ?1",
+            params![before.timestamp()],
+        )?)
+    }
+
+    // Audit trail, oldest first, of everything or of one device
+    pub fn get_audit(&self, device_id: Option<Uuid>) -> Result<Vec<AuditEntry>, ICTError> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, at, device_id, event, detail FROM audit
+             WHERE ?1 IS NULL OR device_id = ?1 ORDER BY at, id",
+        )?;
+        let rows = stmt.query_map(params![device_id.map(|id| id.as_bytes().to_vec())], |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?, row.get::<_, Option<Vec<u8>>>(2)?, row.get(3)?, row.get(4)?))
+        })?;
+        let mut entries = Vec::new();
+        for row in rows {
+            let (id, at, device_id, event, detail) = row?;
+            entries.push(AuditEntry {
+                id,
+                at: timestamp(Some(at)).unwrap_or_default(),
+                device_id: device_id.map(|bytes| Uuid::from_slice(&bytes)).transpose()?,
+                event,
+                detail,
+            });
+        }
+        Ok(entries)
+    }
+
     pub fn count_devices(&self) -> Result<u32> {
         let mut stmt = self
             .conn
@@ -185,19 +993,20 @@ impl Db {
         Ok(count)
     }
 
+    pub fn count_devices_with_status(&self, status: DeviceStatus) -> Result<u32> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT COUNT(*) FROM registered_devices WHERE status = ?1")?;
+        let count: u32 = stmt.query_row([status.as_str()], |row| row.get(0))?;
+        Ok(count)
+    }
+
     pub fn print_all_devices(&self) -> Result<(), ICTError> {
         let mut stmt = self
             .conn
-            .prepare("SELECT id, wrapped_pk, totp_secret, authorized FROM registered_devices")?;
-
-        let device_iter = stmt.query_map([], |row| {
-            Ok(Device::new(
-                &row.get(0)?,
-                &row.get(1)?,
-                &row.get(2)?,
-                row.get(3)?,
-            ))
-        })?;
+            .prepare(&format!("SELECT {} FROM registered_devices", DEVICE_COLUMNS))?;
+
+        let device_iter = stmt.query_map([], |row| Ok(Device::from_row(row)))?;
 
         for device in device_iter {
             println!("{:?}", device?);
@@ -42,6 +42,63 @@ pub enum ICTError {
     #[error("String error")]
     StringError(#[from] std::string::FromUtf8Error),
 
+    #[error("JSON error: {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("Configuration error: {0}")]
+    Config(#[from] config::ConfigError),
+
+    #[error("Interlock conflict: {0}")]
+    Interlock(String),
+
+    #[error("Outside of schedule: {0}")]
+    OutsideSchedule(String),
+
+    #[error("Quota exceeded: {0}")]
+    QuotaExceeded(String),
+
+    #[error("Not found: {0}")]
+    NotFound(String),
+
+    #[error("Invalid status change: {0}")]
+    StatusChange(String),
+
+    #[error("The server is shutting down")]
+    ShuttingDown,
+
     #[error("Custom error: {0}")]
     Custom(String),
 }
+
+impl ICTError {
+    // Process exit code: 1 failure, 3 not found,
+    // 4 refused by the lifecycle, an interlock, a schedule or a quota (2 is left to clap)
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ICTError::NotFound(_) => 3,
+            ICTError::StatusChange(_)
+            | ICTError::Interlock(_)
+            | ICTError::OutsideSchedule(_)
+            | ICTError::QuotaExceeded(_) => 4,
+            _ => 1,
+        }
+    }
+
+    // Short label for metrics
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ICTError::Interlock(_) => "interlock",
+            ICTError::OutsideSchedule(_) => "outside_schedule",
+            ICTError::QuotaExceeded(_) => "quota_exceeded",
+            ICTError::NotFound(_) => "not_found",
+            ICTError::StatusChange(_) => "status_change",
+            ICTError::ShuttingDown => "shutting_down",
+            ICTError::Sqlite(_) => "database",
+            ICTError::Uuid(_) => "invalid_uuid",
+            ICTError::Json(_) => "invalid_json",
+            ICTError::DecodeError(_) | ICTError::SignatureError(_) | ICTError::PKError(_) => "invalid_signature",
+            ICTError::TOTP(_) | ICTError::Secret(_) => "totp",
+            _ => "refused",
+        }
+    }
+}
@@ -0,0 +1,391 @@
+use chrono::{DateTime, Duration, Utc};
+use hmac::{Hmac, Mac};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::sync::{Condvar, Mutex, MutexGuard, OnceLock, TryLockError};
+use std::thread;
+use uuid::Uuid;
+
+use crate::ict_config::{EventKind, Events, Webhook};
+use crate::ict_db::{Db, Device, DeviceStatus};
+use crate::ict_errors::ICTError;
+use crate::ict_inputs::InputState;
+use crate::ict_metrics::metrics;
+use crate::ict_mqtt;
+use crate::ict_operations::OperateStatus;
+
+static NOTIFIER: OnceLock<Notifier> = OnceLock::new();
+// held while posting, so that a flush and the worker never post the same entry
+static DELIVERING: Mutex<()> = Mutex::new(());
+
+// Entries handed to the webhooks per pass of the worker
+const BATCH_SIZE: u32 = 50;
+// How long the worker sleeps when nothing wakes it, events queued by the command line are picked up then
+const POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+const REQUEST_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+// First retry delay, doubled on each failed attempt up to MAX_BACKOFF
+const BACKOFF_BASE: i64 = 5;
+const MAX_BACKOFF: i64 = 3600;
+// Delivered and failed entries are kept that long for troubleshooting
+const OUTBOX_KEEP_DAYS: i64 = 7;
+
+// What is posted to a webhook without a template, and the values a template can use
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Event {
+    // the same on every attempt, receivers can use it to drop duplicates
+    pub id: Uuid,
+    pub event: EventKind,
+    pub at: DateTime<Utc>,
+    pub device: Option<Uuid>,
+    pub device_name: Option<String>,
+    pub summary: String,
+    pub data: Value,
+}
+
+pub fn notifier() -> &'static Notifier {
+    NOTIFIER.get_or_init(Notifier::default)
+}
+
+// Configures the process wide notifier, failures counted so far are kept
+pub fn configure(webhooks: Vec<Webhook>, events: Events) {
+    let mut state = notifier().lock();
+    state.webhooks = webhooks;
+    state.events = events;
+}
+
+// Queues events in the outbox of the database, a worker posts them to the webhooks,
+// they are published right away to MQTT
+#[derive(Default)]
+pub struct Notifier {
+    state: Mutex<State>,
+    wake: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    webhooks: Vec<Webhook>,
+    events: Events,
+    // device -> recent operate failures
+    failures: HashMap<String, Vec<DateTime<Utc>>>,
+    // set when events were queued since the worker last looked
+    queued: bool,
+}
+
+impl Notifier {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn webhooks(&self) -> Vec<Webhook> {
+        self.lock().webhooks.clone()
+    }
+
+    pub fn wants(&self, kind: EventKind) -> bool {
+        ict_mqtt::wants(kind) || self.lock().webhooks.iter().any(|webhook| webhook.wants(kind))
+    }
+
+    // Never fails the operation that triggered the event, problems are only logged
+    pub fn notify(&self, db: &Db, kind: EventKind, device: Option<(Uuid, Option<String>)>, summary: String, data: Value) {
+        let webhooks: Vec<Webhook> = self.webhooks().into_iter().filter(|webhook| webhook.wants(kind)).collect();
+        let mqtt = ict_mqtt::wants(kind);
+        if webhooks.is_empty() && !mqtt {
+            return;
+        }
+        let (device, device_name) = device.map_or((None, None), |(id, name)| (Some(id), name));
+        let event = Event { id: Uuid::new_v4(), event: kind, at: Utc::now(), device, device_name, summary, data };
+        if mqtt {
+            ict_mqtt::publish_event(&event);
+        }
+        if webhooks.is_empty() {
+            return;
+        }
+        let queued = serde_json::to_string(&event).map_err(ICTError::from).and_then(|json| {
+            db.transaction(|db| {
+                for webhook in &webhooks {
+                    db.add_outbox(&webhook.name, &json, event.at)?;
+                }
+                Ok(())
+            })
+        });
+        match queued {
+            Ok(()) => {
+                self.lock().queued = true;
+                self.wake.notify_all();
+            }
+            Err(e) => {
+                error!("Failed to queue the {} event with {}", kind, e);
+                metrics().record_error(&e);
+            }
+        }
+    }
+
+    pub fn registered(&self, db: &Db, device: &Device) {
+        let summary = format!("{} registered and waits for review", name(device.id, &device.display_name));
+        let data = json!({ "status": device.status });
+        self.notify(db, EventKind::Register, Some((device.id, device.display_name.clone())), summary, data);
+    }
+
+    pub fn status_changed(&self, db: &Db, device: &Device, status: DeviceStatus, reason: Option<&str>) {
+        let summary = format!("{} is now {}", name(device.id, &device.display_name), status);
+        let data = json!({ "from": device.status, "to": status, "reason": reason });
+        self.notify(db, EventKind::StatusChange, Some((device.id, device.display_name.clone())), summary, data);
+    }
+
+    // Only relays that actuated open a door, pending quorum approvals do not
+    pub fn operated(&self, db: &Db, device: &Device, status: &OperateStatus, remote_addr: Option<&str>) {
+        self.lock().failures.remove(&device.id.to_string());
+        if status.operated.is_empty() || !self.wants(EventKind::Operate) {
+            return;
+        }
+        let relays: Vec<String> = status.operated.iter().map(|relay| relay.to_string()).collect();
+        let summary = format!("{} operated relays {}", name(device.id, &device.display_name), relays.join(", "));
+        let data = json!({ "relays": status.operated, "inputs": status.inputs, "remote_addr": remote_addr });
+        self.notify(db, EventKind::Operate, Some((device.id, device.display_name.clone())), summary, data);
+    }
+
+    pub fn input_changed(&self, db: &Db, input: &InputState) {
+        let summary = format!("{} is now {}", input.name, if input.active { "active" } else { "inactive" });
+        let data = json!({ "input": input.name, "pin": input.pin, "relay": input.relay, "active": input.active });
+        self.notify(db, EventKind::InputChange, None, summary, data);
+    }
+
+    // Every failure_threshold failures of a device within failure_window make one event
+    pub fn operate_failed(&self, db: &Db, uuid_as_str: &str, error: &ICTError, remote_addr: Option<&str>) {
+        // the server failed or is stopping, not the client
+        if let ICTError::Sqlite(_) | ICTError::ShuttingDown = error {
+            return;
+        }
+        let id = Uuid::parse_str(uuid_as_str).ok();
+        let key = id.map_or(uuid_as_str.to_string(), |id| id.to_string());
+        let now = Utc::now();
+        let (failures, window) = {
+            let mut state = self.lock();
+            let window = state.events.failure_window;
+            let threshold = state.events.failure_threshold as usize;
+            let since = now - Duration::seconds(window as i64);
+            state.failures.retain(|_, times| times.last().is_some_and(|at| *at > since));
+            let times = state.failures.entry(key.clone()).or_default();
+            times.retain(|at| *at > since);
+            times.push(now);
+            if times.len() < threshold {
+                return;
+            }
+            let failures = times.len();
+            state.failures.remove(&key);
+            (failures, window)
+        };
+        if !self.wants(EventKind::OperateFailures) {
+            return;
+        }
+        let device = id.map(|id| (id, db.get_device(id).ok().flatten().and_then(|device| device.display_name)));
+        let who = device.as_ref().map_or(key, |(id, display_name)| name(*id, display_name));
+        let summary = format!("{} failed to operate {} times", who, failures);
+        let data = json!({
+            "failures": failures,
+            "window": window,
+            "error": error.to_string(),
+            "kind": error.kind(),
+            "remote_addr": remote_addr,
+        });
+        self.notify(db, EventKind::OperateFailures, device, summary, data);
+    }
+
+    // Blocks until events are queued or the timeout passes
+    fn wait(&self, timeout: std::time::Duration) {
+        let state = self.lock();
+        let (mut state, _) = self
+            .wake
+            .wait_timeout_while(state, timeout, |state| !state.queued)
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        state.queued = false;
+    }
+}
+
+fn name(id: Uuid, display_name: &Option<String>) -> String {
+    match display_name {
+        Some(display_name) => format!("{} ({})", display_name, id),
+        None => id.to_string(),
+    }
+}
+
+// Replaces each {{path}} by the value at that path of the event, escaped to fit in a JSON string
+pub fn render(template: &str, event: &Value) -> String {
+    let mut rendered = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        rendered.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let Some(end) = after.find("}}") else {
+            rendered.push_str(&rest[start..]);
+            return rendered;
+        };
+        let value = after[..end].trim().split('.').try_fold(event, |value, key| value.get(key));
+        let text = match value {
+            None | Some(Value::Null) => String::new(),
+            Some(Value::String(text)) => text.clone(),
+            Some(other) => other.to_string(),
+        };
+        let escaped = Value::String(text).to_string();
+        rendered.push_str(&escaped[1..escaped.len() - 1]);
+        rest = &after[end + 2..];
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+// A template must render to JSON whatever the values of the event
+pub fn check_template(template: &str) -> Result<(), String> {
+    let sample = Event {
+        id: Uuid::nil(),
+        event: EventKind::Operate,
+        at: Utc::now(),
+        device: Some(Uuid::nil()),
+        device_name: Some("a \"quoted\" name".to_string()),
+        summary: "a \"quoted\" name operated relays 1".to_string(),
+        data: json!({ "relays": [1], "remote_addr": null }),
+    };
+    let event = serde_json::to_value(&sample).map_err(|e| e.to_string())?;
+    serde_json::from_str::<Value>(&render(template, &event))
+        .map(|_| ())
+        .map_err(|e| format!("does not render to JSON: {}", e))
+}
+
+// Value of the X-ICT-Signature header, the HMAC-SHA256 of the body keyed with the webhook secret
+pub fn signature(secret: &str, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(body.as_bytes());
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+// Delay before the attempt following the given number of failed attempts
+pub fn backoff(attempts: u32) -> Duration {
+    let doubled = BACKOFF_BASE.saturating_mul(1 << attempts.saturating_sub(1).min(20));
+    Duration::seconds(doubled.min(MAX_BACKOFF))
+}
+
+fn post(webhook: &Webhook, json: &str, timeout: std::time::Duration) -> Result<(), String> {
+    let event: Event = serde_json::from_str(json).map_err(|e| format!("invalid event in the outbox: {}", e))?;
+    let body = match &webhook.template {
+        Some(template) => render(template, &serde_json::to_value(&event).map_err(|e| e.to_string())?),
+        None => json.to_string(),
+    };
+    let mut request = ureq::post(&webhook.url)
+        .timeout(timeout)
+        .set("Content-Type", "application/json")
+        .set("X-ICT-Event", event.event.as_str())
+        .set("X-ICT-Delivery", &event.id.to_string());
+    if let Some(secret) = &webhook.secret {
+        request = request.set("X-ICT-Signature", &signature(secret, &body));
+    }
+    request.send_string(&body).map(|_| ()).map_err(|e| e.to_string())
+}
+
+// Posts the entries that are due, returns how many were attempted
+pub fn deliver_due(db: &Db, now: DateTime<Utc>) -> Result<usize, ICTError> {
+    let _delivering = DELIVERING.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    deliver(db, now, None)
+}
+
+// Stops before the deadline, a request gets at most the time left
+fn deliver(db: &Db, now: DateTime<Utc>, deadline: Option<std::time::Instant>) -> Result<usize, ICTError> {
+    let webhooks = notifier().webhooks();
+    let entries = db.get_outbox(Some(now), BATCH_SIZE)?;
+    let mut attempted = 0;
+    for entry in &entries {
+        let timeout = match deadline {
+            Some(deadline) => {
+                let left = deadline.saturating_duration_since(std::time::Instant::now());
+                if left.is_zero() {
+                    break;
+                }
+                left.min(REQUEST_TIMEOUT)
+            }
+            None => REQUEST_TIMEOUT,
+        };
+        attempted += 1;
+        let attempts = entry.attempts + 1;
+        let Some(webhook) = webhooks.iter().find(|webhook| webhook.name == entry.webhook) else {
+            warn!("Dropping event {} of webhook {} which is no longer configured", entry.id, entry.webhook);
+            db.set_outbox_failed(entry.id, entry.attempts, "webhook is no longer configured", None, now)?;
+            continue;
+        };
+        match post(webhook, &entry.event, timeout) {
+            Ok(()) => {
+                info!("Delivered event {} to webhook {}", entry.id, webhook.name);
+                db.set_outbox_delivered(entry.id, attempts, Utc::now())?;
+            }
+            Err(e) if attempts >= webhook.max_attempts => {
+                error!("Giving up event {} to webhook {} after {} attempts, last with {}", entry.id, webhook.name, attempts, e);
+                db.set_outbox_failed(entry.id, attempts, &e, None, Utc::now())?;
+            }
+            Err(e) => {
+                let next = Utc::now() + backoff(attempts);
+                warn!("Failed event {} to webhook {} with {}, retrying at {}", entry.id, webhook.name, e, next);
+                db.set_outbox_failed(entry.id, attempts, &e, Some(next), Utc::now())?;
+            }
+        }
+    }
+    Ok(attempted)
+}
+
+// Delivers what is due before the server stops, until the deadline; what fails or is left
+// stays in the outbox for the next start
+pub fn flush(db: &Db, deadline: std::time::Instant) -> Result<usize, ICTError> {
+    // the worker may be in the middle of a post, it is only waited for until the deadline
+    let _delivering = loop {
+        match DELIVERING.try_lock() {
+            Ok(delivering) => break delivering,
+            Err(TryLockError::Poisoned(poisoned)) => break poisoned.into_inner(),
+            Err(TryLockError::WouldBlock) if std::time::Instant::now() < deadline => {
+                thread::sleep(std::time::Duration::from_millis(10))
+            }
+            Err(TryLockError::WouldBlock) => return Ok(0),
+        }
+    };
+    let mut attempted = 0;
+    while std::time::Instant::now() < deadline {
+        let batch = deliver(db, Utc::now(), Some(deadline))?;
+        attempted += batch;
+        if batch < BATCH_SIZE as usize {
+            break;
+        }
+    }
+    Ok(attempted)
+}
+
+// Delivers in the background so that requests never wait on a webhook
+pub fn start_worker(db_path: Option<String>) {
+    thread::spawn(move || {
+        let db = match Db::newg(db_path) {
+            Ok(db) => db,
+            Err(e) => {
+                error!("Could not instantiate Db for the webhooks with {}, events stay in the outbox", e);
+                return;
+            }
+        };
+        let mut pruned_at: Option<DateTime<Utc>> = None;
+        loop {
+            let now = Utc::now();
+            if pruned_at.is_none_or(|at| now - at > Duration::hours(1)) {
+                match db.prune_outbox(now - Duration::days(OUTBOX_KEEP_DAYS)) {
+                    Ok(pruned) if pruned > 0 => info!("Pruned {} old webhook deliveries", pruned),
+                    Ok(_) => {}
+                    Err(e) => error!("Failed pruning the outbox with {}", e),
+                }
+                pruned_at = Some(now);
+            }
+            match deliver_due(&db, now) {
+                Ok(attempted) if attempted as u32 == BATCH_SIZE => continue,
+                Ok(_) => {}
+                Err(e) => {
+                    error!("Failed delivering webhooks with {}", e);
+                    metrics().record_error(&e);
+                }
+            }
+            notifier().wait(POLL_INTERVAL);
+        }
+    });
+}
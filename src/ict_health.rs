@@ -0,0 +1,135 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::Instant;
+use x509_cert::der::DecodePem;
+use x509_cert::Certificate;
+
+use crate::ict_config::Settings;
+use crate::ict_db::Db;
+use crate::ict_migrations;
+use crate::ict_inputs;
+use crate::ict_relays;
+
+// A certificate expiring sooner than this makes the server not ready, leaving time to renew it
+const CERT_EXPIRY_MARGIN_DAYS: i64 = 14;
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthStatus {
+    Ok,
+    Fail,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Check {
+    pub status: HealthStatus,
+    pub detail: String,
+}
+
+impl Check {
+    fn from(result: Result<String, String>) -> Self {
+        match result {
+            Ok(detail) => Check { status: HealthStatus::Ok, detail },
+            Err(detail) => Check { status: HealthStatus::Fail, detail },
+        }
+    }
+}
+
+// The JSON document of /health/live and /health/ready, status is fail if any check fails
+#[derive(Debug, Serialize)]
+pub struct Health {
+    pub status: HealthStatus,
+    pub version: &'static str,
+    pub uptime: u64,
+    pub checked_at: DateTime<Utc>,
+    pub checks: BTreeMap<&'static str, Check>,
+}
+
+impl Health {
+    fn new(started: Instant, checks: BTreeMap<&'static str, Check>) -> Self {
+        let failed = checks.values().any(|check| check.status == HealthStatus::Fail);
+        Health {
+            status: if failed { HealthStatus::Fail } else { HealthStatus::Ok },
+            version: env!("CARGO_PKG_VERSION"),
+            uptime: started.elapsed().as_secs(),
+            checked_at: Utc::now(),
+            checks,
+        }
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.status == HealthStatus::Ok
+    }
+}
+
+// The process answers, nothing else is checked
+pub fn live(started: Instant) -> Health {
+    Health::new(started, BTreeMap::new())
+}
+
+// Whether the server can do its job: database, relays, inputs, clock and certificate
+pub fn ready(db_path: Option<&str>, settings: &Settings, started: Instant) -> Health {
+    let mut checks = BTreeMap::new();
+    checks.insert("database", Check::from(database(db_path)));
+    checks.insert("relays", Check::from(ict_relays::driver_status()));
+    if !settings.inputs.is_empty() {
+        checks.insert("inputs", Check::from(ict_inputs::status(&settings.inputs)));
+    }
+    checks.insert("clock", Check::from(clock()));
+    checks.insert("certificate", Check::from(certificate(settings.web.tls_path.as_deref(), Utc::now())));
+    Health::new(started, checks)
+}
+
+fn database(db_path: Option<&str>) -> Result<String, String> {
+    let Some(db_path) = db_path else {
+        return Ok("in memory".to_string());
+    };
+    let db = Db::open_existing(db_path).map_err(|e| format!("cannot open {}: {}", db_path, e))?;
+    let result = db.quick_check().map_err(|e| format!("quick check of {} failed: {}", db_path, e))?;
+    if result != "ok" {
+        return Err(format!("quick check of {} found corruption: {}", db_path, result));
+    }
+    let version = db.schema_version().map_err(|e| e.to_string())?;
+    if version != ict_migrations::latest_version() {
+        return Err(format!("schema version {} instead of {}", version, ict_migrations::latest_version()));
+    }
+    Ok(format!("{} passed the quick check", db_path))
+}
+
+// Asks the kernel, which knows whether NTP (or any other source) disciplines the clock
+#[cfg(target_os = "linux")]
+fn clock() -> Result<String, String> {
+    // SAFETY: timex is plain data and modes = 0 only reads the kernel state
+    let mut timex: libc::timex = unsafe { std::mem::zeroed() };
+    let state = unsafe { libc::adjtimex(&mut timex) };
+    match state {
+        -1 => Err(format!("cannot read the clock state: {}", std::io::Error::last_os_error())),
+        libc::TIME_ERROR => Err("the kernel reports the clock as not synchronized".to_string()),
+        _ => Ok(format!("synchronized, estimated error {} us", timex.esterror)),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn clock() -> Result<String, String> {
+    Ok("not checked on this platform".to_string())
+}
+
+pub fn certificate(tls_path: Option<&str>, now: DateTime<Utc>) -> Result<String, String> {
+    let Some(tls_path) = tls_path else {
+        return Ok("no web.tls_path configured".to_string());
+    };
+    let path = Path::new(tls_path).join("cert.pem");
+    let pem = std::fs::read(&path).map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+    let certificate = Certificate::from_pem(&pem).map_err(|e| format!("cannot parse {}: {}", path.display(), e))?;
+    let not_after = certificate.tbs_certificate.validity.not_after.to_unix_duration();
+    let not_after = DateTime::<Utc>::from_timestamp(not_after.as_secs() as i64, 0).unwrap_or_default();
+    if not_after <= now {
+        Err(format!("{} expired at {}", path.display(), not_after))
+    } else if not_after - now < Duration::days(CERT_EXPIRY_MARGIN_DAYS) {
+        Err(format!("{} expires at {}, in {} days", path.display(), not_after, (not_after - now).num_days()))
+    } else {
+        Ok(format!("{} valid until {}", path.display(), not_after))
+    }
+}
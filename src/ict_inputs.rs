@@ -0,0 +1,230 @@
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::ict_config::{InputConfig, Level};
+use crate::ict_db::Db;
+use crate::ict_errors::ICTError;
+use crate::ict_events;
+use crate::ict_relays::{self, InputPins};
+use crate::ict_reload::LiveSettings;
+
+static MONITOR: OnceLock<InputMonitor> = OnceLock::new();
+
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+// Key of the lockout in the server state of the database
+const LOCKOUT_STATE: &str = "lockout";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InputState {
+    pub name: String,
+    pub pin: u8,
+    pub relay: Option<u8>,
+    pub active: bool,
+    // when the input took its current state, or when monitoring started
+    pub since: DateTime<Utc>,
+    pub last_activated: Option<DateTime<Utc>>,
+}
+
+// What an operate response tells about the inputs of the operated relays
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct InputReport {
+    pub name: String,
+    pub active: bool,
+    // went active since the relays were energized, ie the door did open
+    pub activated: bool,
+}
+
+pub fn monitor() -> &'static InputMonitor {
+    MONITOR.get_or_init(InputMonitor::default)
+}
+
+// Debounced state of the inputs, shared by the polling thread and the requests
+#[derive(Default)]
+pub struct InputMonitor {
+    inputs: Mutex<Vec<Tracked>>,
+    // while set, inputs with triggers do not fire their relays
+    lockout: Mutex<bool>,
+}
+
+struct Tracked {
+    config: InputConfig,
+    state: InputState,
+    // level last read and since when it has been read, it becomes the state once it held for the debounce
+    reading: bool,
+    reading_since: Instant,
+}
+
+impl InputMonitor {
+    // Takes the levels read at startup as the initial states, without debounce
+    pub fn configure(&self, inputs: &[InputConfig], levels: &[bool], now: Instant) {
+        let at = Utc::now();
+        *self.lock() = inputs
+            .iter()
+            .zip(levels)
+            .map(|(config, level)| {
+                let active = is_active(config, *level);
+                Tracked {
+                    config: config.clone(),
+                    state: InputState {
+                        name: config.name.clone(),
+                        pin: config.pin,
+                        relay: config.relay,
+                        active,
+                        since: at,
+                        last_activated: None,
+                    },
+                    reading: active,
+                    reading_since: now,
+                }
+            })
+            .collect();
+    }
+
+    // Feeds the levels just read, returns the inputs whose debounced state changed
+    pub fn sample(&self, levels: &[bool], now: Instant) -> Vec<InputState> {
+        let mut changed = Vec::new();
+        for (input, level) in self.lock().iter_mut().zip(levels) {
+            let reading = is_active(&input.config, *level);
+            if reading != input.reading {
+                input.reading = reading;
+                input.reading_since = now;
+            }
+            let debounce = Duration::from_millis(input.config.debounce);
+            if input.reading != input.state.active && now.duration_since(input.reading_since) >= debounce {
+                let at = Utc::now();
+                input.state.active = input.reading;
+                input.state.since = at;
+                if input.reading {
+                    input.state.last_activated = Some(at);
+                }
+                changed.push(input.state.clone());
+            }
+        }
+        changed
+    }
+
+    pub fn states(&self) -> Vec<InputState> {
+        self.lock().iter().map(|input| input.state.clone()).collect()
+    }
+
+    // The inputs attached to the given relays
+    pub fn report(&self, relays: &[u8], since: DateTime<Utc>) -> Vec<InputReport> {
+        self.lock()
+            .iter()
+            .filter(|input| input.state.relay.is_some_and(|relay| relays.contains(&relay)))
+            .map(|input| InputReport {
+                name: input.state.name.clone(),
+                active: input.state.active,
+                activated: input.state.last_activated.is_some_and(|at| at >= since),
+            })
+            .collect()
+    }
+
+    pub fn locked_out(&self) -> bool {
+        *self.lockout.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn set_locked_out(&self, locked_out: bool) {
+        *self.lockout.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = locked_out;
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<Tracked>> {
+        self.inputs.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+fn is_active(config: &InputConfig, high: bool) -> bool {
+    high == (config.active == Level::High)
+}
+
+// Polls the input pins in the background, each change is logged and becomes an input_change event,
+// an input with triggers going active pulses its relays
+pub fn start(live: Arc<LiveSettings>, db_path: Option<String>) -> Result<(), ICTError> {
+    let inputs = live.current().inputs.clone();
+    let db = Db::newg(db_path.clone())?;
+    restore_lockout(&db)?;
+    if inputs.is_empty() {
+        return Ok(());
+    }
+    let pins = InputPins::open(&inputs)?;
+    monitor().configure(&inputs, &pins.levels(), Instant::now());
+    for input in monitor().states() {
+        info!("Input {} on pin {} is {}", input.name, input.pin, if input.active { "active" } else { "inactive" });
+    }
+    thread::spawn(move || loop {
+        thread::sleep(POLL_INTERVAL);
+        for input in monitor().sample(&pins.levels(), Instant::now()) {
+            info!("Input {} on pin {} is now {}", input.name, input.pin, if input.active { "active" } else { "inactive" });
+            ict_events::notifier().input_changed(&db, &input);
+            let Some(config) = inputs.iter().find(|config| config.name == input.name && !config.triggers.is_empty()) else {
+                continue;
+            };
+            if input.active {
+                // the pulse holds the relays for close_duration, polling goes on meanwhile
+                let (config, live, db_path) = (config.clone(), live.clone(), db_path.clone());
+                thread::spawn(move || {
+                    let fired = Db::newg(db_path).and_then(|db| trigger(&db, &config, live.current().pi.close_duration));
+                    if let Err(e) = fired {
+                        error!("Failed local trigger of input {} with {}", config.name, e);
+                    }
+                });
+            }
+        }
+    });
+    Ok(())
+}
+
+// Pulses the relays of an input like operate does, returns false when local triggers are locked out
+pub fn trigger(db: &Db, input: &InputConfig, close_duration: u64) -> Result<bool, ICTError> {
+    let at = Utc::now();
+    let relays = input.triggers.iter().map(|relay| relay.to_string()).collect::<Vec<String>>().join(" ");
+    if monitor().locked_out() {
+        warn!("Input {} did not trigger relays {}, local triggers are locked out", input.name, relays);
+        db.add_audit(at, None, "local-trigger", &format!("{}: relays {} locked out", input.name, relays))?;
+        return Ok(false);
+    }
+    info!("Input {} triggers relays {}", input.name, relays);
+    let pulsed = ict_relays::controller().pulse(&input.triggers, close_duration, &format!("input {}", input.name));
+    let detail = match &pulsed {
+        Ok(()) => format!("{}: relays {}", input.name, relays),
+        Err(e) => format!("{}: relays {} failed with {}", input.name, relays, e),
+    };
+    db.add_audit(at, None, "local-trigger", &detail)?;
+    pulsed.map(|_| true)
+}
+
+// Disables or re-enables the local triggers, the setting is kept in the database across restarts
+pub fn set_lockout(db: &Db, locked_out: bool) -> Result<(), ICTError> {
+    db.set_state(LOCKOUT_STATE, if locked_out { "on" } else { "off" })?;
+    monitor().set_locked_out(locked_out);
+    info!("Local triggers are {}", if locked_out { "locked out" } else { "enabled" });
+    db.add_audit(Utc::now(), None, "lockout", if locked_out { "on" } else { "off" })
+}
+
+// Takes back the lockout the server had when it stopped, returns whether local triggers are locked out
+pub fn restore_lockout(db: &Db) -> Result<bool, ICTError> {
+    let locked_out = db.get_state(LOCKOUT_STATE)?.as_deref() == Some("on");
+    monitor().set_locked_out(locked_out);
+    if locked_out {
+        warn!("Local triggers are still locked out");
+    }
+    Ok(locked_out)
+}
+
+// Reported by the readiness check, fails if inputs are configured but not monitored
+pub fn status(inputs: &[InputConfig]) -> Result<String, String> {
+    let states = monitor().states();
+    if states.is_empty() && !inputs.is_empty() {
+        return Err("configured but not monitored".to_string());
+    }
+    let states: Vec<String> = states
+        .iter()
+        .map(|input| format!("{} {}", input.name, if input.active { "active" } else { "inactive" }))
+        .collect();
+    Ok(states.join(", "))
+}
@@ -0,0 +1,158 @@
+use chrono::{SecondsFormat, Utc};
+use env_logger::fmt::Formatter;
+use env_logger::Target;
+use log::kv::{self, Key, Value, VisitSource};
+use log::{LevelFilter, Record};
+use serde_json::Map;
+use std::cell::RefCell;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::ict_config::{LogFormat, LogOutput, Logs};
+use crate::ict_errors::ICTError;
+
+thread_local! {
+    // id of the web request served by this thread, added to every entry it logs
+    static REQUEST_ID: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+pub fn set_request_id(id: Option<String>) {
+    REQUEST_ID.with(|current| *current.borrow_mut() = id);
+}
+
+// The level is applied separately through log::set_max_level so that a reload can change it
+pub fn init(logs: &Logs) -> Result<(), ICTError> {
+    let mut builder = env_logger::Builder::from_default_env();
+    builder.filter_level(LevelFilter::Trace);
+    match logs.format {
+        LogFormat::Text => builder.format(format_text),
+        LogFormat::Json => builder.format(format_json),
+    };
+    if let (LogOutput::File, Some(file)) = (logs.output, &logs.file) {
+        let file = RotatingFile::open(
+            Path::new(file),
+            logs.max_size_mb * 1024 * 1024,
+            Duration::from_secs(logs.max_age_hours * 3600),
+            logs.keep,
+        )?;
+        builder.target(Target::Pipe(Box::new(file)));
+    }
+    builder.try_init().map_err(|e| ICTError::Custom(format!("Failed to set up logging with {}", e)))
+}
+
+// [time LEVEL target] message key=value...
+fn format_text(buf: &mut Formatter, record: &Record) -> io::Result<()> {
+    let mut fields = String::new();
+    for (key, value) in fields_of(record) {
+        match value {
+            serde_json::Value::String(text) => fields.push_str(&format!(" {}={}", key, text)),
+            value => fields.push_str(&format!(" {}={}", key, value)),
+        }
+    }
+    writeln!(buf, "[{} {:<5} {}] {}{}", buf.timestamp_seconds(), record.level(), record.target(), record.args(), fields)
+}
+
+fn format_json(buf: &mut Formatter, record: &Record) -> io::Result<()> {
+    let mut entry = Map::new();
+    entry.insert("time".to_string(), Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true).into());
+    entry.insert("level".to_string(), record.level().as_str().into());
+    entry.insert("target".to_string(), record.target().into());
+    entry.insert("message".to_string(), record.args().to_string().into());
+    entry.extend(fields_of(record));
+    writeln!(buf, "{}", serde_json::Value::Object(entry))
+}
+
+// The request id of the thread, then the key-values of the entry, ie info!(uuid = id; "...")
+fn fields_of(record: &Record) -> Map<String, serde_json::Value> {
+    struct Collect(Map<String, serde_json::Value>);
+    impl<'kvs> VisitSource<'kvs> for Collect {
+        fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+            let value = serde_json::to_value(&value).unwrap_or_else(|_| value.to_string().into());
+            self.0.insert(key.to_string(), value);
+            Ok(())
+        }
+    }
+    let mut fields = Collect(Map::new());
+    if let Some(id) = REQUEST_ID.with(|current| current.borrow().clone()) {
+        fields.0.insert("request_id".to_string(), id.into());
+    }
+    let _ = record.key_values().visit(&mut fields);
+    fields.0
+}
+
+// A log file renamed to .1 (and older ones shifted) when it grows too big or too old
+pub struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    started: SystemTime,
+    max_size: u64,
+    max_age: Duration,
+    keep: usize,
+}
+
+impl RotatingFile {
+    pub fn open(path: &Path, max_size: u64, max_age: Duration, keep: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let metadata = file.metadata()?;
+        Ok(RotatingFile {
+            path: path.to_path_buf(),
+            size: metadata.len(),
+            // a file left by a previous run keeps its age
+            started: metadata.created().or_else(|_| metadata.modified()).unwrap_or_else(|_| SystemTime::now()),
+            file,
+            max_size,
+            max_age,
+            keep,
+        })
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.keep == 0 {
+            std::fs::remove_file(&self.path)?;
+        } else {
+            let _ = std::fs::remove_file(self.rotated(self.keep));
+            for n in (1..self.keep).rev() {
+                let _ = std::fs::rename(self.rotated(n), self.rotated(n + 1));
+            }
+            std::fs::rename(&self.path, self.rotated(1))?;
+        }
+        self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.size = 0;
+        self.started = SystemTime::now();
+        Ok(())
+    }
+
+    fn needs_rotation(&self, incoming: usize) -> bool {
+        let too_big = self.size + incoming as u64 > self.max_size;
+        let too_old = self.started.elapsed().is_ok_and(|age| age >= self.max_age);
+        self.size > 0 && (too_big || too_old)
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.needs_rotation(buf.len()) {
+            // losing the rotation is better than losing the entry
+            if let Err(e) = self.rotate() {
+                eprintln!("Failed to rotate {} with {}", self.path.display(), e);
+            }
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
@@ -0,0 +1,159 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Mutex, MutexGuard, OnceLock};
+use std::time::Duration;
+
+use crate::ict_db::{Db, DeviceStatus};
+use crate::ict_errors::ICTError;
+
+static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+// Upper bounds (seconds) of the request latency buckets, operate includes the relay pulse
+const LATENCY_BUCKETS: [f64; 10] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+pub fn metrics() -> &'static Metrics {
+    METRICS.get_or_init(Metrics::default)
+}
+
+// Counters kept since the server started, exposed in the Prometheus text format
+#[derive(Default)]
+pub struct Metrics {
+    counters: Mutex<Counters>,
+}
+
+#[derive(Default)]
+struct Counters {
+    // (route, status code) -> requests
+    requests: BTreeMap<(String, u16), u64>,
+    latency: BTreeMap<String, Histogram>,
+    // outcome -> operate requests
+    operates: BTreeMap<String, u64>,
+    actuations: BTreeMap<u8, u64>,
+    on_time: BTreeMap<u8, f64>,
+    watchdog_trips: BTreeMap<u8, u64>,
+    quota_exceeded: u64,
+    db_errors: u64,
+}
+
+#[derive(Default)]
+struct Histogram {
+    // cumulative counts per bucket of LATENCY_BUCKETS
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Metrics {
+    pub fn record_request(&self, route: &str, status: u16, elapsed: Duration) {
+        let mut counters = self.lock();
+        *counters.requests.entry((route.to_string(), status)).or_insert(0) += 1;
+        let histogram = counters.latency.entry(route.to_string()).or_default();
+        let seconds = elapsed.as_secs_f64();
+        for (bucket, bound) in histogram.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if seconds <= bound {
+                *bucket += 1;
+            }
+        }
+        histogram.count += 1;
+        histogram.sum += seconds;
+    }
+
+    // Outcome of an operate request, "success", "pending" or the kind of error
+    pub fn record_operate(&self, result: Result<&str, &ICTError>) {
+        let outcome = match result {
+            Ok(outcome) => outcome,
+            Err(e) => {
+                self.record_error(e);
+                e.kind()
+            }
+        };
+        let mut counters = self.lock();
+        if outcome == "quota_exceeded" {
+            counters.quota_exceeded += 1;
+        }
+        *counters.operates.entry(outcome.to_string()).or_insert(0) += 1;
+    }
+
+    pub fn record_actuation(&self, relay: u8, on_time: Duration) {
+        let mut counters = self.lock();
+        *counters.actuations.entry(relay).or_insert(0) += 1;
+        *counters.on_time.entry(relay).or_insert(0.0) += on_time.as_secs_f64();
+    }
+
+    pub fn record_watchdog_trip(&self, relay: u8) {
+        *self.lock().watchdog_trips.entry(relay).or_insert(0) += 1;
+    }
+
+    pub fn record_error(&self, error: &ICTError) {
+        if matches!(error, ICTError::Sqlite(_)) {
+            self.lock().db_errors += 1;
+        }
+    }
+
+    // The counters, plus the gauges read from the database at scrape time
+    pub fn render(&self, db: &Db) -> String {
+        let pending = db.count_devices_with_status(DeviceStatus::Pending).map_err(|e| {
+            let e = ICTError::from(e);
+            self.record_error(&e);
+            e
+        });
+        let counters = self.lock();
+        let mut out = String::new();
+
+        header(&mut out, "ict_http_requests_total", "counter", "HTTP requests by route and status code");
+        for ((route, status), count) in &counters.requests {
+            let _ = writeln!(out, "ict_http_requests_total{{route=\"{}\",status=\"{}\"}} {}", route, status, count);
+        }
+
+        header(&mut out, "ict_http_request_duration_seconds", "histogram", "HTTP request latency by route");
+        for (route, histogram) in &counters.latency {
+            for (bucket, bound) in histogram.buckets.iter().zip(LATENCY_BUCKETS) {
+                let _ = writeln!(out, "ict_http_request_duration_seconds_bucket{{route=\"{}\",le=\"{}\"}} {}", route, bound, bucket);
+            }
+            let _ = writeln!(out, "ict_http_request_duration_seconds_bucket{{route=\"{}\",le=\"+Inf\"}} {}", route, histogram.count);
+            let _ = writeln!(out, "ict_http_request_duration_seconds_sum{{route=\"{}\"}} {}", route, histogram.sum);
+            let _ = writeln!(out, "ict_http_request_duration_seconds_count{{route=\"{}\"}} {}", route, histogram.count);
+        }
+
+        header(&mut out, "ict_operate_total", "counter", "Operate requests by outcome");
+        for (outcome, count) in &counters.operates {
+            let _ = writeln!(out, "ict_operate_total{{outcome=\"{}\"}} {}", outcome, count);
+        }
+
+        header(&mut out, "ict_relay_actuations_total", "counter", "Pulses of each relay");
+        for (relay, count) in &counters.actuations {
+            let _ = writeln!(out, "ict_relay_actuations_total{{relay=\"{}\"}} {}", relay, count);
+        }
+
+        header(&mut out, "ict_relay_on_seconds_total", "counter", "Time each relay spent energized");
+        for (relay, seconds) in &counters.on_time {
+            let _ = writeln!(out, "ict_relay_on_seconds_total{{relay=\"{}\"}} {}", relay, seconds);
+        }
+
+        header(&mut out, "ict_relay_watchdog_trips_total", "counter", "Relays re-opened by the watchdog after their max_on");
+        for (relay, count) in &counters.watchdog_trips {
+            let _ = writeln!(out, "ict_relay_watchdog_trips_total{{relay=\"{}\"}} {}", relay, count);
+        }
+
+        if let Ok(pending) = pending {
+            header(&mut out, "ict_pending_registrations", "gauge", "Registered clients waiting for a decision");
+            let _ = writeln!(out, "ict_pending_registrations {}", pending);
+        }
+
+        header(&mut out, "ict_quota_exceeded_total", "counter", "Operate requests refused because the client's quota is used up");
+        let _ = writeln!(out, "ict_quota_exceeded_total {}", counters.quota_exceeded);
+
+        header(&mut out, "ict_db_errors_total", "counter", "SQLite errors while serving requests");
+        let _ = writeln!(out, "ict_db_errors_total {}", counters.db_errors);
+        out
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Counters> {
+        self.counters.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
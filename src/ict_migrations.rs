@@ -0,0 +1,306 @@
+use log::info;
+use rusqlite::Connection;
+
+use crate::ict_errors::ICTError;
+
+// A schema change, applied in its own transaction and recorded in PRAGMA user_version.
+// Migrations are never edited once released, new changes get a new version.
+pub struct Migration {
+    pub version: u32,
+    pub description: &'static str,
+    sql: &'static str,
+}
+
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "primary keys, cascading foreign keys and indexes",
+        sql: "
+            CREATE TABLE relays_new (
+                device_id BLOB NOT NULL REFERENCES registered_devices(id) ON DELETE CASCADE,
+                relay_id INTEGER NOT NULL,
+                PRIMARY KEY(device_id, relay_id));
+            INSERT OR IGNORE INTO relays_new (device_id, relay_id)
+                SELECT device_id, relay_id FROM relays WHERE device_id IN (SELECT id FROM registered_devices);
+            DROP TABLE relays;
+            ALTER TABLE relays_new RENAME TO relays;
+
+            CREATE TABLE schedules_new (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                device_id BLOB NOT NULL REFERENCES registered_devices(id) ON DELETE CASCADE,
+                relay_id INTEGER,
+                weekdays INTEGER NOT NULL,
+                start_time TEXT NOT NULL,
+                end_time TEXT NOT NULL,
+                timezone TEXT NOT NULL);
+            INSERT INTO schedules_new
+                SELECT id, device_id, relay_id, weekdays, start_time, end_time, timezone FROM schedules
+                WHERE device_id IN (SELECT id FROM registered_devices);
+            DROP TABLE schedules;
+            ALTER TABLE schedules_new RENAME TO schedules;
+            CREATE INDEX schedules_device ON schedules(device_id);
+
+            CREATE TABLE quotas_new (
+                device_id BLOB PRIMARY KEY REFERENCES registered_devices(id) ON DELETE CASCADE,
+                max_per_hour INTEGER,
+                max_per_day INTEGER,
+                max_total INTEGER,
+                min_interval INTEGER,
+                since INTEGER NOT NULL);
+            INSERT INTO quotas_new
+                SELECT device_id, max_per_hour, max_per_day, max_total, min_interval, since FROM quotas
+                WHERE device_id IN (SELECT id FROM registered_devices);
+            DROP TABLE quotas;
+            ALTER TABLE quotas_new RENAME TO quotas;
+
+            CREATE TABLE quorum_approvals_new (
+                relay_id INTEGER NOT NULL,
+                device_id BLOB NOT NULL REFERENCES registered_devices(id) ON DELETE CASCADE,
+                approved_at INTEGER NOT NULL,
+                PRIMARY KEY(relay_id, device_id));
+            INSERT INTO quorum_approvals_new
+                SELECT relay_id, device_id, approved_at FROM quorum_approvals
+                WHERE device_id IN (SELECT id FROM registered_devices);
+            DROP TABLE quorum_approvals;
+            ALTER TABLE quorum_approvals_new RENAME TO quorum_approvals;
+
+            CREATE TABLE group_members_new (
+                group_id INTEGER NOT NULL REFERENCES device_groups(id) ON DELETE CASCADE,
+                device_id BLOB NOT NULL REFERENCES registered_devices(id) ON DELETE CASCADE,
+                PRIMARY KEY(group_id, device_id));
+            INSERT INTO group_members_new
+                SELECT group_id, device_id FROM group_members
+                WHERE device_id IN (SELECT id FROM registered_devices)
+                AND group_id IN (SELECT id FROM device_groups);
+            DROP TABLE group_members;
+            ALTER TABLE group_members_new RENAME TO group_members;
+            CREATE INDEX group_members_device ON group_members(device_id);
+
+            CREATE TABLE group_relays_new (
+                group_id INTEGER NOT NULL REFERENCES device_groups(id) ON DELETE CASCADE,
+                relay_id INTEGER NOT NULL,
+                PRIMARY KEY(group_id, relay_id));
+            INSERT INTO group_relays_new
+                SELECT group_id, relay_id FROM group_relays WHERE group_id IN (SELECT id FROM device_groups);
+            DROP TABLE group_relays;
+            ALTER TABLE group_relays_new RENAME TO group_relays;
+
+            CREATE TABLE operations_new (
+                device_id BLOB NOT NULL REFERENCES registered_devices(id) ON DELETE CASCADE,
+                operated_at INTEGER NOT NULL);
+            INSERT INTO operations_new
+                SELECT device_id, operated_at FROM operations WHERE device_id IN (SELECT id FROM registered_devices);
+            DROP TABLE operations;
+            ALTER TABLE operations_new RENAME TO operations;
+            CREATE INDEX operations_device_time ON operations(device_id, operated_at);
+        ",
+    },
+    Migration {
+        version: 2,
+        description: "device lifecycle status and audit trail",
+        sql: "
+            ALTER TABLE registered_devices ADD COLUMN status TEXT NOT NULL DEFAULT 'pending'
+                CHECK (status IN ('pending', 'authorized', 'suspended', 'revoked', 'expired'));
+            ALTER TABLE registered_devices ADD COLUMN status_reason TEXT;
+            ALTER TABLE registered_devices ADD COLUMN status_changed_at INTEGER;
+            UPDATE registered_devices SET status = CASE
+                WHEN authorized != 1 THEN 'pending'
+                WHEN valid_until IS NOT NULL AND valid_until <= CAST(strftime('%s', 'now') AS INTEGER) THEN 'expired'
+                ELSE 'authorized' END;
+            ALTER TABLE registered_devices DROP COLUMN authorized;
+
+            CREATE TABLE audit (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                at INTEGER NOT NULL,
+                device_id BLOB,
+                event TEXT NOT NULL,
+                detail TEXT NOT NULL);
+            CREATE INDEX audit_device ON audit(device_id, at);
+        ",
+    },
+    Migration {
+        version: 3,
+        description: "device metadata and activity",
+        sql: "
+            ALTER TABLE registered_devices ADD COLUMN display_name TEXT;
+            ALTER TABLE registered_devices ADD COLUMN owner TEXT;
+            ALTER TABLE registered_devices ADD COLUMN notes TEXT;
+            ALTER TABLE registered_devices ADD COLUMN created_at INTEGER;
+            ALTER TABLE registered_devices ADD COLUMN last_seen_at INTEGER;
+            ALTER TABLE registered_devices ADD COLUMN last_remote_addr TEXT;
+            ALTER TABLE registered_devices ADD COLUMN operation_count INTEGER NOT NULL DEFAULT 0;
+            UPDATE registered_devices SET
+                operation_count = (SELECT COUNT(*) FROM operations WHERE device_id = registered_devices.id),
+                last_seen_at = (SELECT MAX(operated_at) FROM operations WHERE device_id = registered_devices.id);
+        ",
+    },
+    Migration {
+        version: 4,
+        description: "webhook outbox",
+        sql: "
+            CREATE TABLE outbox (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                webhook TEXT NOT NULL,
+                event TEXT NOT NULL,
+                created_at INTEGER NOT NULL,
+                status TEXT NOT NULL DEFAULT 'pending' CHECK (status IN ('pending', 'delivered', 'failed')),
+                attempts INTEGER NOT NULL DEFAULT 0,
+                next_attempt_at INTEGER NOT NULL,
+                last_error TEXT,
+                done_at INTEGER);
+            CREATE INDEX outbox_due ON outbox(status, next_attempt_at);
+        ",
+    },
+    Migration {
+        version: 5,
+        description: "single use MQTT commands",
+        sql: "
+            CREATE TABLE used_commands (
+                device_id BLOB NOT NULL REFERENCES registered_devices(id) ON DELETE CASCADE,
+                message TEXT NOT NULL,
+                used_at INTEGER NOT NULL,
+                PRIMARY KEY(device_id, message));
+            CREATE INDEX used_commands_time ON used_commands(used_at);
+        ",
+    },
+    Migration {
+        version: 6,
+        description: "server state kept across restarts",
+        sql: "
+            CREATE TABLE server_state (
+                key TEXT PRIMARY KEY,
+                value TEXT NOT NULL);
+        ",
+    },
+];
+
+pub fn schema_version(conn: &Connection) -> Result<u32, ICTError> {
+    Ok(conn.query_row("PRAGMA user_version", [], |row| row.get(0))?)
+}
+
+pub fn latest_version() -> u32 {
+    MIGRATIONS.last().map_or(0, |m| m.version)
+}
+
+pub fn pending(conn: &Connection) -> Result<Vec<&'static Migration>, ICTError> {
+    let version = schema_version(conn)?;
+    Ok(MIGRATIONS.iter().filter(|m| m.version > version).collect())
+}
+
+// Applies the pending migrations in order, a dry run rolls each of them back once it succeeded.
+// Returns the migrations that were (or would have been) applied.
+pub fn migrate(conn: &Connection, dry_run: bool) -> Result<Vec<&'static Migration>, ICTError> {
+    let pending = pending(conn)?;
+    if pending.is_empty() {
+        return Ok(pending);
+    }
+    // tables are rebuilt, enforcement would cascade the drops
+    conn.execute_batch("PRAGMA foreign_keys = OFF")?;
+    let result = apply(conn, &pending, dry_run);
+    conn.execute_batch("PRAGMA foreign_keys = ON")?;
+    result.map(|_| pending)
+}
+
+fn apply(conn: &Connection, migrations: &[&Migration], dry_run: bool) -> Result<(), ICTError> {
+    // a dry run keeps everything in one transaction so later migrations see the earlier ones
+    let dry_run_tx = if dry_run { Some(conn.unchecked_transaction()?) } else { None };
+    for migration in migrations {
+        let tx = if dry_run { None } else { Some(conn.unchecked_transaction()?) };
+        if schema_version(conn)? == 0 {
+            legacy_schema(conn)?;
+        }
+        conn.execute_batch(migration.sql).map_err(|e| {
+            ICTError::Custom(format!("Migration {} ({}) failed: {}", migration.version, migration.description, e))
+        })?;
+        let violations: u32 = conn.query_row("SELECT COUNT(*) FROM pragma_foreign_key_check", [], |row| row.get(0))?;
+        if violations > 0 {
+            return Err(ICTError::Custom(format!(
+                "Migration {} ({}) leaves {} foreign key violations",
+                migration.version, migration.description, violations
+            )));
+        }
+        conn.pragma_update(None, "user_version", migration.version)?;
+        if let Some(tx) = tx {
+            tx.commit()?;
+            info!("Applied migration {}: {}", migration.version, migration.description);
+        }
+    }
+    if let Some(tx) = dry_run_tx {
+        tx.rollback()?;
+    }
+    Ok(())
+}
+
+// Schema of the databases created before migrations existed (user_version 0),
+// brought up to date so that migration 1 finds every table and column
+fn legacy_schema(conn: &Connection) -> Result<(), ICTError> {
+    conn.execute_batch(
+        "
+        CREATE TABLE IF NOT EXISTS registered_devices (
+            id BLOB PRIMARY KEY,
+            wrapped_pk BLOB NOT NULL,
+            totp_secret BLOB NOT NULL,
+            authorized INTEGER NOT NULL,
+            valid_from INTEGER,
+            valid_until INTEGER
+        );
+        CREATE TABLE IF NOT EXISTS relays (
+            device_id BLOB NOT NULL,
+            relay_id INTEGER NOT NULL,
+            FOREIGN KEY(device_id) REFERENCES registered_devices(id));
+        CREATE TABLE IF NOT EXISTS schedules (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            device_id BLOB NOT NULL,
+            relay_id INTEGER,
+            weekdays INTEGER NOT NULL,
+            start_time TEXT NOT NULL,
+            end_time TEXT NOT NULL,
+            timezone TEXT NOT NULL,
+            FOREIGN KEY(device_id) REFERENCES registered_devices(id));
+        CREATE TABLE IF NOT EXISTS quotas (
+            device_id BLOB PRIMARY KEY,
+            max_per_hour INTEGER,
+            max_per_day INTEGER,
+            max_total INTEGER,
+            min_interval INTEGER,
+            since INTEGER NOT NULL,
+            FOREIGN KEY(device_id) REFERENCES registered_devices(id));
+        CREATE TABLE IF NOT EXISTS quorum_approvals (
+            relay_id INTEGER NOT NULL,
+            device_id BLOB NOT NULL,
+            approved_at INTEGER NOT NULL,
+            PRIMARY KEY(relay_id, device_id),
+            FOREIGN KEY(device_id) REFERENCES registered_devices(id));
+        CREATE TABLE IF NOT EXISTS device_groups (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            name TEXT NOT NULL UNIQUE);
+        CREATE TABLE IF NOT EXISTS group_members (
+            group_id INTEGER NOT NULL,
+            device_id BLOB NOT NULL,
+            PRIMARY KEY(group_id, device_id),
+            FOREIGN KEY(group_id) REFERENCES device_groups(id),
+            FOREIGN KEY(device_id) REFERENCES registered_devices(id));
+        CREATE TABLE IF NOT EXISTS group_relays (
+            group_id INTEGER NOT NULL,
+            relay_id INTEGER NOT NULL,
+            PRIMARY KEY(group_id, relay_id),
+            FOREIGN KEY(group_id) REFERENCES device_groups(id));
+        CREATE TABLE IF NOT EXISTS operations (
+            device_id BLOB NOT NULL,
+            operated_at INTEGER NOT NULL,
+            FOREIGN KEY(device_id) REFERENCES registered_devices(id));
+        ",
+    )?;
+    add_column_if_missing(conn, "registered_devices", "valid_from", "INTEGER")?;
+    add_column_if_missing(conn, "registered_devices", "valid_until", "INTEGER")?;
+    Ok(())
+}
+
+fn add_column_if_missing(conn: &Connection, table: &str, column: &str, definition: &str) -> Result<(), ICTError> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let columns = stmt.query_map([], |row| row.get::<_, String>(1))?.collect::<Result<Vec<String>, _>>()?;
+    if !columns.iter().any(|c| c == column) {
+        conn.execute(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition), [])?;
+    }
+    Ok(())
+}
@@ -0,0 +1,286 @@
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::sync::{Arc, OnceLock};
+
+use crate::ict_config::{EventKind, Mqtt, RelayConfig, Settings};
+use crate::ict_db::Db;
+use crate::ict_errors::ICTError;
+use crate::ict_events::Event;
+use crate::ict_inputs::InputReport;
+use crate::ict_metrics::metrics;
+use crate::ict_operations::{operate_once, PendingRelay};
+use crate::ict_reload::LiveSettings;
+
+static PUBLISHER: OnceLock<Publisher> = OnceLock::new();
+
+// How long to wait before connecting again after the broker went away
+#[cfg(feature = "mqtt")]
+const RECONNECT_DELAY: std::time::Duration = std::time::Duration::from_secs(5);
+
+// Commands run on a fixed set of threads, those arriving while the queue is full are refused
+#[cfg(feature = "mqtt")]
+const COMMAND_WORKERS: usize = 4;
+#[cfg(feature = "mqtt")]
+const COMMAND_QUEUE: usize = 16;
+
+// Sends (topic, retained, payload) to the broker without blocking
+pub type Publish = Box<dyn Fn(&str, bool, Vec<u8>) + Send + Sync>;
+
+// Publishes relay states and events of the whole process once installed
+pub struct Publisher {
+    mqtt: Mqtt,
+    publish: Publish,
+}
+
+impl Publisher {
+    pub fn new(mqtt: &Mqtt, publish: Publish) -> Self {
+        Publisher { mqtt: mqtt.clone(), publish }
+    }
+}
+
+// Only one publisher per process, the MQTT settings are read at startup
+pub fn install(publisher: Publisher) -> Result<(), ICTError> {
+    PUBLISHER
+        .set(publisher)
+        .map_err(|_| ICTError::Custom("An MQTT publisher is already installed".to_string()))
+}
+
+pub fn status_topic(prefix: &str) -> String {
+    format!("{}/status", prefix)
+}
+
+pub fn relay_topic(prefix: &str, relay: u8) -> String {
+    format!("{}/relay/{}/state", prefix, relay)
+}
+
+pub fn event_topic(prefix: &str, kind: EventKind) -> String {
+    format!("{}/events/{}", prefix, kind)
+}
+
+pub fn command_topic(prefix: &str) -> String {
+    format!("{}/command/operate", prefix)
+}
+
+pub fn result_topic(prefix: &str) -> String {
+    format!("{}/command/result", prefix)
+}
+
+pub fn wants(kind: EventKind) -> bool {
+    PUBLISHER.get().is_some_and(|publisher| publisher.mqtt.wants(kind))
+}
+
+pub fn publish_event(event: &Event) {
+    let Some(publisher) = PUBLISHER.get() else {
+        return;
+    };
+    match serde_json::to_vec(event) {
+        Ok(payload) => (publisher.publish)(&event_topic(&publisher.mqtt.topic_prefix, event.event), false, payload),
+        Err(e) => error!("Failed to serialize the {} event for MQTT with {}", event.event, e),
+    }
+}
+
+// Retained, so a subscriber gets the current state as soon as it subscribes
+pub fn publish_relay(relay: u8, on: bool) {
+    if let Some(publisher) = PUBLISHER.get() {
+        let state = if on { "ON" } else { "OFF" };
+        (publisher.publish)(&relay_topic(&publisher.mqtt.topic_prefix, relay), true, state.as_bytes().to_vec());
+    }
+}
+
+// Home Assistant discovery topic and config of a named relay, shown as a binary sensor
+// since operating it needs a signed command
+pub fn discovery(mqtt: &Mqtt, relay: &RelayConfig) -> Option<(String, Value)> {
+    let (Some(discovery_prefix), Some(name)) = (&mqtt.discovery_prefix, &relay.name) else {
+        return None;
+    };
+    let unique_id = format!("{}_relay_{}", mqtt.client_id, relay.id);
+    let config = json!({
+        "name": name,
+        "unique_id": unique_id,
+        "state_topic": relay_topic(&mqtt.topic_prefix, relay.id),
+        "payload_on": "ON",
+        "payload_off": "OFF",
+        "availability_topic": status_topic(&mqtt.topic_prefix),
+        "device": {
+            "identifiers": [mqtt.client_id],
+            "name": "ICT Server",
+            "manufacturer": "ict_server",
+            "sw_version": env!("CARGO_PKG_VERSION"),
+        },
+    });
+    Some((format!("{}/binary_sensor/{}/config", discovery_prefix, unique_id), config))
+}
+
+// Same body as the HTTP operate request
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OperateCommand {
+    pub id: String,
+    pub totp_message: String,
+    pub signature: String,
+}
+
+// Published on the result topic, outcome is success, pending or the kind of error
+#[derive(Debug, Serialize, PartialEq)]
+pub struct CommandResult {
+    pub id: Option<String>,
+    pub outcome: String,
+    pub operated: Vec<u8>,
+    pub pending: Vec<PendingRelay>,
+    pub inputs: Vec<InputReport>,
+}
+
+// Validates and runs an operate command like the HTTP operate request, a signed message is accepted once
+pub fn handle_command(db: &Db, payload: &[u8], settings: &Settings) -> CommandResult {
+    let command: OperateCommand = match serde_json::from_slice(payload) {
+        Ok(command) => command,
+        Err(e) => {
+            warn!("Invalid MQTT operate command with {}", e);
+            return CommandResult { id: None, outcome: "invalid_json".to_string(), operated: Vec::new(), pending: Vec::new(), inputs: Vec::new() };
+        }
+    };
+    let result = operate_once(db, &command.id, &command.totp_message, &command.signature, settings.totp.sha, &settings.pi.close_duration);
+    metrics().record_operate(match &result {
+        Ok(status) if !status.is_complete() => Ok("pending"),
+        Ok(_) => Ok("success"),
+        Err(e) => Err(e),
+    });
+    match result {
+        Ok(status) => {
+            let outcome = if status.is_complete() { "success" } else { "pending" };
+            info!(uuid = command.id.as_str(), outcome = outcome; "MQTT operate with uuid {}, {:?}", &command.id, status);
+            CommandResult { id: Some(command.id), outcome: outcome.to_string(), operated: status.operated, pending: status.pending, inputs: status.inputs }
+        }
+        Err(e) => {
+            error!(uuid = command.id.as_str(), outcome = e.kind(); "Failed MQTT operate uuid {} with {}", &command.id, e);
+            CommandResult { id: Some(command.id), outcome: e.kind().to_string(), operated: Vec::new(), pending: Vec::new(), inputs: Vec::new() }
+        }
+    }
+}
+
+// Connects to the broker of the settings, if any, and keeps reconnecting in the background
+#[cfg(feature = "mqtt")]
+pub fn start(live: Arc<LiveSettings>, db_path: Option<String>) -> Result<(), ICTError> {
+    use rumqttc::{Client, LastWill, MqttOptions, QoS};
+
+    let mqtt = live.current().mqtt.clone();
+    let Some(host) = mqtt.host.clone() else {
+        return Ok(());
+    };
+    let mut options = MqttOptions::new(&mqtt.client_id, &host, mqtt.port);
+    options.set_keep_alive(std::time::Duration::from_secs(mqtt.keep_alive));
+    options.set_last_will(LastWill::new(status_topic(&mqtt.topic_prefix), "offline", QoS::AtLeastOnce, true));
+    if let Some(username) = &mqtt.username {
+        options.set_credentials(username, mqtt.password.clone().unwrap_or_default());
+    }
+    let (client, connection) = Client::new(options, 100);
+    let publisher = client.clone();
+    install(Publisher::new(
+        &mqtt,
+        Box::new(move |topic, retain, payload| {
+            if let Err(e) = publisher.try_publish(topic, QoS::AtLeastOnce, retain, payload) {
+                warn!("Dropped MQTT message to {} with {}", topic, e);
+            }
+        }),
+    ))?;
+    info!("Connecting to the MQTT broker {}:{}", host, mqtt.port);
+    std::thread::spawn(move || run(client, connection, mqtt, live, db_path));
+    Ok(())
+}
+
+#[cfg(not(feature = "mqtt"))]
+pub fn start(live: Arc<LiveSettings>, _db_path: Option<String>) -> Result<(), ICTError> {
+    match &live.current().mqtt.host {
+        Some(host) => Err(ICTError::Custom(format!("Built without the mqtt feature, not connecting to {}", host))),
+        None => Ok(()),
+    }
+}
+
+#[cfg(feature = "mqtt")]
+fn run(client: rumqttc::Client, mut connection: rumqttc::Connection, mqtt: Mqtt, live: Arc<LiveSettings>, db_path: Option<String>) {
+    use rumqttc::{Event as MqttEvent, Packet};
+    use std::sync::mpsc::sync_channel;
+    use std::sync::Mutex;
+
+    // operate holds the relays for close_duration, the connection must keep being polled meanwhile
+    let (queue, commands_rx) = sync_channel::<Vec<u8>>(COMMAND_QUEUE);
+    let commands_rx = Arc::new(Mutex::new(commands_rx));
+    for _ in 0..COMMAND_WORKERS {
+        let (client, live, db_path, topic, commands_rx) =
+            (client.clone(), live.clone(), db_path.clone(), result_topic(&mqtt.topic_prefix), commands_rx.clone());
+        std::thread::spawn(move || loop {
+            let payload = match commands_rx.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).recv() {
+                Ok(payload) => payload,
+                Err(_) => return,
+            };
+            let result = match Db::newg(db_path.clone()) {
+                Ok(db) => handle_command(&db, &payload, &live.current()),
+                Err(e) => {
+                    error!("Could not instantiate Db while processing an MQTT command with {}", e);
+                    metrics().record_error(&e);
+                    CommandResult { id: None, outcome: e.kind().to_string(), operated: Vec::new(), pending: Vec::new(), inputs: Vec::new() }
+                }
+            };
+            publish_result(&client, &topic, &result);
+        });
+    }
+
+    let commands = command_topic(&mqtt.topic_prefix);
+    for notification in connection.iter() {
+        match notification {
+            Ok(MqttEvent::Incoming(Packet::ConnAck(_))) => {
+                info!("Connected to the MQTT broker");
+                announce(&client, &mqtt, &live.current());
+            }
+            Ok(MqttEvent::Incoming(Packet::Publish(message))) if mqtt.commands && message.topic == commands => {
+                if queue.try_send(message.payload.to_vec()).is_err() {
+                    warn!("Refused an MQTT operate command, {} are already waiting", COMMAND_QUEUE);
+                    let busy = CommandResult { id: None, outcome: "busy".to_string(), operated: Vec::new(), pending: Vec::new(), inputs: Vec::new() };
+                    publish_result(&client, &result_topic(&mqtt.topic_prefix), &busy);
+                }
+            }
+            Ok(_) => {}
+            Err(e) => {
+                warn!("MQTT connection failed with {}, retrying in {:?}", e, RECONNECT_DELAY);
+                std::thread::sleep(RECONNECT_DELAY);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "mqtt")]
+fn publish_result(client: &rumqttc::Client, topic: &str, result: &CommandResult) {
+    match serde_json::to_vec(result) {
+        Ok(payload) => {
+            if let Err(e) = client.try_publish(topic, rumqttc::QoS::AtLeastOnce, false, payload) {
+                warn!("Dropped MQTT command result with {}", e);
+            }
+        }
+        Err(e) => error!("Failed to serialize the MQTT command result with {}", e),
+    }
+}
+
+// Availability, discovery, current relay states and the command subscription, again on each reconnection
+#[cfg(feature = "mqtt")]
+fn announce(client: &rumqttc::Client, mqtt: &Mqtt, settings: &Settings) {
+    use rumqttc::QoS;
+
+    let mut messages = vec![(status_topic(&mqtt.topic_prefix), "online".as_bytes().to_vec())];
+    for relay in &settings.relays {
+        if let Some((topic, config)) = discovery(mqtt, relay) {
+            messages.push((topic, config.to_string().into_bytes()));
+        }
+        let state = if crate::ict_relays::controller().is_active(relay.id) { "ON" } else { "OFF" };
+        messages.push((relay_topic(&mqtt.topic_prefix, relay.id), state.as_bytes().to_vec()));
+    }
+    for (topic, payload) in messages {
+        if let Err(e) = client.try_publish(&topic, QoS::AtLeastOnce, true, payload) {
+            warn!("Dropped MQTT message to {} with {}", topic, e);
+        }
+    }
+    if mqtt.commands {
+        if let Err(e) = client.try_subscribe(command_topic(&mqtt.topic_prefix), QoS::AtLeastOnce) {
+            error!("Failed to subscribe to the MQTT commands with {}", e);
+        }
+    }
+}
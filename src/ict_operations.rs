@@ -5,21 +5,30 @@ use rsa::pkcs8::DecodePublicKey;
 use rsa::{pkcs1v15::Pkcs1v15Encrypt, RsaPublicKey};
 use totp_rs::{Secret, TOTP};
 use uuid::Uuid;
+use chrono::{DateTime, Duration, Utc};
 use log::{info};
 use rand::rngs::OsRng;
 use serde::{Deserialize, Serialize};
 use rsa::pkcs1v15::VerifyingKey;
 use rsa::signature::Verifier;
 use rsa::sha2::Sha256;
-use std::thread;
-use std::time::Duration;
 
+use crate::ict_config::TotpAlgorithm;
 use crate::ict_db::Db;
 use crate::ict_db::Device;
+use crate::ict_db::DeviceStatus;
+use crate::ict_db::Group;
+use crate::ict_db::Quota;
 use crate::ict_errors::ICTError;
+use crate::ict_events;
+use crate::ict_inputs::{self, InputReport};
+use crate::ict_relays::{self, RelayController};
+use crate::ict_schedules::{parse_datetime, Schedule, Weekdays};
 
-#[cfg(feature = "gpio")]
-use rppal::gpio::{Gpio};
+pub const MAX_DISPLAY_NAME: usize = 64;
+
+// A used command message is kept well past the TOTP window that would accept it again
+const USED_COMMAND_RETENTION: i64 = 5;
 
 #[derive(Deserialize,Serialize)]
 pub struct OperationMessage {
@@ -27,41 +36,198 @@ pub struct OperationMessage {
     pub _salt: String,
 }
 
-pub fn register(db: &Db, uuid_as_str: &str, pem_public_key: &str) -> Result<String, ICTError> {
+// Outcome of an operate request, relays needing more devices to agree are pending
+#[derive(Debug, Serialize, PartialEq)]
+pub struct OperateStatus {
+    pub operated: Vec<u8>,
+    pub pending: Vec<PendingRelay>,
+    // sensors of the operated relays, read once the pulse ended
+    pub inputs: Vec<InputReport>,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct PendingRelay {
+    pub relay: u8,
+    pub approvals: u32,
+    pub quorum: u32,
+    // seconds before the oldest approval lapses
+    pub expires_in: i64,
+}
+
+impl OperateStatus {
+    pub fn is_complete(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+// Display names come from unauthenticated requests: terminal escape sequences and
+// control characters are dropped and they are cut to MAX_DISPLAY_NAME characters
+fn clean_display_name(name: &str) -> String {
+    let mut chars = name.chars().peekable();
+    let mut cleaned = String::new();
+    while let Some(c) = chars.next() {
+        match c {
+            // CSI (ESC [ or 0x9b): parameters and intermediates up to a final byte in @..~
+            '\u{9b}' | '\u{1b}' if c == '\u{9b}' || chars.next_if_eq(&'[').is_some() => {
+                for c in chars.by_ref() {
+                    if ('@'..='~').contains(&c) {
+                        break;
+                    }
+                }
+            }
+            // OSC (ESC ]): up to BEL or ESC \
+            '\u{1b}' if chars.peek() == Some(&']') => {
+                while let Some(c) = chars.next() {
+                    if c == '\u{7}' || (c == '\u{1b}' && chars.next_if_eq(&'\\').is_some()) {
+                        break;
+                    }
+                }
+            }
+            // any other escape is ESC, intermediates in ' '../ and a final character
+            '\u{1b}' => {
+                while chars.next_if(|c| (' '..='/').contains(c)).is_some() {}
+                chars.next();
+            }
+            c if c.is_control() => {}
+            c => cleaned.push(c),
+        }
+    }
+    cleaned.trim().chars().take(MAX_DISPLAY_NAME).collect::<String>().trim_end().to_string()
+}
+
+pub fn register(db: &Db, uuid_as_str: &str, pem_public_key: &str, display_name: Option<&str>) -> Result<String, ICTError> {
     let uuid = Uuid::parse_str(uuid_as_str)?;
     let public_key = RsaPublicKey::from_public_key_pem(pem_public_key)?;
     let secret = Secret::generate_secret();
+    let now = Utc::now();
 
-    let device = Device {
-        id: uuid,
-        wrapped_pk: public_key,
-        totp_secret: secret.clone(),
-        authorized: 0,
-    };
+    let mut device = Device::new(uuid, public_key, secret);
+    device.display_name = display_name.map(clean_display_name).filter(|name| !name.is_empty());
+    device.created_at = Some(now);
     db.add_device(&device)?;
+    db.add_audit(now, Some(uuid), "registered", DeviceStatus::Pending.as_str())?;
+    ict_events::notifier().registered(db, &device);
 
-    println!("secret {}",&device.totp_secret.to_encoded().to_string());
-    let encrypted_secret = device.wrapped_pk.encrypt(&mut OsRng, Pkcs1v15Encrypt, &device.totp_secret.to_encoded().to_string().as_bytes())?;
+    let encrypted_secret = device.wrapped_pk.encrypt(&mut OsRng, Pkcs1v15Encrypt, device.totp_secret.to_encoded().to_string().as_bytes())?;
 
     Ok(general_purpose::STANDARD.encode(&encrypted_secret))
 }
 
 pub fn authorize(db: &Db, uuid_as_str: &str) -> Result<(), ICTError> {
-    set_auth(db, uuid_as_str, 1)
+    authorize_between(db, uuid_as_str, None, None, None)
+}
+
+// Authorizes a client for a limited time, either end of the window may be left open
+pub fn authorize_between(
+    db: &Db,
+    uuid_as_str: &str,
+    from: Option<&str>,
+    until: Option<&str>,
+    reason: Option<&str>,
+) -> Result<(), ICTError> {
+    let uuid = Uuid::parse_str(uuid_as_str)?;
+    let valid_from = from.map(parse_datetime).transpose()?;
+    let valid_until = until.map(parse_datetime).transpose()?;
+    if let (Some(from), Some(until)) = (valid_from, valid_until) {
+        if from >= until {
+            return Err(ICTError::Custom("Authorization must start before it ends".to_string()));
+        }
+    }
+    if valid_until.is_some_and(|until| until <= Utc::now()) {
+        return Err(ICTError::Custom("Authorization would already be expired".to_string()));
+    }
+
+    change_status(db, uuid, DeviceStatus::Authorized, reason)?;
+    db.set_validity_on_device(uuid, valid_from, valid_until)?;
+
+    Ok(())
+}
+
+// Suspended clients can be authorized again
+pub fn suspend(db: &Db, uuid_as_str: &str, reason: Option<&str>) -> Result<(), ICTError> {
+    let uuid = Uuid::parse_str(uuid_as_str)?;
+    change_status(db, uuid, DeviceStatus::Suspended, reason)
 }
 
 pub fn unauthorize(db: &Db, uuid_as_str: &str) -> Result<(), ICTError> {
-    set_auth(db, uuid_as_str, 0)
+    suspend(db, uuid_as_str, None)
 }
 
-pub fn set_auth(db: &Db, uuid_as_str: &str, auth_value: u8) -> Result<(), ICTError> {
+// Revoked clients have to be deleted and registered again
+pub fn revoke(db: &Db, uuid_as_str: &str, reason: Option<&str>) -> Result<(), ICTError> {
     let uuid = Uuid::parse_str(uuid_as_str)?;
+    change_status(db, uuid, DeviceStatus::Revoked, reason)
+}
+
+// Moves a client along its lifecycle, refusing the transitions that are not allowed
+fn change_status(db: &Db, uuid: Uuid, status: DeviceStatus, reason: Option<&str>) -> Result<(), ICTError> {
+    let now = Utc::now();
+    let mut device = db.get_device(uuid)?.ok_or(ICTError::NotFound(format!("device {}", uuid)))?;
+    refresh_status(db, &mut device, now)?;
+    if !device.status.can_become(status) {
+        let hint = if device.status == DeviceStatus::Revoked {
+            ", it has to be deleted and registered again"
+        } else {
+            ""
+        };
+        return Err(ICTError::StatusChange(format!(
+            "client {} is {} and cannot become {}{}",
+            uuid, device.status, status, hint
+        )));
+    }
+    record_status(db, &device, status, reason, now)
+}
 
-    db.set_authorization_on_device(uuid, auth_value)?;
+// Records the expiry of an authorization whose window ended
+fn refresh_status(db: &Db, device: &mut Device, now: DateTime<Utc>) -> Result<(), ICTError> {
+    if device.status_at(now) != device.status {
+        let at = device.valid_until.unwrap_or(now);
+        record_status(db, device, DeviceStatus::Expired, Some("authorization window ended"), at)?;
+        device.status = DeviceStatus::Expired;
+    }
+    Ok(())
+}
 
+fn record_status(
+    db: &Db,
+    device: &Device,
+    status: DeviceStatus,
+    reason: Option<&str>,
+    at: DateTime<Utc>,
+) -> Result<(), ICTError> {
+    db.set_device_status(device.id, status, reason, at)?;
+    let detail = match reason {
+        Some(reason) => format!("{} -> {}: {}", device.status, status, reason),
+        None => format!("{} -> {}", device.status, status),
+    };
+    db.add_audit(at, Some(device.id), "status", &detail)?;
+    ict_events::notifier().status_changed(db, device, status, reason);
     Ok(())
 }
 
+// Edits the admin maintained information of a client, None keeps a field and an empty value clears it
+pub fn set_client_info(
+    db: &Db,
+    uuid_as_str: &str,
+    display_name: Option<&str>,
+    owner: Option<&str>,
+    notes: Option<&str>,
+) -> Result<(), ICTError> {
+    let uuid = Uuid::parse_str(uuid_as_str)?;
+    let device = db.get_device(uuid)?.ok_or(ICTError::NotFound(format!("device {}", uuid)))?;
+    let merge = |value: Option<&str>, current: Option<String>| match value {
+        Some("") => None,
+        Some(value) => Some(value.to_string()),
+        None => current,
+    };
+    db.set_device_info(
+        uuid,
+        merge(display_name, device.display_name).as_deref(),
+        merge(owner, device.owner).as_deref(),
+        merge(notes, device.notes).as_deref(),
+    )
+}
+
 pub fn delete_device(db: &Db, uuid_as_str: &str) -> Result<(), ICTError> {
     let uuid = Uuid::parse_str(uuid_as_str)?;
 
@@ -70,23 +236,54 @@ pub fn delete_device(db: &Db, uuid_as_str: &str) -> Result<(), ICTError> {
     Ok(())
 }
 
-pub fn operate(db: &Db, uuid_as_str: &str, message: &str, signature: &str, sha_algo: String, close_duration: &u64) -> Result<bool, ICTError> {
+pub fn operate(db: &Db, uuid_as_str: &str, message: &str, signature: &str, sha_algo: TotpAlgorithm, close_duration: &u64, remote_addr: Option<&str>) -> Result<OperateStatus, ICTError> {
+    operate_with(ict_relays::controller(), db, uuid_as_str, message, signature, sha_algo, close_duration, remote_addr)
+}
+
+// Like operate, with the relays driven by the given controller instead of the process wide one
+#[allow(clippy::too_many_arguments)]
+pub fn operate_with(relays: &RelayController, db: &Db, uuid_as_str: &str, message: &str, signature: &str, sha_algo: TotpAlgorithm, close_duration: &u64, remote_addr: Option<&str>) -> Result<OperateStatus, ICTError> {
+    run_operate(relays, db, uuid_as_str, message, signature, sha_algo, close_duration, remote_addr, false)
+}
+
+// Like operate, refusing a signed message the device already used, for the commands
+// a broker delivers to anyone subscribed and may deliver again
+pub fn operate_once(db: &Db, uuid_as_str: &str, message: &str, signature: &str, sha_algo: TotpAlgorithm, close_duration: &u64) -> Result<OperateStatus, ICTError> {
+    run_operate(ict_relays::controller(), db, uuid_as_str, message, signature, sha_algo, close_duration, None, true)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn run_operate(relays: &RelayController, db: &Db, uuid_as_str: &str, message: &str, signature: &str, sha_algo: TotpAlgorithm, close_duration: &u64, remote_addr: Option<&str>, single_use: bool) -> Result<OperateStatus, ICTError> {
+    let result = operate_device(relays, db, uuid_as_str, message, signature, sha_algo, close_duration, remote_addr, single_use);
+    if let Err(e) = &result {
+        ict_events::notifier().operate_failed(db, uuid_as_str, e, remote_addr);
+    }
+    result
+}
+
+#[allow(clippy::too_many_arguments)]
+fn operate_device(controller: &RelayController, db: &Db, uuid_as_str: &str, message: &str, signature: &str, sha_algo: TotpAlgorithm, close_duration: &u64, remote_addr: Option<&str>, single_use: bool) -> Result<OperateStatus, ICTError> {
     let uuid = Uuid::parse_str(uuid_as_str)?;
-    let device = db.get_device(uuid)?.ok_or(ICTError::Custom(
-        "No device with that uuid found".to_string(),
-    ))?;
-    if device.authorized != 1 {
-        return Err(ICTError::Custom(
-            "Will not operate a device/client that is not authorized".to_string(),
-        ));
+    let mut device = db.get_device(uuid)?.ok_or(ICTError::NotFound(format!("device {}", uuid)))?;
+    let now = Utc::now();
+    refresh_status(db, &mut device, now)?;
+    if device.status != DeviceStatus::Authorized {
+        return Err(ICTError::Custom(format!(
+            "Will not operate a device/client that is {}", device.status
+        )));
+    }
+    if let Some(from) = device.valid_from.filter(|from| now < *from) {
+        return Err(ICTError::Custom(format!(
+            "Will not operate a device/client whose authorization starts at {}", from
+        )));
     }
     
     // check signature
-    let verifying_key = VerifyingKey::<Sha256>::new(device.wrapped_pk);
+    let verifying_key = VerifyingKey::<Sha256>::new(device.wrapped_pk.clone());
     let signature_bytes = general_purpose::STANDARD.decode(signature)
         .map_err(|_| ICTError::Custom("Failed to decode base64 signature".into()))?;
 
-    match verifying_key.verify(&message.as_bytes(), &Signature::try_from(signature_bytes.as_slice())?) {
+    match verifying_key.verify(message.as_bytes(), &Signature::try_from(signature_bytes.as_slice())?) {
         Ok(()) => (),
         Err(_e) => return Result::Err(ICTError::Custom("Message signature verification failed".to_string())),
     }
@@ -96,14 +293,8 @@ pub fn operate(db: &Db, uuid_as_str: &str, message: &str, signature: &str, sha_a
         .map_err(|_| ICTError::Custom("Failed to parse JSON message".into()))?;
     let decrypted_token = parsed.token;
 
-    let algo = match sha_algo.as_str() {
-        "sha1" => totp_rs::Algorithm::SHA1,
-        "sha512" => totp_rs::Algorithm::SHA512,
-        _ => totp_rs::Algorithm::SHA256,
-    };
-
     let totp = TOTP::new(
-        algo,
+        sha_algo.into(),
         6,                        // number of digits
         1,                          // step (in 30-second blocks, 1 = 30s)
         30,                         // period (seconds)
@@ -111,87 +302,377 @@ pub fn operate(db: &Db, uuid_as_str: &str, message: &str, signature: &str, sha_a
     )?;
 
     if totp.check_current(&decrypted_token)? {
+        // a message is spent once accepted, even when the schedule, the quota or the relays refuse it
+        if single_use {
+            db.prune_used_commands(now - Duration::minutes(USED_COMMAND_RETENTION))?;
+            if !db.use_command(device.id, message, now)? {
+                return Err(ICTError::Custom("Command was already used".to_string()));
+            }
+        }
+        db.record_contact(device.id, now, remote_addr)?;
         // here perform the relay logic (close the circuit for limit time)
+        let relays = scheduled_relays(db, device.id, db.get_effective_relays(device.id)?, now)?;
+        // checked and recorded under the write lock, concurrent operates cannot all pass the quota
+        let operation = db.immediate_transaction(|db| {
+            check_quota(db, device.id, now)?;
+            db.prune_operations(now - Duration::days(1))?;
+            Ok(db.record_operation(device.id, now)?)
+        })?;
+        let status = match actuate(controller, db, device.id, &relays, *close_duration, now) {
+            Ok(status) if status.operated.is_empty() && !status.pending.is_empty() => {
+                // an approval that only waits for the quorum does not use the quota
+                db.cancel_operation(device.id, operation)?;
+                status
+            }
+            Ok(status) => status,
+            Err(e) => {
+                db.cancel_operation(device.id, operation)?;
+                return Err(e);
+            }
+        };
+        db.add_audit(now, Some(device.id), "operate", &operate_detail(&status))?;
+        ict_events::notifier().operated(db, &device, &status, remote_addr);
+        Ok(status)
+    } else {
+        Err(ICTError::Custom("TOTP token is not valid".to_string()))
+    }
+}
 
-        //dumb way to silence warning
-        #[cfg(not(feature = "gpio"))]
-        let _ = close_duration;
-        #[cfg(feature = "gpio")]
-        let mut output_pins: Vec<rppal::gpio::OutputPin> = Vec::new();
-
-        let relays = db.get_relays(device.id)?;
-        relays.iter().for_each(|relay| {
-            info!("closing relays {} for uuid {}", relay, uuid_as_str);
-            #[cfg(feature = "gpio")]
-            {
-                if let Ok(gpio) = Gpio::new() {
-                    if let Ok(pin) = gpio.get(*relay) {
-                        let mut pin = pin.into_output();
-                        pin.set_high();
-                        output_pins.push(pin); // <-- Hold pin
-                    } else {
-                        info!("Failed to get GPIO pin {}", relay);
-                    }
-                } else {
-                    info!("Failed to initialize GPIO");
-                }
+fn actuate(controller: &RelayController, db: &Db, device_id: Uuid, relays: &[u8], close_duration: u64, now: DateTime<Utc>) -> Result<OperateStatus, ICTError> {
+    let (mut status, approvals) = apply_quorum(controller, db, device_id, relays, now)?;
+    if !status.operated.is_empty() {
+        let pulsed_at = Utc::now();
+        if let Err(e) = controller.pulse(&status.operated, close_duration, &device_id.to_string()) {
+            // the approvals that reached the quorum count again for the next attempt
+            for (relay, approvals) in approvals {
+                db.restore_approvals(relay, &approvals)?;
             }
-        });
+            return Err(e);
+        }
+        status.inputs = ict_inputs::monitor().report(&status.operated, pulsed_at);
+    }
+    Ok(status)
+}
 
-        thread::sleep(Duration::from_millis(close_duration.clone()));
+// ie "relays 16 20, pending 21, input front door active (activated)"
+fn operate_detail(status: &OperateStatus) -> String {
+    let relays = |relays: Vec<u8>| relays.iter().map(|relay| relay.to_string()).collect::<Vec<String>>().join(" ");
+    let mut parts = Vec::new();
+    if !status.operated.is_empty() {
+        parts.push(format!("relays {}", relays(status.operated.clone())));
+    }
+    if !status.pending.is_empty() {
+        parts.push(format!("pending {}", relays(status.pending.iter().map(|p| p.relay).collect())));
+    }
+    for input in &status.inputs {
+        let state = if input.active { "active" } else { "inactive" };
+        let activated = if input.activated { " (activated)" } else { "" };
+        parts.push(format!("input {} {}{}", input.name, state, activated));
+    }
+    parts.join(", ")
+}
 
-        info!("re-opening relays for uuid {}",uuid_as_str);
-        #[cfg(feature = "gpio")]
-        {
-            output_pins.iter_mut().for_each(|pin| {
-                info!("re-opening relays {:?} for uuid {}", pin, uuid_as_str);
-                pin.set_low(); // <-- Now safely turn off
-            });
-         }
-        // relays.iter().for_each(|relay| {
-        //     info!("re-opening relays {} for uuid {}", relay, uuid_as_str);
-        //     #[cfg(feature = "gpio")]
-        //     {
-        //         if let Ok(gpio) = Gpio::new() {
-        //             if let Ok(pin) = gpio.get(*relay) {
-        //                 let mut pin = pin.into_output();
-        //                 pin.set_low();
-        //             } else {
-        //                 info!("Failed to get GPIO pin {}", relay);
-        //             }
-        //         } else {
-        //             info!("Failed to initialize GPIO");
-        //         }
-        //     }
-        // });
-        Ok(true)
-    } else {
-        Err(ICTError::Custom("TOTP token is not valid".to_string()))
+// Drops the relays that are outside of their schedule, fails if nothing is left to operate
+fn scheduled_relays(db: &Db, device_id: Uuid, relays: Vec<u8>, at: DateTime<Utc>) -> Result<Vec<u8>, ICTError> {
+    let schedules = db.get_schedules(Some(device_id))?;
+    let device_schedules: Vec<&Schedule> = schedules.iter().filter(|s| s.relay_id.is_none()).collect();
+    if !device_schedules.is_empty() && !device_schedules.iter().any(|s| s.allows(at)) {
+        return Err(ICTError::OutsideSchedule(format!(
+            "device {} is outside of its access schedule",
+            device_id
+        )));
     }
+
+    let allowed: Vec<u8> = relays
+        .iter()
+        .copied()
+        .filter(|relay| {
+            let mut relay_schedules = schedules.iter().filter(|s| s.relay_id == Some(*relay)).peekable();
+            relay_schedules.peek().is_none() || relay_schedules.any(|s| s.allows(at))
+        })
+        .collect();
+    if allowed.is_empty() && !relays.is_empty() {
+        return Err(ICTError::OutsideSchedule(format!(
+            "relays {:?} of device {} are outside of their access schedule",
+            relays, device_id
+        )));
+    }
+    Ok(allowed)
 }
 
-pub fn list_clients(db: &Db) -> Result<(),ICTError> {
-    info!("Listing registered clients:");
-    let devices = db.get_devices()?;
-    for d in devices {
-        info!("Client {:?}",d);
-        let relays = db.get_relays(d.id)?;
-        for r in relays {
-            info!("   has relay {}",r);
+fn check_quota(db: &Db, device_id: Uuid, now: DateTime<Utc>) -> Result<(), ICTError> {
+    let Some(quota) = db.get_quota(device_id)? else {
+        return Ok(());
+    };
+    if let (Some(min_interval), Some(last)) = (quota.min_interval, db.last_operation(device_id)?) {
+        let wait = last + Duration::seconds(min_interval as i64) - now;
+        if wait > Duration::zero() {
+            return Err(ICTError::QuotaExceeded(format!(
+                "device {} must wait {}s before operating again",
+                device_id,
+                wait.num_seconds().max(1)
+            )));
+        }
+    }
+    let limits = [
+        (quota.max_per_hour, now - Duration::hours(1), "per hour"),
+        (quota.max_per_day, now - Duration::days(1), "per day"),
+        (quota.max_total, quota.since, "in total"),
+    ];
+    for (max, since, period) in limits {
+        if let Some(max) = max {
+            if db.count_operations_since(device_id, since)? >= max {
+                return Err(ICTError::QuotaExceeded(format!(
+                    "device {} reached its limit of {} operations {}",
+                    device_id, max, period
+                )));
+            }
         }
     }
     Ok(())
 }
 
-pub fn describe_client(db: &Db, uuid_as_str: &str) -> Result<(),ICTError>{
-    info!("Describing registered client {}:",uuid_as_str);
+// (device, approved at) of the approvals of a relay
+type Approvals = Vec<(Uuid, DateTime<Utc>)>;
+
+// Relays with a quorum only actuate once enough distinct devices operated them within their window,
+// returns the approvals consumed by the relays that reached their quorum
+fn apply_quorum(controller: &RelayController, db: &Db, device_id: Uuid, relays: &[u8], now: DateTime<Utc>) -> Result<(OperateStatus, Vec<(u8, Approvals)>), ICTError> {
+    let mut status = OperateStatus { operated: Vec::new(), pending: Vec::new(), inputs: Vec::new() };
+    let mut consumed = Vec::new();
+    for relay in relays {
+        let config = controller.relay(*relay);
+        if config.quorum <= 1 {
+            status.operated.push(*relay);
+            continue;
+        }
+        let window = Duration::seconds(config.quorum_window as i64);
+        // only one of concurrent approvals reaching the quorum takes the approvals
+        let (approvals, taken, first) = db.immediate_transaction(|db| {
+            let approvals = db.approve_relay(*relay, device_id, now, now - window)?;
+            if approvals >= config.quorum {
+                Ok((approvals, Some(db.take_approvals(*relay)?), None))
+            } else {
+                Ok((approvals, None, db.first_approval(*relay)?))
+            }
+        })?;
+        if let Some(taken) = taken {
+            info!("quorum of {} reached for relay {}", config.quorum, relay);
+            consumed.push((*relay, taken));
+            status.operated.push(*relay);
+        } else {
+            let first = first.unwrap_or(now);
+            info!("relay {} pending with {} of {} approvals", relay, approvals, config.quorum);
+            status.pending.push(PendingRelay {
+                relay: *relay,
+                approvals,
+                quorum: config.quorum,
+                expires_in: (first + window - now).num_seconds(),
+            });
+        }
+    }
+    Ok((status, consumed))
+}
+
+// Restricts list_clients, unset fields match every client
+#[derive(Debug, Default)]
+pub struct ClientFilter {
+    pub status: Option<DeviceStatus>,
+    pub relay: Option<u8>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ClientSummary {
+    pub id: Uuid,
+    pub display_name: Option<String>,
+    pub owner: Option<String>,
+    pub status: DeviceStatus,
+    // granted directly and through groups
+    pub relays: Vec<u8>,
+    pub created_at: Option<DateTime<Utc>>,
+    pub last_seen_at: Option<DateTime<Utc>>,
+    pub last_remote_addr: Option<String>,
+    pub operation_count: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ClientDetails {
+    #[serde(flatten)]
+    pub summary: ClientSummary,
+    pub fingerprint: String,
+    pub notes: Option<String>,
+    pub status_reason: Option<String>,
+    pub status_changed_at: Option<DateTime<Utc>>,
+    pub valid_from: Option<DateTime<Utc>>,
+    pub valid_until: Option<DateTime<Utc>>,
+    pub grants: Vec<GrantInfo>,
+    pub quota: Option<QuotaUsage>,
+    pub schedules: Vec<ScheduleInfo>,
+    pub history: Vec<HistoryEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GrantInfo {
+    pub relay: u8,
+    pub source: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct QuotaUsage {
+    pub max_per_hour: Option<u32>,
+    pub used_last_hour: u32,
+    pub max_per_day: Option<u32>,
+    pub used_last_day: u32,
+    pub max_total: Option<u32>,
+    pub used_total: u32,
+    pub min_interval: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ScheduleInfo {
+    pub id: i64,
+    pub client: Uuid,
+    // None applies to all the relays of the client
+    pub relay: Option<u8>,
+    pub days: String,
+    pub from: String,
+    pub until: String,
+    pub timezone: String,
+}
+
+impl From<&Schedule> for ScheduleInfo {
+    fn from(s: &Schedule) -> Self {
+        ScheduleInfo {
+            id: s.id,
+            client: s.device_id,
+            relay: s.relay_id,
+            days: s.weekdays.to_string(),
+            from: s.start_as_string(),
+            until: s.end_as_string(),
+            timezone: s.timezone.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub at: DateTime<Utc>,
+    pub event: String,
+    pub detail: String,
+}
+
+pub fn list_clients(db: &Db, filter: &ClientFilter) -> Result<Vec<ClientSummary>, ICTError> {
+    let now = Utc::now();
+    let mut clients = Vec::new();
+    for mut d in db.get_devices()? {
+        refresh_status(db, &mut d, now)?;
+        let summary = summarize(db, d)?;
+        if filter.status.is_some_and(|status| status != summary.status)
+            || filter.relay.is_some_and(|relay| !summary.relays.contains(&relay))
+        {
+            continue;
+        }
+        clients.push(summary);
+    }
+    Ok(clients)
+}
+
+fn summarize(db: &Db, d: Device) -> Result<ClientSummary, ICTError> {
+    Ok(ClientSummary {
+        id: d.id,
+        relays: db.get_effective_relays(d.id)?,
+        display_name: d.display_name,
+        owner: d.owner,
+        status: d.status,
+        created_at: d.created_at,
+        last_seen_at: d.last_seen_at,
+        last_remote_addr: d.last_remote_addr,
+        operation_count: d.operation_count,
+    })
+}
+
+pub fn describe_client(db: &Db, uuid_as_str: &str) -> Result<ClientDetails, ICTError> {
+    let uuid = Uuid::parse_str(uuid_as_str)?;
+    let now = Utc::now();
+    let mut device = db.get_device(uuid)?.ok_or(ICTError::NotFound(format!("device {}", uuid)))?;
+    refresh_status(db, &mut device, now)?;
+
+    let quota = match db.get_quota(uuid)? {
+        Some(q) => Some(QuotaUsage {
+            max_per_hour: q.max_per_hour,
+            used_last_hour: db.count_operations_since(uuid, now - Duration::hours(1))?,
+            max_per_day: q.max_per_day,
+            used_last_day: db.count_operations_since(uuid, now - Duration::days(1))?,
+            max_total: q.max_total,
+            used_total: db.count_operations_since(uuid, q.since)?,
+            min_interval: q.min_interval,
+        }),
+        None => None,
+    };
+    let grants = db
+        .get_relay_grants(uuid)?
+        .into_iter()
+        .map(|grant| GrantInfo { relay: grant.relay_id, source: grant.source.to_string() })
+        .collect();
+    let schedules = db
+        .get_schedules(Some(uuid))?
+        .into_iter()
+        .map(|s| ScheduleInfo::from(&s))
+        .collect();
+    let history = db
+        .get_audit(Some(uuid))?
+        .into_iter()
+        .map(|entry| HistoryEntry { at: entry.at, event: entry.event, detail: entry.detail })
+        .collect();
+
+    Ok(ClientDetails {
+        fingerprint: device.fingerprint()?,
+        notes: device.notes.clone(),
+        status_reason: device.status_reason.clone(),
+        status_changed_at: device.status_changed_at,
+        valid_from: device.valid_from,
+        valid_until: device.valid_until,
+        summary: summarize(db, device)?,
+        grants,
+        quota,
+        schedules,
+        history,
+    })
+}
+
+// Outcome of reviewing a pending registration
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReviewDecision {
+    // authorizes the client with these relays
+    Approve(Vec<u8>),
+    // deletes the client
+    Reject,
+    Skip,
+}
+
+// Applies a review decision to a pending client and records it in the audit trail
+pub fn review_client(db: &Db, uuid_as_str: &str, decision: &ReviewDecision) -> Result<(), ICTError> {
     let uuid = Uuid::parse_str(uuid_as_str)?;
-    let device = db.get_device(uuid)?;
-    info!("Client {:?}",device);
-    for relay in db.get_relays(uuid)? {
-        info!("    has relay {}",relay);
+    let device = db.get_device(uuid)?.ok_or(ICTError::NotFound(format!("device {}", uuid)))?;
+    if device.status != DeviceStatus::Pending {
+        return Err(ICTError::StatusChange(format!("client {} is {} and not pending review", uuid, device.status)));
     }
-    Ok(())
+    let detail = match decision {
+        ReviewDecision::Approve(relays) => {
+            authorize_between(db, uuid_as_str, None, None, Some("approved in review"))?;
+            for relay in relays {
+                db.add_relay(uuid, *relay)?;
+            }
+            format!("approved with relays {:?}", relays)
+        }
+        ReviewDecision::Reject => {
+            db.delete_device(uuid)?;
+            "rejected and deleted".to_string()
+        }
+        ReviewDecision::Skip => "skipped".to_string(),
+    };
+    db.add_audit(Utc::now(), Some(uuid), "review", &detail)
 }
 
 pub fn associate_relay(db: &Db, uuid_as_str: &str, relay: &u8) -> Result<(),ICTError>{
@@ -205,3 +686,114 @@ pub fn clear_relays(db: &Db, uuid_as_str: &str) -> Result<(),ICTError> {
     db.remove_relays(uuid)?;
     Ok(())
 }
+
+pub fn add_schedule(db: &Db, uuid_as_str: &str, relay: Option<u8>, days: &str, from: &str, until: &str, timezone: &str) -> Result<i64, ICTError> {
+    let uuid = Uuid::parse_str(uuid_as_str)?;
+    db.get_device(uuid)?.ok_or(ICTError::NotFound(format!("device {}", uuid)))?;
+    let schedule = Schedule::new(uuid, relay, Weekdays::parse(days)?, from, until, timezone)?;
+    db.add_schedule(&schedule)
+}
+
+pub fn list_schedules(db: &Db, uuid_as_str: Option<&str>) -> Result<Vec<ScheduleInfo>, ICTError> {
+    let uuid = uuid_as_str.map(Uuid::parse_str).transpose()?;
+    Ok(db.get_schedules(uuid)?.iter().map(ScheduleInfo::from).collect())
+}
+
+pub fn remove_schedule(db: &Db, id: i64) -> Result<(), ICTError> {
+    if db.remove_schedule(id)? {
+        Ok(())
+    } else {
+        Err(ICTError::NotFound(format!("schedule {}", id)))
+    }
+}
+
+pub fn set_quota(db: &Db, uuid_as_str: &str, per_hour: Option<u32>, per_day: Option<u32>, total: Option<u32>, min_interval: Option<u64>) -> Result<(), ICTError> {
+    let uuid = Uuid::parse_str(uuid_as_str)?;
+    db.get_device(uuid)?.ok_or(ICTError::NotFound(format!("device {}", uuid)))?;
+    if per_hour.is_none() && per_day.is_none() && total.is_none() && min_interval.is_none() {
+        return Err(ICTError::Custom("A quota needs at least one limit".to_string()));
+    }
+    db.set_quota(&Quota {
+        device_id: uuid,
+        max_per_hour: per_hour,
+        max_per_day: per_day,
+        max_total: total,
+        min_interval,
+        since: Utc::now(),
+    })
+}
+
+pub fn clear_quota(db: &Db, uuid_as_str: &str) -> Result<(), ICTError> {
+    let uuid = Uuid::parse_str(uuid_as_str)?;
+    db.remove_quota(uuid)?;
+    Ok(())
+}
+
+fn find_group(db: &Db, name: &str) -> Result<Group, ICTError> {
+    db.get_group(name)?
+        .ok_or(ICTError::NotFound(format!("group {}", name)))
+}
+
+pub fn add_group(db: &Db, name: &str) -> Result<(), ICTError> {
+    if db.get_group(name)?.is_some() {
+        return Err(ICTError::Custom(format!("Group {} already exists", name)));
+    }
+    db.add_group(name)?;
+    Ok(())
+}
+
+pub fn delete_group(db: &Db, name: &str) -> Result<(), ICTError> {
+    let group = find_group(db, name)?;
+    db.delete_group(group.id)?;
+    Ok(())
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GroupInfo {
+    pub name: String,
+    pub relays: Vec<u8>,
+    pub members: Vec<Uuid>,
+}
+
+pub fn list_groups(db: &Db) -> Result<Vec<GroupInfo>, ICTError> {
+    let mut groups = Vec::new();
+    for group in db.get_groups()? {
+        groups.push(GroupInfo {
+            relays: db.get_group_relays(group.id)?,
+            members: db.get_group_members(group.id)?,
+            name: group.name,
+        });
+    }
+    Ok(groups)
+}
+
+pub fn add_group_member(db: &Db, name: &str, uuid_as_str: &str) -> Result<(), ICTError> {
+    let uuid = Uuid::parse_str(uuid_as_str)?;
+    let group = find_group(db, name)?;
+    db.get_device(uuid)?.ok_or(ICTError::NotFound(format!("device {}", uuid)))?;
+    db.add_group_member(group.id, uuid)?;
+    Ok(())
+}
+
+pub fn remove_group_member(db: &Db, name: &str, uuid_as_str: &str) -> Result<(), ICTError> {
+    let uuid = Uuid::parse_str(uuid_as_str)?;
+    let group = find_group(db, name)?;
+    if !db.remove_group_member(group.id, uuid)? {
+        return Err(ICTError::Custom(format!("Client {} is not in group {}", uuid, name)));
+    }
+    Ok(())
+}
+
+pub fn grant_group_relay(db: &Db, name: &str, relay: &u8) -> Result<(), ICTError> {
+    let group = find_group(db, name)?;
+    db.add_group_relay(group.id, *relay)?;
+    Ok(())
+}
+
+pub fn revoke_group_relay(db: &Db, name: &str, relay: &u8) -> Result<(), ICTError> {
+    let group = find_group(db, name)?;
+    if !db.remove_group_relay(group.id, *relay)? {
+        return Err(ICTError::Custom(format!("Relay {} is not granted to group {}", relay, name)));
+    }
+    Ok(())
+}
@@ -0,0 +1,214 @@
+use chrono::{DateTime, SecondsFormat, Utc};
+use ict_server::ict_config::PathCheck;
+use ict_server::ict_operations::{ClientDetails, ClientSummary, GroupInfo, ScheduleInfo};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+use crate::ict_args::OutputFormat;
+
+// Rows of text printed as an aligned table or as CSV, JSON is serialized from the data itself
+struct Table {
+    headers: Vec<&'static str>,
+    rows: Vec<Vec<String>>,
+}
+
+impl Table {
+    fn print(&self) {
+        let widths: Vec<usize> = self
+            .headers
+            .iter()
+            .enumerate()
+            .map(|(i, header)| self.rows.iter().map(|row| row[i].len()).fold(header.len(), usize::max))
+            .collect();
+        let line = |cells: Vec<String>| {
+            let padded: Vec<String> = cells
+                .iter()
+                .zip(&widths)
+                .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+                .collect();
+            println!("{}", padded.join("  ").trim_end());
+        };
+        line(self.headers.iter().map(|header| header.to_uppercase()).collect());
+        for row in &self.rows {
+            line(row.clone());
+        }
+    }
+
+    fn print_csv(&self) {
+        println!("{}", self.headers.join(","));
+        for row in &self.rows {
+            let cells: Vec<String> = row.iter().map(|cell| csv_escape(cell)).collect();
+            println!("{}", cells.join(","));
+        }
+    }
+
+    fn print_as(&self, format: OutputFormat, data: &impl Serialize) {
+        match format {
+            OutputFormat::Table => self.print(),
+            OutputFormat::Csv => self.print_csv(),
+            OutputFormat::Json => print_json(data),
+        }
+    }
+}
+
+fn csv_escape(cell: &str) -> String {
+    if cell.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", cell.replace('"', "\"\""))
+    } else {
+        cell.to_string()
+    }
+}
+
+pub fn print_json(data: &impl Serialize) {
+    match serde_json::to_string_pretty(data) {
+        Ok(json) => println!("{}", json),
+        Err(e) => eprintln!("Failed to serialize output with {}", e),
+    }
+}
+
+fn time(at: Option<DateTime<Utc>>) -> String {
+    at.map_or(String::new(), |at| at.to_rfc3339_opts(SecondsFormat::Secs, true))
+}
+
+fn text(value: &Option<String>) -> String {
+    value.clone().unwrap_or_default()
+}
+
+fn relays(relays: &[u8]) -> String {
+    relays.iter().map(|relay| relay.to_string()).collect::<Vec<String>>().join(" ")
+}
+
+pub fn print_clients(clients: &[ClientSummary], format: OutputFormat) {
+    let table = Table {
+        headers: vec!["id", "name", "owner", "status", "relays", "last_seen", "last_remote_addr", "operations"],
+        rows: clients
+            .iter()
+            .map(|c| {
+                vec![
+                    c.id.to_string(),
+                    text(&c.display_name),
+                    text(&c.owner),
+                    c.status.to_string(),
+                    relays(&c.relays),
+                    time(c.last_seen_at),
+                    text(&c.last_remote_addr),
+                    c.operation_count.to_string(),
+                ]
+            })
+            .collect(),
+    };
+    table.print_as(format, &clients);
+}
+
+// One field per row, lists get a row per entry
+pub fn print_client(client: &ClientDetails, format: OutputFormat) {
+    let c = &client.summary;
+    let mut rows = vec![
+        ("id", c.id.to_string()),
+        ("name", text(&c.display_name)),
+        ("owner", text(&c.owner)),
+        ("fingerprint", client.fingerprint.clone()),
+        ("notes", text(&client.notes)),
+        ("status", c.status.to_string()),
+        ("status_reason", text(&client.status_reason)),
+        ("status_changed_at", time(client.status_changed_at)),
+        ("valid_from", time(client.valid_from)),
+        ("valid_until", time(client.valid_until)),
+        ("created_at", time(c.created_at)),
+        ("last_seen", time(c.last_seen_at)),
+        ("last_remote_addr", text(&c.last_remote_addr)),
+        ("operations", c.operation_count.to_string()),
+    ];
+    for grant in &client.grants {
+        rows.push(("relay", format!("{} ({})", grant.relay, grant.source)));
+    }
+    if let Some(q) = &client.quota {
+        let show = |max: Option<u32>, used: u32| format!("{}/{}", used, max.map_or("unlimited".to_string(), |m| m.to_string()));
+        rows.push(("quota_hour", show(q.max_per_hour, q.used_last_hour)));
+        rows.push(("quota_day", show(q.max_per_day, q.used_last_day)));
+        rows.push(("quota_total", show(q.max_total, q.used_total)));
+        rows.push(("quota_min_interval", q.min_interval.map_or(String::new(), |s| format!("{}s", s))));
+    }
+    for s in &client.schedules {
+        rows.push((
+            "schedule",
+            format!(
+                "{}: {} on {} from {} until {} ({})",
+                s.id,
+                s.relay.map_or("all relays".to_string(), |r| format!("relay {}", r)),
+                s.days,
+                s.from,
+                s.until,
+                s.timezone
+            ),
+        ));
+    }
+    for entry in &client.history {
+        rows.push(("history", format!("{} {} {}", time(Some(entry.at)), entry.event, entry.detail)));
+    }
+    let table = Table {
+        headers: vec!["field", "value"],
+        rows: rows.into_iter().map(|(field, value)| vec![field.to_string(), value]).collect(),
+    };
+    table.print_as(format, client);
+}
+
+pub fn print_groups(groups: &[GroupInfo], format: OutputFormat) {
+    let table = Table {
+        headers: vec!["name", "relays", "members"],
+        rows: groups
+            .iter()
+            .map(|g| {
+                let members: Vec<String> = g.members.iter().map(|member| member.to_string()).collect();
+                vec![g.name.clone(), relays(&g.relays), members.join(" ")]
+            })
+            .collect(),
+    };
+    table.print_as(format, &groups);
+}
+
+pub fn print_schedules(schedules: &[ScheduleInfo], format: OutputFormat) {
+    let table = Table {
+        headers: vec!["id", "client", "relay", "days", "from", "until", "timezone"],
+        rows: schedules
+            .iter()
+            .map(|s| {
+                vec![
+                    s.id.to_string(),
+                    s.client.to_string(),
+                    s.relay.map_or("all".to_string(), |r| r.to_string()),
+                    s.days.clone(),
+                    s.from.clone(),
+                    s.until.clone(),
+                    s.timezone.clone(),
+                ]
+            })
+            .collect(),
+    };
+    table.print_as(format, &schedules);
+}
+
+pub fn print_path_checks(checks: &[PathCheck]) {
+    let table = Table {
+        headers: vec!["check", "path", "result"],
+        rows: checks
+            .iter()
+            .map(|c| {
+                vec![
+                    c.name.to_string(),
+                    c.path.display().to_string(),
+                    c.problem.as_ref().map_or("ok".to_string(), |problem| format!("FAILED: {}", problem)),
+                ]
+            })
+            .collect(),
+    };
+    table.print();
+}
+
+pub fn print_config_sources(sources: &BTreeMap<String, String>) {
+    let table = Table {
+        headers: vec!["key", "source"],
+        rows: sources.iter().map(|(key, source)| vec![key.clone(), source.clone()]).collect(),
+    };
+    table.print();
+}
@@ -0,0 +1,633 @@
+use log::{error, info, warn};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, OnceLock, RwLock, TryLockError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::ict_config::{InputConfig, Interlock, InterlockPolicy, Pull, RelayConfig};
+use crate::ict_metrics::metrics;
+use crate::ict_errors::ICTError;
+use crate::ict_mqtt;
+
+#[cfg(feature = "gpio")]
+use rppal::gpio::Gpio;
+
+static CONTROLLER: OnceLock<RelayController> = OnceLock::new();
+static HARDWARE_WATCHDOG: Mutex<Option<File>> = Mutex::new(None);
+
+const WATCH_INTERVAL: Duration = Duration::from_millis(10);
+const PET_INTERVAL: Duration = Duration::from_secs(1);
+// How long the panic hook waits for a lock before driving the pins without it
+const PANIC_LOCK_WAIT: Duration = Duration::from_millis(50);
+
+// Configures the process wide controller
+pub fn init(relays: Vec<RelayConfig>, interlocks: Vec<Interlock>) -> &'static RelayController {
+    let controller = controller();
+    controller.configure(relays, interlocks);
+    controller
+}
+
+pub fn controller() -> &'static RelayController {
+    CONTROLLER.get_or_init(|| RelayController::new(Vec::new(), Vec::new()))
+}
+
+// Whether the relays can be driven, without the gpio feature they are only logged
+pub fn driver_status() -> Result<String, String> {
+    #[cfg(feature = "gpio")]
+    {
+        Gpio::new().map(|_| "GPIO initialized".to_string()).map_err(|e| format!("GPIO unavailable: {}", e))
+    }
+    #[cfg(not(feature = "gpio"))]
+    {
+        Ok("simulated, built without the gpio feature".to_string())
+    }
+}
+
+// Serializes access to the relays so that interlock groups are honored
+// across all the threads serving requests
+pub struct RelayController {
+    definitions: RwLock<Arc<Definitions>>,
+    state: Mutex<RelayState>,
+    changed: Condvar,
+    outputs: Mutex<Outputs>,
+    // the pins of the outputs, for the panic hook
+    #[cfg(feature = "gpio")]
+    lines: OutputLines,
+}
+
+struct Definitions {
+    relays: Vec<RelayConfig>,
+    interlocks: Vec<Interlock>,
+}
+
+impl Definitions {
+    fn relay(&self, id: u8) -> RelayConfig {
+        self.relays.iter().find(|relay| relay.id == id).cloned().unwrap_or_else(|| RelayConfig::new(id))
+    }
+}
+
+// Output pins of the relays, owned by the controller so that any thread can drive them back to idle
+#[derive(Default)]
+struct Outputs {
+    // relay -> since when it is energized
+    energized: HashMap<u8, Instant>,
+    // also reachable by the panic hook without this lock
+    #[cfg(feature = "gpio")]
+    pins: OutputLines,
+}
+
+#[cfg(feature = "gpio")]
+type OutputLines = Arc<RwLock<HashMap<u8, Arc<Mutex<OutputLine>>>>>;
+
+// An output pin and the level its relay idles at
+#[cfg(feature = "gpio")]
+struct OutputLine {
+    pin: rppal::gpio::OutputPin,
+    idle_high: bool,
+}
+
+#[cfg(feature = "gpio")]
+impl OutputLine {
+    fn write(&mut self, high: bool) {
+        if high {
+            self.pin.set_high()
+        } else {
+            self.pin.set_low()
+        }
+    }
+}
+
+impl Outputs {
+    fn set(&mut self, relay: &RelayConfig, energized: bool) {
+        #[cfg(feature = "gpio")]
+        {
+            let idle_high = relay.active != crate::ict_config::Level::High;
+            let high = energized != idle_high;
+            let line = self.pins.read().unwrap_or_else(|poisoned| poisoned.into_inner()).get(&relay.id).cloned();
+            match line {
+                Some(line) => {
+                    let mut line = line.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+                    line.idle_high = idle_high;
+                    line.write(high);
+                }
+                None => match Gpio::new().and_then(|gpio| gpio.get(relay.id)) {
+                    Ok(pin) => {
+                        let pin = if high { pin.into_output_high() } else { pin.into_output_low() };
+                        let line = Arc::new(Mutex::new(OutputLine { pin, idle_high }));
+                        self.pins.write().unwrap_or_else(|poisoned| poisoned.into_inner()).insert(relay.id, line);
+                    }
+                    Err(e) => error!("Failed to get GPIO pin {} with {}", relay.id, e),
+                },
+            }
+        }
+        if energized {
+            self.energized.entry(relay.id).or_insert_with(Instant::now);
+        } else {
+            self.energized.remove(&relay.id);
+        }
+    }
+
+    // Every relay driven so far
+    fn known(&self) -> BTreeSet<u8> {
+        #[allow(unused_mut)]
+        let mut known: BTreeSet<u8> = self.energized.keys().copied().collect();
+        #[cfg(feature = "gpio")]
+        known.extend(self.pins.read().unwrap_or_else(|poisoned| poisoned.into_inner()).keys());
+        known
+    }
+}
+
+#[derive(Default)]
+struct RelayState {
+    // relay -> number of pulses currently holding it
+    active: HashMap<u8, usize>,
+    // interlock group name -> last relay released in that group and when
+    released: HashMap<String, (u8, Instant)>,
+    // uuid -> number of pulses in progress for that client
+    pulsing: HashMap<String, usize>,
+    // clients whose pulses in progress must end now
+    cancelled: HashSet<String>,
+    // set by drain, new pulses are refused
+    draining: bool,
+}
+
+enum Blocker<'a> {
+    Conflict { group: &'a Interlock, relay: u8, active: u8 },
+    DeadTime(Duration),
+}
+
+impl RelayController {
+    pub fn new(relays: Vec<RelayConfig>, interlocks: Vec<Interlock>) -> Self {
+        let outputs = Outputs::default();
+        RelayController {
+            definitions: RwLock::new(Arc::new(Definitions { relays, interlocks })),
+            state: Mutex::new(RelayState::default()),
+            changed: Condvar::new(),
+            #[cfg(feature = "gpio")]
+            lines: outputs.pins.clone(),
+            outputs: Mutex::new(outputs),
+        }
+    }
+
+    // Swaps the relay and interlock definitions, pulses in progress are not affected
+    // and idle relays follow a change of their active level
+    pub fn configure(&self, relays: Vec<RelayConfig>, interlocks: Vec<Interlock>) {
+        let definitions = Arc::new(Definitions { relays, interlocks });
+        *self.definitions.write().unwrap_or_else(|poisoned| poisoned.into_inner()) = definitions.clone();
+        let mut outputs = self.outputs();
+        for relay in outputs.known() {
+            if !outputs.energized.contains_key(&relay) {
+                outputs.set(&definitions.relay(relay), false);
+            }
+        }
+        self.changed.notify_all();
+    }
+
+    // Definition of a relay, relays without one get the defaults
+    pub fn relay(&self, id: u8) -> RelayConfig {
+        self.definitions().relay(id)
+    }
+
+    fn definitions(&self) -> Arc<Definitions> {
+        self.definitions.read().unwrap_or_else(|poisoned| poisoned.into_inner()).clone()
+    }
+
+    // Closes the relays for close_duration ms then re-opens them
+    pub fn pulse(&self, relays: &[u8], close_duration: u64, uuid: &str) -> Result<(), ICTError> {
+        let definitions = self.definitions();
+        check_request(&definitions.interlocks, relays)?;
+        // relays already held by another pulse are already energized
+        let closed = self.acquire(&definitions.interlocks, relays)?;
+        for relay in &closed {
+            info!("closing relays {} for uuid {}", relay, uuid);
+        }
+        self.drive(&definitions, &closed, true);
+        for relay in &closed {
+            ict_mqtt::publish_relay(*relay, true);
+        }
+
+        let energized_at = Instant::now();
+        self.hold(uuid, Duration::from_millis(close_duration));
+        let opened = self.release(&definitions, relays, uuid);
+        // the pulses that found a relay already energized do not count it again
+        for relay in &closed {
+            metrics().record_actuation(*relay, energized_at.elapsed());
+        }
+
+        for relay in opened {
+            ict_mqtt::publish_relay(relay, false);
+        }
+        Ok(())
+    }
+
+    // Whether a pulse currently holds the relay
+    pub fn is_active(&self, relay: u8) -> bool {
+        self.lock().active.contains_key(&relay)
+    }
+
+    // Whether the relay's output is at its active level
+    pub fn is_energized(&self, relay: u8) -> bool {
+        self.outputs().energized.contains_key(&relay)
+    }
+
+    // Drives the defined relays, and any other relay driven so far, to their idle level whatever holds them,
+    // returns the relays that were energized
+    pub fn reset(&self, reason: &str) -> Vec<u8> {
+        self.reset_outputs(&self.definitions(), self.outputs(), reason)
+    }
+
+    // Like reset without blocking on a lock the panicking thread may hold: past a short wait
+    // the pins are driven to idle one by one, around the outputs
+    pub fn reset_on_panic(&self) {
+        let definitions = match try_lock_for(|| self.definitions.try_read(), PANIC_LOCK_WAIT) {
+            Some(definitions) => definitions.clone(),
+            None => return self.drive_idle_directly(),
+        };
+        match try_lock_for(|| self.outputs.try_lock(), PANIC_LOCK_WAIT) {
+            Some(outputs) => {
+                self.reset_outputs(&definitions, outputs, "panic");
+            }
+            None => self.drive_idle_directly(),
+        }
+    }
+
+    fn drive_idle_directly(&self) {
+        #[cfg(feature = "gpio")]
+        {
+            let Some(pins) = try_lock_for(|| self.lines.try_read(), PANIC_LOCK_WAIT) else {
+                error!("relay outputs are locked on panic, no pin could be driven to idle");
+                return;
+            };
+            for (relay, line) in pins.iter() {
+                match try_lock_for(|| line.try_lock(), PANIC_LOCK_WAIT) {
+                    Some(mut line) => {
+                        let idle_high = line.idle_high;
+                        line.write(idle_high);
+                    }
+                    None => error!("relay {} is locked on panic and could not be driven to idle", relay),
+                }
+            }
+            warn!("relay outputs were locked on panic, their pins were driven to idle one by one");
+        }
+        #[cfg(not(feature = "gpio"))]
+        warn!("relay outputs were locked on panic, the simulated relays keep their state");
+    }
+
+    fn reset_outputs(&self, definitions: &Definitions, mut outputs: MutexGuard<'_, Outputs>, reason: &str) -> Vec<u8> {
+        let mut relays = outputs.known();
+        relays.extend(definitions.relays.iter().map(|relay| relay.id));
+        let relays: Vec<u8> = relays.into_iter().collect();
+        let energized: Vec<u8> = relays.iter().copied().filter(|relay| outputs.energized.contains_key(relay)).collect();
+        for relay in &relays {
+            outputs.set(&definitions.relay(*relay), false);
+        }
+        drop(outputs);
+        if !energized.is_empty() {
+            warn!("relays {:?} were energized, driven to idle on {}", energized, reason);
+        } else if !relays.is_empty() {
+            info!("relays {:?} driven to idle on {}", relays, reason);
+        }
+        for relay in &energized {
+            ict_mqtt::publish_relay(*relay, false);
+        }
+        energized
+    }
+
+    // Re-opens the relays energized for longer than their max_on, returns them
+    pub fn watch(&self, now: Instant) -> Vec<u8> {
+        let definitions = self.definitions();
+        let mut outputs = self.outputs();
+        let overdue: Vec<RelayConfig> = outputs
+            .energized
+            .iter()
+            .map(|(relay, since)| (definitions.relay(*relay), *since))
+            .filter(|(relay, since)| now.saturating_duration_since(*since) > Duration::from_millis(relay.max_on))
+            .map(|(relay, _)| relay)
+            .collect();
+        for relay in &overdue {
+            error!("relay {} energized for more than {} ms, the watchdog re-opens it", relay.id, relay.max_on);
+            outputs.set(relay, false);
+        }
+        drop(outputs);
+        for relay in &overdue {
+            metrics().record_watchdog_trip(relay.id);
+            ict_mqtt::publish_relay(relay.id, false);
+        }
+        overdue.iter().map(|relay| relay.id).collect()
+    }
+
+    fn drive(&self, definitions: &Definitions, relays: &[u8], energized: bool) {
+        let mut outputs = self.outputs();
+        for relay in relays {
+            outputs.set(&definitions.relay(*relay), energized);
+        }
+    }
+
+    fn outputs(&self) -> MutexGuard<'_, Outputs> {
+        self.outputs.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    // Refuses new pulses and waits for the ones in progress until the deadline, the ones still going
+    // are then cancelled; returns false if some had to be
+    pub fn drain(&self, deadline: Instant) -> bool {
+        let mut state = self.lock();
+        state.draining = true;
+        // pulses queued behind an interlock give up
+        self.changed.notify_all();
+        loop {
+            if state.pulsing.is_empty() && state.active.is_empty() {
+                return true;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            state = self.wait(state, deadline - now);
+        }
+        let pulsing: Vec<String> = state.pulsing.keys().cloned().collect();
+        warn!("cancelling the pulses in progress for {:?}", pulsing);
+        state.cancelled.extend(pulsing);
+        self.changed.notify_all();
+        false
+    }
+
+    // Accepts pulses again after a drain
+    pub fn resume(&self) {
+        self.lock().draining = false;
+    }
+
+    // Re-opens the relays a client is currently holding, returns false if it held none
+    pub fn cancel(&self, uuid: &str) -> bool {
+        let mut state = self.lock();
+        if !state.pulsing.contains_key(uuid) {
+            return false;
+        }
+        info!("cancelling pulse in progress for uuid {}", uuid);
+        state.cancelled.insert(uuid.to_string());
+        self.changed.notify_all();
+        true
+    }
+
+    // Waits for the pulse duration, or less if the client's pulses get cancelled
+    fn hold(&self, uuid: &str, duration: Duration) {
+        let deadline = Instant::now() + duration;
+        let mut state = self.lock();
+        *state.pulsing.entry(uuid.to_string()).or_insert(0) += 1;
+        loop {
+            let now = Instant::now();
+            if now >= deadline || state.cancelled.contains(uuid) {
+                break;
+            }
+            state = self.wait(state, deadline - now);
+        }
+        if let Some(count) = state.pulsing.get_mut(uuid) {
+            *count -= 1;
+            if *count == 0 {
+                state.pulsing.remove(uuid);
+                state.cancelled.remove(uuid);
+            }
+        }
+    }
+
+    fn blocker<'a>(&self, interlocks: &'a [Interlock], state: &RelayState, relays: &[u8]) -> Option<Blocker<'a>> {
+        for relay in relays {
+            for group in groups_of(interlocks, *relay) {
+                if let Some(active) = group
+                    .relays
+                    .iter()
+                    .find(|r| *r != relay && state.active.contains_key(r))
+                {
+                    return Some(Blocker::Conflict { group, relay: *relay, active: *active });
+                }
+                if let Some((released, at)) = state.released.get(&group.name) {
+                    let dead_time = Duration::from_millis(group.dead_time);
+                    if released != relay {
+                        if let Some(remaining) = dead_time.checked_sub(at.elapsed()).filter(|r| !r.is_zero()) {
+                            return Some(Blocker::DeadTime(remaining));
+                        }
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    // Returns the relays that were not held by another pulse
+    fn acquire(&self, interlocks: &[Interlock], relays: &[u8]) -> Result<Vec<u8>, ICTError> {
+        let started = Instant::now();
+        let mut state = self.lock();
+        loop {
+            if state.draining {
+                return Err(ICTError::ShuttingDown);
+            }
+            match self.blocker(interlocks, &state, relays) {
+                None => break,
+                Some(Blocker::DeadTime(remaining)) => {
+                    state = self.wait(state, remaining);
+                }
+                Some(Blocker::Conflict { group, relay, active }) => {
+                    let remaining = Duration::from_millis(group.queue_timeout).saturating_sub(started.elapsed());
+                    if group.policy == InterlockPolicy::Reject || remaining.is_zero() {
+                        return Err(ICTError::Interlock(format!(
+                            "relay {} conflicts with active relay {} in interlock group {}",
+                            relay, active, group.name
+                        )));
+                    }
+                    info!("relay {} queued behind relay {} in interlock group {}", relay, active, group.name);
+                    state = self.wait(state, remaining);
+                }
+            }
+        }
+        let mut closed = Vec::new();
+        for relay in relays {
+            let count = state.active.entry(*relay).or_insert(0);
+            *count += 1;
+            if *count == 1 {
+                closed.push(*relay);
+            }
+        }
+        Ok(closed)
+    }
+
+    // Re-opens and returns the relays no other pulse holds any more,
+    // they are idle before another relay of their interlock groups can be energized
+    fn release(&self, definitions: &Definitions, relays: &[u8], uuid: &str) -> Vec<u8> {
+        let mut state = self.lock();
+        let mut opened = Vec::new();
+        for relay in relays {
+            if let Some(count) = state.active.get_mut(relay) {
+                *count -= 1;
+                if *count == 0 {
+                    state.active.remove(relay);
+                    opened.push(*relay);
+                }
+            }
+        }
+        info!("re-opening relays {:?} for uuid {}", opened, uuid);
+        self.drive(definitions, &opened, false);
+        for relay in relays {
+            for group in groups_of(&definitions.interlocks, *relay) {
+                state.released.insert(group.name.clone(), (*relay, Instant::now()));
+            }
+        }
+        self.changed.notify_all();
+        opened
+    }
+
+    fn lock(&self) -> MutexGuard<'_, RelayState> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn wait<'a>(&self, state: MutexGuard<'a, RelayState>, timeout: Duration) -> MutexGuard<'a, RelayState> {
+        match self.changed.wait_timeout(state, timeout) {
+            Ok((state, _)) => state,
+            Err(poisoned) => poisoned.into_inner().0,
+        }
+    }
+}
+
+fn groups_of(interlocks: &[Interlock], relay: u8) -> impl Iterator<Item = &Interlock> {
+    interlocks.iter().filter(move |group| group.relays.contains(&relay))
+}
+
+// a single request can never be satisfied if it holds two relays of the same group
+fn check_request(interlocks: &[Interlock], relays: &[u8]) -> Result<(), ICTError> {
+    for (i, relay) in relays.iter().enumerate() {
+        for other in &relays[i + 1..] {
+            if other == relay {
+                continue;
+            }
+            if let Some(group) = groups_of(interlocks, *relay).find(|g| g.relays.contains(other)) {
+                return Err(ICTError::Interlock(format!(
+                    "relays {} and {} are both in interlock group {}",
+                    relay, other, group.name
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+// Re-opens the relays held past their max_on in the background, and pets the hardware watchdog
+// device for as long as that keeps running, so the board reboots if the process wedges
+pub fn start_watchdog(device: Option<&str>) -> Result<(), ICTError> {
+    if let Some(device) = device {
+        let file = OpenOptions::new().write(true).open(device)?;
+        info!("Petting the hardware watchdog {}", device);
+        *hardware_watchdog() = Some(file);
+    }
+    thread::spawn(|| {
+        let mut petted: Option<Instant> = None;
+        loop {
+            controller().watch(Instant::now());
+            if petted.is_none_or(|at| at.elapsed() >= PET_INTERVAL) {
+                if let Some(file) = hardware_watchdog().as_mut() {
+                    if let Err(e) = file.write_all(b"\0") {
+                        error!("Failed petting the hardware watchdog with {}", e);
+                    }
+                }
+                petted = Some(Instant::now());
+            }
+            thread::sleep(WATCH_INTERVAL);
+        }
+    });
+    Ok(())
+}
+
+// Disarms the hardware watchdog before a deliberate stop, the magic close tells the driver not to reboot
+pub fn stop_watchdog() {
+    if let Some(mut file) = hardware_watchdog().take() {
+        if let Err(e) = file.write_all(b"V") {
+            error!("Failed disarming the hardware watchdog with {}", e);
+        }
+    }
+}
+
+fn hardware_watchdog() -> MutexGuard<'static, Option<File>> {
+    HARDWARE_WATCHDOG.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+// A panicking thread may leave relays energized, they are all re-opened before the panic is reported
+pub fn install_panic_hook() {
+    let report = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        controller().reset_on_panic();
+        report(info);
+    }));
+}
+
+// Takes a lock without waiting past the timeout, a poisoned lock is taken all the same
+fn try_lock_for<T>(try_lock: impl Fn() -> Result<T, TryLockError<T>>, timeout: Duration) -> Option<T> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        match try_lock() {
+            Ok(guard) => return Some(guard),
+            Err(TryLockError::Poisoned(poisoned)) => return Some(poisoned.into_inner()),
+            Err(TryLockError::WouldBlock) if Instant::now() < deadline => thread::sleep(Duration::from_millis(1)),
+            Err(TryLockError::WouldBlock) => return None,
+        }
+    }
+}
+
+// Levels set with simulate_input, read instead of the pins without the gpio feature
+static SIMULATED_INPUTS: OnceLock<Mutex<HashMap<u8, bool>>> = OnceLock::new();
+
+pub fn simulate_input(pin: u8, high: bool) {
+    let levels = SIMULATED_INPUTS.get_or_init(|| Mutex::new(HashMap::new()));
+    levels.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).insert(pin, high);
+}
+
+// Input pins configured with their pull resistor, held for as long as they are read
+pub struct InputPins {
+    #[cfg(feature = "gpio")]
+    pins: Vec<rppal::gpio::InputPin>,
+    // pin and the level it idles at when nothing simulates it
+    #[cfg(not(feature = "gpio"))]
+    pins: Vec<(u8, bool)>,
+}
+
+impl InputPins {
+    pub fn open(inputs: &[InputConfig]) -> Result<InputPins, ICTError> {
+        #[cfg(feature = "gpio")]
+        {
+            let gpio = Gpio::new().map_err(|e| ICTError::Custom(format!("GPIO unavailable: {}", e)))?;
+            let mut pins = Vec::new();
+            for input in inputs {
+                let pin = gpio
+                    .get(input.pin)
+                    .map_err(|e| ICTError::Custom(format!("Failed to get GPIO pin {} with {}", input.pin, e)))?;
+                pins.push(match input.pull {
+                    Pull::Up => pin.into_input_pullup(),
+                    Pull::Down => pin.into_input_pulldown(),
+                    Pull::None => pin.into_input(),
+                });
+            }
+            Ok(InputPins { pins })
+        }
+        #[cfg(not(feature = "gpio"))]
+        {
+            let idle = |input: &InputConfig| match input.pull {
+                Pull::Up => true,
+                Pull::Down => false,
+                Pull::None => input.active == crate::ict_config::Level::Low,
+            };
+            Ok(InputPins { pins: inputs.iter().map(|input| (input.pin, idle(input))).collect() })
+        }
+    }
+
+    // true for a high level, in the order of the inputs the pins were opened with
+    pub fn levels(&self) -> Vec<bool> {
+        #[cfg(feature = "gpio")]
+        {
+            self.pins.iter().map(|pin| pin.is_high()).collect()
+        }
+        #[cfg(not(feature = "gpio"))]
+        {
+            let levels = SIMULATED_INPUTS.get_or_init(|| Mutex::new(HashMap::new()));
+            let levels = levels.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            self.pins.iter().map(|(pin, idle)| levels.get(pin).copied().unwrap_or(*idle)).collect()
+        }
+    }
+}
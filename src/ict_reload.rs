@@ -0,0 +1,179 @@
+use log::{error, info, warn};
+use serde_json::Value;
+use signal_hook::consts::SIGHUP;
+use signal_hook::iterator::Signals;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use crate::ict_config::{conf_d_files, load_config, Logs, Settings};
+use crate::ict_errors::ICTError;
+use crate::ict_events;
+use crate::ict_relays;
+
+// Keys only read when the server starts, a new value is reported and ignored until a restart,
+// a section stands for all of its keys
+pub const RESTART_KEYS: [&str; 13] = [
+    "database.path",
+    "web.tls_path",
+    "admin",
+    "metrics.listen",
+    "pi.watchdog_device",
+    "logs.format",
+    "logs.output",
+    "logs.file",
+    "logs.max_size_mb",
+    "logs.max_age_hours",
+    "logs.keep",
+    "mqtt",
+    "inputs",
+];
+
+pub fn needs_restart(key: &str) -> bool {
+    RESTART_KEYS
+        .iter()
+        .any(|restart| key.strip_prefix(restart).is_some_and(|rest| rest.is_empty() || rest.starts_with('.')))
+}
+
+// The settings of a running server, replaced as a whole when the configuration is reloaded
+pub struct LiveSettings {
+    path: String,
+    current: RwLock<Arc<Settings>>,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct ReloadReport {
+    // keys whose new value is in use
+    pub changed: Vec<String>,
+    // keys whose new value waits for a restart
+    pub restart_required: Vec<String>,
+}
+
+impl LiveSettings {
+    pub fn new(path: &str, settings: Settings) -> Self {
+        LiveSettings { path: path.to_string(), current: RwLock::new(Arc::new(settings)) }
+    }
+
+    // A request keeps the settings it started with even if a reload happens meanwhile
+    pub fn current(&self) -> Arc<Settings> {
+        self.current.read().unwrap_or_else(|poisoned| poisoned.into_inner()).clone()
+    }
+
+    // Loads and validates the configuration again, the current settings stay when it is invalid
+    pub fn reload(&self) -> Result<ReloadReport, ICTError> {
+        let mut next = load_config(&self.path)?;
+        let mut current = self.current.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let before = flatten(&current)?;
+        let restart_required = changed_keys(&before, &flatten(&next)?)
+            .into_iter()
+            .filter(|key| needs_restart(key))
+            .collect();
+        next.database = current.database.clone();
+        next.web.tls_path = current.web.tls_path.clone();
+        next.admin = current.admin.clone();
+        next.metrics = current.metrics.clone();
+        next.mqtt = current.mqtt.clone();
+        next.inputs = current.inputs.clone();
+        next.pi.watchdog_device = current.pi.watchdog_device.clone();
+        next.logs = Logs { level: next.logs.level, ..current.logs.clone() };
+        next.sources.retain(|key, _| !needs_restart(key));
+        for (key, source) in current.sources.iter().filter(|(key, _)| needs_restart(key)) {
+            next.sources.insert(key.clone(), source.clone());
+        }
+        // the kept restart-only values have to fit with the reloaded ones
+        next.validate()?;
+        let changed = changed_keys(&before, &flatten(&next)?);
+        apply(&next);
+        *current = Arc::new(next);
+        Ok(ReloadReport { changed, restart_required })
+    }
+}
+
+// Runtime side of the settings, done at startup and on every reload
+pub fn apply(settings: &Settings) {
+    log::set_max_level(settings.logs.level.filter());
+    ict_relays::init(settings.relays.clone(), settings.interlocks.clone());
+    ict_events::configure(settings.webhooks.clone(), settings.events.clone());
+}
+
+// Reloads on SIGHUP and when the config file or one of the conf.d files changes
+pub fn watch(live: Arc<LiveSettings>, interval: Duration) -> Result<(), ICTError> {
+    let mut signals = Signals::new([SIGHUP])?;
+    let on_signal = live.clone();
+    thread::spawn(move || {
+        for _ in signals.forever() {
+            info!("SIGHUP received, reloading the configuration");
+            reload_and_log(&on_signal);
+        }
+    });
+    thread::spawn(move || {
+        let mut seen = file_times(&live.path);
+        loop {
+            thread::sleep(interval);
+            let times = file_times(&live.path);
+            if times != seen {
+                seen = times;
+                info!("Configuration files changed, reloading the configuration");
+                reload_and_log(&live);
+            }
+        }
+    });
+    Ok(())
+}
+
+fn reload_and_log(live: &LiveSettings) {
+    match live.reload() {
+        Ok(report) if report.changed.is_empty() && report.restart_required.is_empty() => {
+            info!("Configuration reloaded, nothing changed");
+        }
+        Ok(report) => {
+            if !report.changed.is_empty() {
+                info!("Configuration reloaded, now using new {}", report.changed.join(", "));
+            }
+            for key in report.restart_required {
+                warn!("Configuration {} changed, restart the server to use it", key);
+            }
+        }
+        Err(e) => error!("Rejected the new configuration, keeping the current one: {}", e),
+    }
+}
+
+// Modification times of the files making the configuration, a file appearing or going away counts too
+fn file_times(path: &str) -> Vec<(PathBuf, Option<SystemTime>)> {
+    let mut files = vec![PathBuf::from(path)];
+    files.extend(conf_d_files(path).unwrap_or_default());
+    files
+        .into_iter()
+        .map(|file| {
+            let modified = std::fs::metadata(&file).and_then(|m| m.modified()).ok();
+            (file, modified)
+        })
+        .collect()
+}
+
+// Dotted key -> value, lists like relays count as a single value
+fn flatten(settings: &Settings) -> Result<BTreeMap<String, Value>, ICTError> {
+    fn walk(prefix: &str, value: Value, keys: &mut BTreeMap<String, Value>) {
+        match value {
+            Value::Object(map) => {
+                for (key, value) in map {
+                    let key = if prefix.is_empty() { key } else { format!("{}.{}", prefix, key) };
+                    walk(&key, value, keys);
+                }
+            }
+            value => {
+                keys.insert(prefix.to_string(), value);
+            }
+        }
+    }
+    let mut keys = BTreeMap::new();
+    walk("", serde_json::to_value(settings)?, &mut keys);
+    Ok(keys)
+}
+
+fn changed_keys(before: &BTreeMap<String, Value>, after: &BTreeMap<String, Value>) -> Vec<String> {
+    let keys: BTreeSet<&String> = before.keys().chain(after.keys()).collect();
+    keys.into_iter().filter(|key| before.get(*key) != after.get(*key)).cloned().collect()
+}
@@ -0,0 +1,125 @@
+use ict_server::ict_admin::{self, AdminRequest};
+use ict_server::ict_db::{Db, DeviceStatus};
+use ict_server::ict_errors::ICTError;
+use ict_server::ict_operations::{
+    describe_client, list_clients, review_client, ClientDetails, ClientFilter, ClientSummary, ReviewDecision,
+};
+use serde::de::DeserializeOwned;
+use std::io::{BufRead, Write};
+use std::os::unix::net::UnixStream;
+
+use crate::ict_args::OutputFormat;
+use crate::ict_output;
+
+// Where the decisions are applied, the running server when there is one
+pub enum Backend<'a> {
+    Local(&'a Db),
+    Server(&'a UnixStream),
+}
+
+impl Backend<'_> {
+    fn pending(&self) -> Result<Vec<ClientSummary>, ICTError> {
+        let filter = ClientFilter { status: Some(DeviceStatus::Pending), relay: None };
+        match self {
+            Backend::Local(db) => list_clients(db, &filter),
+            Backend::Server(stream) => {
+                request(stream, &AdminRequest::ListClients { status: filter.status, relay: filter.relay })
+            }
+        }
+    }
+
+    fn describe(&self, uuid: &str) -> Result<ClientDetails, ICTError> {
+        match self {
+            Backend::Local(db) => describe_client(db, uuid),
+            Backend::Server(stream) => request(stream, &AdminRequest::DescribeClient { uuid: uuid.to_string() }),
+        }
+    }
+
+    fn review(&self, uuid: &str, decision: &ReviewDecision) -> Result<(), ICTError> {
+        match self {
+            Backend::Local(db) => review_client(db, uuid, decision),
+            Backend::Server(stream) => {
+                let review = AdminRequest::Review { uuid: uuid.to_string(), decision: decision.clone() };
+                request::<serde_json::Value>(stream, &review).map(|_| ())
+            }
+        }
+    }
+}
+
+fn request<T: DeserializeOwned>(stream: &UnixStream, request: &AdminRequest) -> Result<T, ICTError> {
+    let response = ict_admin::send(stream, request)?;
+    match response.error {
+        Some(e) => Err(ICTError::Custom(e)),
+        None => Ok(serde_json::from_value(response.data)?),
+    }
+}
+
+// Walks through the pending registrations, stops at the end or when the admin quits
+pub fn run(backend: &Backend, input: &mut impl BufRead) -> Result<(), ICTError> {
+    let pending = backend.pending()?;
+    if pending.is_empty() {
+        println!("No registration waiting for review");
+        return Ok(());
+    }
+    for (i, client) in pending.iter().enumerate() {
+        let uuid = client.id.to_string();
+        println!();
+        println!("Registration {} of {}", i + 1, pending.len());
+        ict_output::print_client(&backend.describe(&uuid)?, OutputFormat::Table);
+
+        let decision = loop {
+            let Some(answer) = prompt(input, "Approve, reject, skip or quit? [a/r/s/q] ")? else {
+                return Ok(());
+            };
+            match answer.to_lowercase().as_str() {
+                "a" | "approve" => match prompt_relays(input)? {
+                    Some(relays) => break ReviewDecision::Approve(relays),
+                    None => return Ok(()),
+                },
+                "r" | "reject" => {
+                    let confirm = prompt(input, &format!("Delete client {}? [y/N] ", uuid))?;
+                    if confirm.is_some_and(|c| c.eq_ignore_ascii_case("y")) {
+                        break ReviewDecision::Reject;
+                    }
+                }
+                "s" | "skip" => break ReviewDecision::Skip,
+                "q" | "quit" => return Ok(()),
+                _ => println!("Please answer a, r, s or q"),
+            }
+        };
+        match backend.review(&uuid, &decision) {
+            Ok(_) => println!("Client {}: {:?}", uuid, decision),
+            Err(e) => println!("Failed review of client {} with {}", uuid, e),
+        }
+    }
+    Ok(())
+}
+
+// Trimmed answer, None at the end of the input
+fn prompt(input: &mut impl BufRead, question: &str) -> Result<Option<String>, ICTError> {
+    print!("{}", question);
+    std::io::stdout().flush()?;
+    let mut line = String::new();
+    if input.read_line(&mut line)? == 0 {
+        println!();
+        return Ok(None);
+    }
+    Ok(Some(line.trim().to_string()))
+}
+
+fn prompt_relays(input: &mut impl BufRead) -> Result<Option<Vec<u8>>, ICTError> {
+    loop {
+        let Some(answer) = prompt(input, "Relays to associate, ie 16 20 (empty for none): ")? else {
+            return Ok(None);
+        };
+        let relays: Result<Vec<u8>, _> = answer
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|relay| !relay.is_empty())
+            .map(str::parse)
+            .collect();
+        match relays {
+            Ok(relays) => return Ok(Some(relays)),
+            Err(_) => println!("Relays are numbers between 0 and 255"),
+        }
+    }
+}
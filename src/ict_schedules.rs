@@ -0,0 +1,142 @@
+use chrono::{DateTime, Datelike, Duration, Local, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use uuid::Uuid;
+
+use crate::ict_errors::ICTError;
+
+const DAY_NAMES: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+const FULL_DAY_NAMES: [&str; 7] = ["monday", "tuesday", "wednesday", "thursday", "friday", "saturday", "sunday"];
+const TIME_FORMAT: &str = "%H:%M";
+
+// Access window of a device (relay_id None) or of one of its relays.
+// When start is after end the window wraps past midnight and belongs to the day it starts on,
+// when start equals end it is the whole day.
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    pub id: i64,
+    pub device_id: Uuid,
+    pub relay_id: Option<u8>,
+    pub weekdays: Weekdays,
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+    pub timezone: Tz,
+}
+
+// Bit 0 is monday, bit 6 is sunday
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Weekdays(pub u8);
+
+impl Schedule {
+    pub fn new(
+        device_id: Uuid,
+        relay_id: Option<u8>,
+        weekdays: Weekdays,
+        start: &str,
+        end: &str,
+        timezone: &str,
+    ) -> Result<Schedule, ICTError> {
+        Ok(Schedule {
+            id: 0,
+            device_id,
+            relay_id,
+            weekdays,
+            start: parse_time(start)?,
+            end: parse_time(end)?,
+            timezone: timezone
+                .parse()
+                .map_err(|_| ICTError::Custom(format!("Unknown timezone {}", timezone)))?,
+        })
+    }
+
+    pub fn allows(&self, at: DateTime<Utc>) -> bool {
+        let local = at.with_timezone(&self.timezone);
+        let time = local.time();
+        let today = self.weekdays.contains(local.weekday().num_days_from_monday());
+        if self.start == self.end {
+            today
+        } else if self.start < self.end {
+            today && self.start <= time && time < self.end
+        } else {
+            let yesterday = (local - Duration::days(1)).weekday().num_days_from_monday();
+            (today && time >= self.start) || (self.weekdays.contains(yesterday) && time < self.end)
+        }
+    }
+
+    pub fn start_as_string(&self) -> String {
+        self.start.format(TIME_FORMAT).to_string()
+    }
+
+    pub fn end_as_string(&self) -> String {
+        self.end.format(TIME_FORMAT).to_string()
+    }
+}
+
+impl Weekdays {
+    // Accepts comma separated days or ranges of days, ie "mon-fri" or "sat,sun" or "mon,wed-fri"
+    pub fn parse(days: &str) -> Result<Weekdays, ICTError> {
+        let mut mask = 0u8;
+        for part in days.split(',').map(str::trim) {
+            let (first, last) = match part.split_once('-') {
+                Some((first, last)) => (day_index(first)?, day_index(last)?),
+                None => (day_index(part)?, day_index(part)?),
+            };
+            if first > last {
+                return Err(ICTError::Custom(format!("Invalid range of days {}", part)));
+            }
+            for day in first..=last {
+                mask |= 1 << day;
+            }
+        }
+        Ok(Weekdays(mask))
+    }
+
+    pub fn contains(&self, day_from_monday: u32) -> bool {
+        self.0 & (1 << day_from_monday) != 0
+    }
+}
+
+impl std::fmt::Display for Weekdays {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let days: Vec<&str> = (0..7)
+            .filter(|day| self.contains(*day))
+            .map(|day| DAY_NAMES[day as usize])
+            .collect();
+        write!(f, "{}", days.join(","))
+    }
+}
+
+fn day_index(day: &str) -> Result<u32, ICTError> {
+    let day = day.trim().to_lowercase();
+    DAY_NAMES
+        .iter()
+        .position(|name| day == *name)
+        .or_else(|| FULL_DAY_NAMES.iter().position(|name| day == *name))
+        .map(|index| index as u32)
+        .ok_or(ICTError::Custom(format!("Unknown day {}", day)))
+}
+
+fn parse_time(time: &str) -> Result<NaiveTime, ICTError> {
+    NaiveTime::parse_from_str(time, TIME_FORMAT)
+        .map_err(|_| ICTError::Custom(format!("Invalid time {}, expected HH:MM", time)))
+}
+
+// Accepts RFC 3339 or, in the server's local time, "YYYY-MM-DD HH:MM[:SS]" and "YYYY-MM-DD"
+pub fn parse_datetime(value: &str) -> Result<DateTime<Utc>, ICTError> {
+    if let Ok(datetime) = DateTime::parse_from_rfc3339(value) {
+        return Ok(datetime.with_timezone(&Utc));
+    }
+    let naive = ["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M", "%Y-%m-%dT%H:%M"]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(value, format).ok())
+        .or_else(|| {
+            NaiveDate::parse_from_str(value, "%Y-%m-%d")
+                .ok()
+                .and_then(|date| date.and_hms_opt(0, 0, 0))
+        })
+        .ok_or(ICTError::Custom(format!("Invalid date/time {}", value)))?;
+    Local
+        .from_local_datetime(&naive)
+        .earliest()
+        .map(|datetime| datetime.with_timezone(&Utc))
+        .ok_or(ICTError::Custom(format!("Date/time {} does not exist in local time", value)))
+}
@@ -1,10 +1,26 @@
 use crate::ict_db::Db;
 use crate::ict_operations::{operate, register};
 use crate::ict_config::Settings;
-use log::{info,error};
-use rouille::{router, Response};
+use crate::ict_health::{self, Health};
+use crate::ict_logging;
+use crate::ict_metrics::metrics;
+use crate::ict_reload::LiveSettings;
+use crate::ict_errors::ICTError;
+use crate::ict_relays;
+use log::{info,error,warn};
+use rouille::{router, Request, Response};
 use serde::{Deserialize, Serialize};
-use std::time::Instant;
+use std::net::SocketAddr;
+use std::sync::mpsc::{self, Sender, TryRecvError};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+// How often the listeners check whether they must stop
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(100);
+// Left to the requests whose pulses a shutdown cancelled to answer
+const ABORT_GRACE: Duration = Duration::from_secs(2);
 
 // Simulate a DB with ID → (public_key, secret)
 // type Db = Arc<Mutex<HashMap<String, (String, String)>>>;
@@ -13,6 +29,8 @@ use std::time::Instant;
 struct RegisterRequest {
     id: String,
     pem_public_key: String,
+    #[serde(default)]
+    display_name: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -27,66 +45,274 @@ struct SecretResponse {
     encrypted_secret: String,
 }
 
-pub fn start_web_server(port: &u32, db: &Db, settings: Settings) {
+// A running server, it serves until shutdown is called or it is dropped
+pub struct WebServer {
+    addr: SocketAddr,
+    listeners: Vec<(Sender<()>, JoinHandle<()>)>,
+    in_flight: Arc<InFlight>,
+}
+
+impl WebServer {
+    // The address of the API, ie the port picked when started on port 0
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    // Stops accepting connections, lets the requests and relay pulses in flight complete until the deadline,
+    // cancels the pulses still going after it and leaves the relays idle; returns false if anything was cut short
+    pub fn shutdown(self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        for (stop, listener) in self.listeners {
+            let _ = stop.send(());
+            let _ = listener.join();
+        }
+        info!("Stopped accepting connections, {} requests in flight", self.in_flight.count());
+        let drained = ict_relays::controller().drain(deadline);
+        let answered = self.in_flight.wait(deadline.max(Instant::now()) + ABORT_GRACE);
+        if !answered {
+            warn!("{} requests still in flight after the shutdown deadline", self.in_flight.count());
+        }
+        ict_relays::controller().reset("shutdown");
+        drained && answered
+    }
+}
+
+// Requests being answered
+#[derive(Default)]
+struct InFlight {
+    count: Mutex<usize>,
+    done: Condvar,
+}
+
+impl InFlight {
+    fn enter(self: &Arc<Self>) -> Answering {
+        *self.lock() += 1;
+        Answering(self.clone())
+    }
+
+    fn count(&self) -> usize {
+        *self.lock()
+    }
+
+    // Returns false if requests are still in flight at the deadline
+    fn wait(&self, deadline: Instant) -> bool {
+        let count = self.lock();
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        let (count, _) = self
+            .done
+            .wait_timeout_while(count, timeout, |count| *count > 0)
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        *count == 0
+    }
+
+    fn lock(&self) -> MutexGuard<'_, usize> {
+        self.count.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+// Counts a request as in flight until dropped, even if its handler panics
+struct Answering(Arc<InFlight>);
+
+impl Drop for Answering {
+    fn drop(&mut self) {
+        *self.0.lock() -= 1;
+        self.0.done.notify_all();
+    }
+}
+
+// Accepts connections on a thread of its own until told to stop
+fn listen<F>(server: rouille::Server<F>) -> (Sender<()>, JoinHandle<()>)
+where
+    F: Send + Sync + 'static + Fn(&Request) -> Response,
+{
+    let (stop, stopped) = mpsc::channel();
+    let listener = thread::spawn(move || {
+        while let Err(TryRecvError::Empty) = stopped.try_recv() {
+            server.poll_timeout(ACCEPT_INTERVAL);
+        }
+    });
+    (stop, listener)
+}
+
+pub fn start_web_server(port: &u32, db: &Db, live: Arc<LiveSettings>) -> Result<WebServer, ICTError> {
     let db_path2 = db.path.clone();
-    rouille::start_server(format!("0.0.0.0:{}", port), move |request| {
+    let metrics_listen = live.current().metrics.listen.clone();
+    let mut listeners = Vec::new();
+    if let Some(listen) = &metrics_listen {
+        listeners.extend(start_metrics_server(listen, db.path.clone()));
+    }
+    // a server started again in the same process pulses again
+    ict_relays::controller().resume();
+    let started = Instant::now();
+    let in_flight = Arc::new(InFlight::default());
+    let counted = in_flight.clone();
+    let server = rouille::Server::new(format!("0.0.0.0:{}", port), move |request| {
+            let _answering = counted.enter();
             let start = Instant::now();
-            let db2 = match Db::newg(db_path2.clone()) {
-                Ok(db2) => db2,
+            let request_id = Uuid::new_v4().simple().to_string();
+            ict_logging::set_request_id(Some(request_id.clone()));
+            let response = respond(request, db_path2.clone(), &live.current(), metrics_listen.is_some(), started);
+            let duration = start.elapsed();
+            info!(
+                route = route(request), remote_addr:% = request.remote_addr(), status = response.status_code, duration_ms = duration.as_secs_f64() * 1000.0;
+                "{} {} from:{} code:{} {:.2?}", request.method(), request.url(), request.remote_addr(), response.status_code, duration
+            );
+            ict_logging::set_request_id(None);
+            metrics().record_request(route(request), response.status_code, duration);
+            response.with_additional_header("X-Request-Id", request_id)
+        })
+        .map_err(|e| ICTError::Custom(format!("Failed to listen on port {} with {}", port, e)))?;
+    let addr = server.server_addr();
+    listeners.push(listen(server));
+    Ok(WebServer { addr, listeners, in_flight })
+}
+
+// /metrics on a listener of its own, ie bound to localhost only
+fn start_metrics_server(listen: &str, db_path: Option<String>) -> Option<(Sender<()>, JoinHandle<()>)> {
+    let server = rouille::Server::new(listen, move |request| {
+        router!(request,
+            (GET) (/metrics) => {
+                match Db::newg(db_path.clone()) {
+                    Ok(db) => metrics_response(&db),
+                    Err(e) => {
+                        error!("Could not instantiate Db while processing metrics with {}",e);
+                        metrics().record_error(&e);
+                        Response::text("Server Failure").with_status_code(500)
+                    },
+                }
+            },
+            _ => Response::empty_404()
+        )
+    });
+    match server {
+        Ok(server) => {
+            info!("Serving metrics on {}", listen);
+            Some(self::listen(server))
+        }
+        Err(e) => {
+            error!("Failed to listen for metrics on {} with {}", listen, e);
+            None
+        }
+    }
+}
+
+fn metrics_response(db: &Db) -> Response {
+    Response::from_data("text/plain; version=0.0.4", metrics().render(db))
+}
+
+// Known routes only, so that random paths do not create new series
+fn route(request: &Request) -> &'static str {
+    match request.url().as_str() {
+        "/register" => "register",
+        "/operate" => "operate",
+        "/health/live" => "health_live",
+        "/health/ready" => "health_ready",
+        "/metrics" => "metrics",
+        _ => "other",
+    }
+}
+
+// Health answers even when the database cannot be opened, that is what it reports
+fn respond(request: &Request, db_path: Option<String>, settings: &Settings, metrics_listen: bool, started: Instant) -> Response {
+    router!(request,
+        (GET) (/health/live) => {
+            health_response(&ict_health::live(started))
+        },
+        (GET) (/health/ready) => {
+            health_response(&ict_health::ready(db_path.as_deref(), settings, started))
+        },
+        _ => respond_api(request, db_path, settings, metrics_listen)
+    )
+}
+
+fn health_response(health: &Health) -> Response {
+    Response::json(health).with_status_code(if health.is_ok() { 200 } else { 503 })
+}
+
+fn respond_api(request: &Request, db_path: Option<String>, settings: &Settings, metrics_listen: bool) -> Response {
+    let db2 = match Db::newg(db_path) {
+        Ok(db2) => db2,
+        Err(e) => {
+            error!("Could not instantiate Db while processing request with {}",e);
+            metrics().record_error(&e);
+            return Response::text("Server Failure").with_status_code(500)
+        },
+    };
+    router!(request,
+        (POST) (/register) => {
+            let body: RegisterRequest = match rouille::input::json_input(request) {
+                Ok(data) => data,
                 Err(e) => {
-                    error!("Could not instantiate Db while processing request with {}",e);
-                    return Response::text("Server Failure").with_status_code(400)
+                    error!("Could not parse the body {}",e);
+                    return Response::text("Invalid JSON").with_status_code(400)
                 },
             };
-            let response = router!(request,
-                (POST) (/register) => {
-                    let body: RegisterRequest = match rouille::input::json_input(request) {
-                        Ok(data) => data,
-                        Err(e) => {
-                            error!("Could not parse the body {}",e);
-                            return Response::text("Invalid JSON").with_status_code(400)
-                        },
-                    };
-                    match register(&db2,&body.id,&body.pem_public_key) {
-                            Ok(encrypted_secret) => {
-                                info!("Successful register during web request with uuid {}",&body.id);
-                                Response::json(&SecretResponse { encrypted_secret })
-                            },
-                            Err(e) => {
-                                error!("Failed register during web request uuid {} with {}",&body.id,e);
-                                Response::text("Registration Failed").with_status_code(400)
-                            },
-                        }
-                },
+            match register(&db2,&body.id,&body.pem_public_key,body.display_name.as_deref()) {
+                    Ok(encrypted_secret) => {
+                        info!(uuid = body.id.as_str(), outcome = "success"; "Successful register during web request with uuid {}",&body.id);
+                        Response::json(&SecretResponse { encrypted_secret })
+                    },
+                    Err(e) => {
+                        error!(uuid = body.id.as_str(), outcome = e.kind(); "Failed register during web request uuid {} with {}",&body.id,e);
+                        metrics().record_error(&e);
+                        Response::text("Registration Failed").with_status_code(400)
+                    },
+                }
+        },
 
-                (POST) (/operate) => {
-                    let body: OperateRequest = match rouille::input::json_input(request) {
-                        Ok(data) => data,
-                        Err(_) => return Response::text("Invalid JSON").with_status_code(400),
-                    };
-
-                    match operate(&db2,&body.id,&body.totp_message,&body.signature, settings.totp.sha.clone(), &settings.pi.close_duration) {
-                            Ok(_) => {
-                                info!("Successful operate during web request with uuid {}",&body.id);
-                                Response::text("Operate successful")
-                            },
-                            Err(e) => {
-                                error!("Failed operate during web request uuid {} with {}",&body.id,e);
-                                Response::text("Operate Failed").with_status_code(400)
-                            },
-                         }
-                },
+        (POST) (/operate) => {
+            let body: OperateRequest = match rouille::input::json_input(request) {
+                Ok(data) => data,
+                Err(_) => return Response::text("Invalid JSON").with_status_code(400),
+            };
 
-                (GET) (/stats) => {
-                    Response::text("Server up and running").with_status_code(200)
-                },
+            let result = operate(&db2,&body.id,&body.totp_message,&body.signature, settings.totp.sha, &settings.pi.close_duration, Some(&request.remote_addr().ip().to_string()));
+            metrics().record_operate(match &result {
+                Ok(status) if !status.is_complete() => Ok("pending"),
+                Ok(_) => Ok("success"),
+                Err(e) => Err(e),
+            });
+            match result {
+                    Ok(status) if !status.is_complete() => {
+                        info!(uuid = body.id.as_str(), outcome = "pending"; "Pending operate during web request with uuid {}, {:?}",&body.id,status.pending);
+                        Response::json(&status).with_status_code(202)
+                    },
+                    Ok(status) => {
+                        info!(uuid = body.id.as_str(), outcome = "success"; "Successful operate during web request with uuid {}",&body.id);
+                        Response::json(&status)
+                    },
+                    Err(ICTError::Interlock(e)) => {
+                        error!(uuid = body.id.as_str(), outcome = "interlock"; "Interlock conflict during web request uuid {} with {}",&body.id,e);
+                        Response::text("Operate Conflict").with_status_code(409)
+                    },
+                    Err(ICTError::OutsideSchedule(e)) => {
+                        error!(uuid = body.id.as_str(), outcome = "outside_schedule"; "Operate outside of schedule during web request uuid {} with {}",&body.id,e);
+                        Response::text("Operate Outside Schedule").with_status_code(403)
+                    },
+                    Err(ICTError::QuotaExceeded(e)) => {
+                        error!(uuid = body.id.as_str(), outcome = "quota_exceeded"; "Quota exceeded during web request uuid {} with {}",&body.id,e);
+                        Response::text("Operate Quota Exceeded").with_status_code(429)
+                    },
+                    Err(ICTError::ShuttingDown) => {
+                        warn!(uuid = body.id.as_str(), outcome = "shutting_down"; "Operate refused during web request uuid {}, the server is shutting down",&body.id);
+                        Response::text("Server Shutting Down").with_status_code(503)
+                    },
+                    Err(e) => {
+                        error!(uuid = body.id.as_str(), outcome = e.kind(); "Failed operate during web request uuid {} with {}",&body.id,e);
+                        Response::text("Operate Failed").with_status_code(400)
+                    },
+                 }
+        },
 
-                _ => {
-                    Response::empty_404()
-                }
-            );
-            let duration = start.elapsed();
-            info!("{} {} from:{} code:{} {:.2?}", request.method(), request.url(), request.remote_addr(), response.status_code, duration);
-            response
-        })
+        (GET) (/metrics) => {
+            if metrics_listen {
+                return Response::empty_404()
+            }
+            metrics_response(&db2)
+        },
+
+        _ => {
+            Response::empty_404()
+        }
+    )
 }
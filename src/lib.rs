@@ -1,5 +1,17 @@
 pub mod ict_db;
+pub mod ict_migrations;
 pub mod ict_operations;
 pub mod ict_errors;
 pub mod ict_web;
 pub mod ict_config;
+pub mod ict_relays;
+pub mod ict_schedules;
+pub mod ict_admin;
+pub mod ict_bundle;
+pub mod ict_reload;
+pub mod ict_metrics;
+pub mod ict_health;
+pub mod ict_logging;
+pub mod ict_events;
+pub mod ict_mqtt;
+pub mod ict_inputs;
@@ -1,15 +1,32 @@
 mod ict_args;
+mod ict_output;
+mod ict_review;
 
-use ict_args::Operation;
-use ict_server::ict_config::load_config;
+use ict_args::{DbCommand, ImportModeArg, LockoutArg, Operation};
+use ict_server::ict_admin::{self, AdminRequest};
+use ict_server::ict_bundle::{self, ImportMode};
+use ict_server::ict_config::{check_paths, load_config, Settings};
 use ict_server::ict_db::Db;
+use ict_server::ict_events;
+use ict_server::ict_inputs;
+use ict_server::ict_logging;
+use ict_server::ict_migrations;
+use ict_server::ict_mqtt;
+use ict_server::ict_operations::{ClientDetails, ClientSummary};
 use ict_server::ict_operations::{
-    associate_relay, authorize, clear_relays, delete_device, describe_client, list_clients,
-    operate, register, unauthorize,
+    add_group, add_group_member, add_schedule, associate_relay, authorize_between, clear_quota,
+    clear_relays, delete_device, delete_group, describe_client, grant_group_relay, list_clients,
+    list_groups, list_schedules, ClientFilter, operate, register, remove_group_member, remove_schedule, revoke,
+    revoke_group_relay, set_client_info, set_quota, suspend,
 };
+use ict_server::ict_relays;
+use ict_server::ict_reload::{self, LiveSettings};
 use ict_server::ict_web::start_web_server;
-use log::{error, info, LevelFilter};
-use std::str::FromStr;
+use log::{error, info};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 fn main() {
     let args = ict_args::load_args();
@@ -20,13 +37,52 @@ fn main() {
             std::process::exit(1);
         }
     };
-    env_logger::Builder::from_default_env()
-        .format_timestamp_secs()
-        .filter_level(LevelFilter::from_str(&settings.logs.level).unwrap_or(LevelFilter::Info))
-        .init();
+    if let Err(e) = ict_logging::init(&settings.logs) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
     info!("ICT Server starting");
     info!("Using config file: {}", args.config);
     info!("Using DB file: {}", settings.database.path);
+    ict_reload::apply(&settings);
+
+    if let Operation::CheckConfig = &args.operation {
+        check_config(&settings);
+        return;
+    }
+    if let Operation::Db { command: DbCommand::Migrate { dry_run } } = &args.operation {
+        migrate_db(&settings.database.path, *dry_run);
+        return;
+    }
+
+    // a running server owns the database, admin commands go through its socket
+    if let Operation::Review = &args.operation {
+        if let Some(stream) = ict_admin::connect(&settings.admin_socket()) {
+            review(&ict_review::Backend::Server(&stream));
+            return;
+        }
+    }
+    if let Some(request) = admin_request(&args.operation) {
+        match ict_admin::connect(&settings.admin_socket()) {
+            Some(stream) => {
+                run_through_server(&stream, request, &args.operation);
+                return;
+            }
+            // only a running server has inputs to lock out
+            None if matches!(request, AdminRequest::Status | AdminRequest::Lockout { .. }) => {
+                error!("No server listening on {}", settings.admin_socket().display());
+                std::process::exit(1);
+            }
+            None => {}
+        }
+    }
+    // an import rewrites whole tables, the running server would keep working from stale state
+    if let Operation::Import { .. } = &args.operation {
+        if ict_admin::connect(&settings.admin_socket()).is_some() {
+            error!("A server listens on {}, stop it before importing", settings.admin_socket().display());
+            std::process::exit(1);
+        }
+    }
 
     let db = Db::new(&settings.database.path).unwrap_or_else(|e| {
         error!("Failed to open DB with {}", e);
@@ -34,30 +90,54 @@ fn main() {
     });
 
     match &args.operation {
-        Operation::Register { uuid, public_key } => {
-            let secret = register(&db, uuid, public_key).unwrap_or_else(|e| {
+        Operation::Register { uuid, public_key, name } => {
+            let secret = register(&db, uuid, public_key, name.as_deref()).unwrap_or_else(|e| {
                 error!("Failed egistration of new client uuid {} with {}",uuid, e);
                 std::process::exit(1);
             });
             info!("Successful registration of new client uuid {}, secret is {}", uuid, secret);
         }
-        Operation::Authorize { uuid } => {
-            match authorize(&db, uuid) {
+        Operation::Authorize { uuid, from, until, reason } => {
+            match authorize_between(&db, uuid, from.as_deref(), until.as_deref(), reason.as_deref()) {
                 Ok(_) => {
                     info!("Successful Authorization of registered client uuid {}",uuid);
                 }
                 Err(e) => {
                     error!("Failed Authoriztion of registed client uuid {} with {}", uuid, e);
+                    std::process::exit(e.exit_code());
+                }
+            }
+        }
+        Operation::Suspend { uuid, reason } => {
+            match suspend(&db, uuid, reason.as_deref()) {
+                Ok(_) => {
+                    info!("Successful suspension of registed client uuid {}",uuid);
+                }
+                Err(e) => {
+                    error!("Failed suspension of registed client uuid {} with {}",uuid,e);
+                    std::process::exit(e.exit_code());
                 }
             }
         }
-        Operation::Unauthorize { uuid } => {
-            match unauthorize(&db, uuid) {
+        Operation::Revoke { uuid, reason } => {
+            match revoke(&db, uuid, reason.as_deref()) {
                 Ok(_) => {
-                    info!("Successful un-authorization of registed client uuid {}",uuid);
+                    info!("Successful revocation of registed client uuid {}",uuid);
                 }
                 Err(e) => {
-                    error!("Failed un-authorization of registed client uuid {} with {}",uuid,e);
+                    error!("Failed revocation of registed client uuid {} with {}",uuid,e);
+                    std::process::exit(e.exit_code());
+                }
+            }
+        }
+        Operation::SetClientInfo { uuid, name, owner, notes } => {
+            match set_client_info(&db, uuid, name.as_deref(), owner.as_deref(), notes.as_deref()) {
+                Ok(_) => {
+                    info!("Successful update of info of client uuid {}",uuid);
+                }
+                Err(e) => {
+                    error!("Failed update of info of client uuid {} with {}",uuid,e);
+                    std::process::exit(e.exit_code());
                 }
             }
         }
@@ -68,24 +148,44 @@ fn main() {
                 }
                 Err(e) => {
                     error!("Failed delete of client uuid {} with {}",uuid,e);
+                    std::process::exit(e.exit_code());
                 }
             }
         }
         Operation::Operate { uuid, message ,signature} => {
-            match operate(&db, uuid, &message, signature, settings.totp.sha, &settings.pi.close_duration) {
+            match operate(&db, uuid, message, signature, settings.totp.sha, &settings.pi.close_duration, None) {
+                Ok(status) if !status.is_complete() => {
+                    info!("Pending operate relays of client uuid {} with {:?}",uuid,status.pending);
+                }
                 Ok(_) => {
                     info!("Successful operate relays of client uuid {}",uuid);
                 }
                 Err(e) => {
                     error!("Failed operate relays of client uuid {} with {}",uuid,e);
+                    std::process::exit(e.exit_code());
+                }
+            }
+        }
+        Operation::ListClients { format, status, relay } => {
+            match list_clients(&db, &ClientFilter { status: *status, relay: *relay }) {
+                Ok(clients) => ict_output::print_clients(&clients, *format),
+                Err(e) => {
+                    error!("Failed listing clients with {}",e);
+                    std::process::exit(e.exit_code());
                 }
             }
         }
-        Operation::ListClients {} => {
-            let _ = list_clients(&db);
+        Operation::DescribeClient { uuid, format } => {
+            match describe_client(&db, uuid) {
+                Ok(client) => ict_output::print_client(&client, *format),
+                Err(e) => {
+                    error!("Failed describing client {} with {}",uuid,e);
+                    std::process::exit(e.exit_code());
+                }
+            }
         }
-        Operation::DescribeClient { uuid } => {
-            let _ = describe_client(&db, uuid);
+        Operation::Review => {
+            review(&ict_review::Backend::Local(&db));
         }
         Operation::AssociateRelay { uuid, relay } => {
             match associate_relay(&db, uuid, relay) {
@@ -94,6 +194,7 @@ fn main() {
                 }
                 Err(e) => {
                     error!("Failed associate relay {} on client {} with {}",relay,uuid,e);
+                    std::process::exit(e.exit_code());
                 }
             }
         }
@@ -103,13 +204,413 @@ fn main() {
                     info!("Successful clear relays on client {}",uuid);
                 }
                 Err(e) => {
-                    error!("Successful clear relays on client {} with {}",uuid,e);
+                    error!("Failed clear relays on client {} with {}",uuid,e);
+                    std::process::exit(e.exit_code());
+                }
+            }
+        }
+        Operation::AddSchedule { uuid, relay, days, from, until, timezone } => {
+            match add_schedule(&db, uuid, *relay, days, from, until, timezone) {
+                Ok(id) => {
+                    info!("Successful add schedule {} on client {}",id,uuid);
+                }
+                Err(e) => {
+                    error!("Failed add schedule on client {} with {}",uuid,e);
+                    std::process::exit(e.exit_code());
+                }
+            }
+        }
+        Operation::ListSchedules { uuid, format } => {
+            match list_schedules(&db, uuid.as_deref()) {
+                Ok(schedules) => ict_output::print_schedules(&schedules, *format),
+                Err(e) => {
+                    error!("Failed listing schedules with {}",e);
+                    std::process::exit(e.exit_code());
+                }
+            }
+        }
+        Operation::RemoveSchedule { id } => {
+            match remove_schedule(&db, *id) {
+                Ok(_) => {
+                    info!("Successful remove schedule {}",id);
+                }
+                Err(e) => {
+                    error!("Failed remove schedule {} with {}",id,e);
+                    std::process::exit(e.exit_code());
+                }
+            }
+        }
+        Operation::SetQuota { uuid, per_hour, per_day, total, min_interval } => {
+            match set_quota(&db, uuid, *per_hour, *per_day, *total, *min_interval) {
+                Ok(_) => {
+                    info!("Successful set quota on client {}",uuid);
+                }
+                Err(e) => {
+                    error!("Failed set quota on client {} with {}",uuid,e);
+                    std::process::exit(e.exit_code());
+                }
+            }
+        }
+        Operation::ClearQuota { uuid } => {
+            match clear_quota(&db, uuid) {
+                Ok(_) => {
+                    info!("Successful clear quota on client {}",uuid);
+                }
+                Err(e) => {
+                    error!("Failed clear quota on client {} with {}",uuid,e);
+                    std::process::exit(e.exit_code());
+                }
+            }
+        }
+        Operation::AddGroup { name } => {
+            match add_group(&db, name) {
+                Ok(_) => {
+                    info!("Successful add group {}",name);
+                }
+                Err(e) => {
+                    error!("Failed add group {} with {}",name,e);
+                    std::process::exit(e.exit_code());
+                }
+            }
+        }
+        Operation::DeleteGroup { name } => {
+            match delete_group(&db, name) {
+                Ok(_) => {
+                    info!("Successful delete group {}",name);
+                }
+                Err(e) => {
+                    error!("Failed delete group {} with {}",name,e);
+                    std::process::exit(e.exit_code());
+                }
+            }
+        }
+        Operation::ListGroups { format } => {
+            match list_groups(&db) {
+                Ok(groups) => ict_output::print_groups(&groups, *format),
+                Err(e) => {
+                    error!("Failed listing groups with {}",e);
+                    std::process::exit(e.exit_code());
+                }
+            }
+        }
+        Operation::AddGroupMember { name, uuid } => {
+            match add_group_member(&db, name, uuid) {
+                Ok(_) => {
+                    info!("Successful add client {} to group {}",uuid,name);
+                }
+                Err(e) => {
+                    error!("Failed add client {} to group {} with {}",uuid,name,e);
+                    std::process::exit(e.exit_code());
+                }
+            }
+        }
+        Operation::RemoveGroupMember { name, uuid } => {
+            match remove_group_member(&db, name, uuid) {
+                Ok(_) => {
+                    info!("Successful remove client {} from group {}",uuid,name);
+                }
+                Err(e) => {
+                    error!("Failed remove client {} from group {} with {}",uuid,name,e);
+                    std::process::exit(e.exit_code());
+                }
+            }
+        }
+        Operation::GrantGroupRelay { name, relay } => {
+            match grant_group_relay(&db, name, relay) {
+                Ok(_) => {
+                    info!("Successful grant relay {} to group {}",relay,name);
+                }
+                Err(e) => {
+                    error!("Failed grant relay {} to group {} with {}",relay,name,e);
+                    std::process::exit(e.exit_code());
+                }
+            }
+        }
+        Operation::RevokeGroupRelay { name, relay } => {
+            match revoke_group_relay(&db, name, relay) {
+                Ok(_) => {
+                    info!("Successful revoke relay {} from group {}",relay,name);
+                }
+                Err(e) => {
+                    error!("Failed revoke relay {} from group {} with {}",relay,name,e);
+                    std::process::exit(e.exit_code());
+                }
+            }
+        }
+        Operation::Export { output, encrypt } => {
+            let passphrase = encrypt.then(|| passphrase(true));
+            let written = ict_bundle::export(&db, &settings)
+                .and_then(|bundle| ict_bundle::to_json(&bundle, passphrase.as_deref()))
+                .and_then(|json| ict_bundle::write(std::path::Path::new(output), &json));
+            match written {
+                Ok(_) => {
+                    info!("Successful export to {}",output);
+                }
+                Err(e) => {
+                    error!("Failed export to {} with {}",output,e);
+                    std::process::exit(e.exit_code());
+                }
+            }
+        }
+        Operation::Import { input, mode } => {
+            let mode = match mode {
+                ImportModeArg::Merge => ImportMode::Merge,
+                ImportModeArg::Replace => ImportMode::Replace,
+            };
+            let imported = std::fs::read_to_string(input)
+                .map_err(Into::into)
+                .and_then(|json| {
+                    let passphrase = ict_bundle::is_encrypted(&json).then(|| passphrase(false));
+                    ict_bundle::from_json(&json, passphrase.as_deref())
+                })
+                .and_then(|bundle| ict_bundle::import(&db, &bundle, mode));
+            match imported {
+                Ok(summary) => {
+                    info!("Successful import of {} clients and {} groups from {}",summary.devices,summary.groups,input);
+                }
+                Err(e) => {
+                    error!("Failed import from {} with {}",input,e);
+                    std::process::exit(e.exit_code());
+                }
+            }
+        }
+        Operation::Snapshot { output } => {
+            match db.snapshot(std::path::Path::new(output)) {
+                Ok(_) => {
+                    info!("Successful snapshot to {}",output);
+                }
+                Err(e) => {
+                    error!("Failed snapshot to {} with {}",output,e);
+                    std::process::exit(e.exit_code());
                 }
             }
         }
         Operation::Serve { port} => {
+            for (key, source) in &settings.sources {
+                info!("Config {} from {}", key, source);
+            }
+            // a previous run may have been killed with relays energized
+            ict_relays::install_panic_hook();
+            ict_relays::controller().reset("startup");
+            if let Err(e) = ict_relays::start_watchdog(settings.pi.watchdog_device.as_deref()) {
+                error!("Failed to start the relay watchdog with {}", e);
+                std::process::exit(1);
+            }
+            let signals = stop_signals();
+            let admin = ict_admin::start(&settings.admin_socket(), db.path.clone(), settings.backup_dir()).unwrap_or_else(|e| {
+                error!("Failed to start admin socket with {}", e);
+                std::process::exit(1);
+            });
+            let live = Arc::new(LiveSettings::new(&args.config, Settings::clone(&settings)));
+            if let Err(e) = ict_reload::watch(live.clone(), Duration::from_secs(2)) {
+                error!("Failed to watch the configuration with {}, reloading is disabled", e);
+            }
+            ict_events::start_worker(db.path.clone());
+            if let Err(e) = ict_inputs::start(live.clone(), db.path.clone()) {
+                error!("Failed to start monitoring the inputs with {}", e);
+            }
+            if let Err(e) = ict_mqtt::start(live.clone(), db.path.clone()) {
+                error!("Failed to start MQTT with {}", e);
+            }
             info!("Starting server on port {}", port);
-            start_web_server(&port, &db, ict_server::ict_config::Settings::clone(&settings));
+            let web = start_web_server(port, &db, live.clone()).unwrap_or_else(|e| {
+                error!("Failed to start the web server with {}", e);
+                std::process::exit(1);
+            });
+
+            let signal = wait_for_stop(signals);
+            let timeout = Duration::from_secs(live.current().web.shutdown_timeout);
+            info!("Stopping on signal {}, waiting up to {:?} for the requests and pulses in flight", signal, timeout);
+            let completed = web.shutdown(timeout);
+            // after the requests, whose operates may have queued events
+            let deadline = Instant::now() + Duration::from_secs(live.current().events.flush_timeout);
+            match ict_events::flush(&db, deadline) {
+                Ok(attempted) if attempted > 0 => info!("Flushed {} webhook deliveries", attempted),
+                Ok(_) => {}
+                Err(e) => error!("Failed flushing the webhooks with {}, they stay in the outbox", e),
+            }
+            ict_relays::stop_watchdog();
+            drop(admin);
+            if !completed {
+                error!("Stopped, cutting short what was still in flight");
+                std::process::exit(1);
+            }
+            info!("Stopped");
+        }
+        Operation::Status | Operation::Lockout { .. } | Operation::CheckConfig | Operation::Db { .. } => {}
+    }
+}
+
+// Handled from startup on, so that no stop signal kills the server with relays energized
+fn stop_signals() -> Signals {
+    Signals::new([SIGTERM, SIGINT]).unwrap_or_else(|e| {
+        error!("Failed to handle the stop signals with {}", e);
+        std::process::exit(1);
+    })
+}
+
+// Blocks until the first stop signal, a second one stops at once with the relays idle
+fn wait_for_stop(mut signals: Signals) -> i32 {
+    let signal = signals.forever().next().unwrap_or(SIGTERM);
+    std::thread::spawn(move || {
+        if let Some(signal) = signals.forever().next() {
+            error!("Stopping at once on signal {}", signal);
+            ict_relays::controller().reset("shutdown");
+            ict_relays::stop_watchdog();
+            std::process::exit(1);
+        }
+    });
+    signal
+}
+
+fn review(backend: &ict_review::Backend) {
+    if let Err(e) = ict_review::run(backend, &mut std::io::stdin().lock()) {
+        error!("Failed review of pending registrations with {}", e);
+        std::process::exit(e.exit_code());
+    }
+}
+
+// Taken from ICT_BUNDLE_PASSPHRASE, or asked on the terminal
+fn passphrase(confirm: bool) -> String {
+    if let Ok(passphrase) = std::env::var("ICT_BUNDLE_PASSPHRASE") {
+        return passphrase;
+    }
+    let asked = rpassword::prompt_password("Bundle passphrase: ").unwrap_or_else(|e| {
+        error!("Failed reading the passphrase with {}", e);
+        std::process::exit(1);
+    });
+    if asked.is_empty() {
+        error!("The passphrase cannot be empty");
+        std::process::exit(1);
+    }
+    if confirm && rpassword::prompt_password("Repeat passphrase: ").ok().as_ref() != Some(&asked) {
+        error!("Passphrases do not match");
+        std::process::exit(1);
+    }
+    asked
+}
+
+// Operations a running server performs on behalf of the command line
+fn admin_request(operation: &Operation) -> Option<AdminRequest> {
+    match operation {
+        Operation::Authorize { uuid, from, until, reason } => Some(AdminRequest::Authorize {
+            uuid: uuid.clone(),
+            from: from.clone(),
+            until: until.clone(),
+            reason: reason.clone(),
+        }),
+        Operation::Suspend { uuid, reason } => Some(AdminRequest::Suspend { uuid: uuid.clone(), reason: reason.clone() }),
+        Operation::Revoke { uuid, reason } => Some(AdminRequest::Revoke { uuid: uuid.clone(), reason: reason.clone() }),
+        Operation::Delete { uuid } => Some(AdminRequest::Delete { uuid: uuid.clone() }),
+        Operation::AssociateRelay { uuid, relay } => Some(AdminRequest::AssociateRelay { uuid: uuid.clone(), relay: *relay }),
+        Operation::SetClientInfo { uuid, name, owner, notes } => Some(AdminRequest::SetClientInfo {
+            uuid: uuid.clone(),
+            name: name.clone(),
+            owner: owner.clone(),
+            notes: notes.clone(),
+        }),
+        Operation::ClearRelays { uuid } => Some(AdminRequest::ClearRelays { uuid: uuid.clone() }),
+        Operation::AddSchedule { uuid, relay, days, from, until, timezone } => Some(AdminRequest::AddSchedule {
+            uuid: uuid.clone(),
+            relay: *relay,
+            days: days.clone(),
+            from: from.clone(),
+            until: until.clone(),
+            timezone: timezone.clone(),
+        }),
+        Operation::RemoveSchedule { id } => Some(AdminRequest::RemoveSchedule { id: *id }),
+        Operation::SetQuota { uuid, per_hour, per_day, total, min_interval } => Some(AdminRequest::SetQuota {
+            uuid: uuid.clone(),
+            per_hour: *per_hour,
+            per_day: *per_day,
+            total: *total,
+            min_interval: *min_interval,
+        }),
+        Operation::ClearQuota { uuid } => Some(AdminRequest::ClearQuota { uuid: uuid.clone() }),
+        Operation::AddGroup { name } => Some(AdminRequest::AddGroup { name: name.clone() }),
+        Operation::DeleteGroup { name } => Some(AdminRequest::DeleteGroup { name: name.clone() }),
+        Operation::AddGroupMember { name, uuid } => Some(AdminRequest::AddGroupMember { name: name.clone(), uuid: uuid.clone() }),
+        Operation::RemoveGroupMember { name, uuid } => Some(AdminRequest::RemoveGroupMember { name: name.clone(), uuid: uuid.clone() }),
+        Operation::GrantGroupRelay { name, relay } => Some(AdminRequest::GrantGroupRelay { name: name.clone(), relay: *relay }),
+        Operation::RevokeGroupRelay { name, relay } => Some(AdminRequest::RevokeGroupRelay { name: name.clone(), relay: *relay }),
+        Operation::ListClients { status, relay, .. } => Some(AdminRequest::ListClients { status: *status, relay: *relay }),
+        Operation::DescribeClient { uuid, .. } => Some(AdminRequest::DescribeClient { uuid: uuid.clone() }),
+        // the server resolves relative paths from its own directory
+        Operation::Snapshot { output } => std::path::absolute(output).ok().map(|path| AdminRequest::Snapshot { path }),
+        Operation::Status => Some(AdminRequest::Status),
+        Operation::Lockout { state } => Some(AdminRequest::Lockout { on: matches!(state, LockoutArg::On) }),
+        _ => None,
+    }
+}
+
+fn run_through_server(stream: &std::os::unix::net::UnixStream, request: AdminRequest, operation: &Operation) {
+    let response = ict_admin::send(stream, &request).unwrap_or_else(|e| {
+        error!("Failed talking to the running server with {}", e);
+        std::process::exit(e.exit_code());
+    });
+    if let Some(e) = response.error {
+        error!("Failed {:?} on the running server with {}", request, e);
+        std::process::exit(response.exit_code);
+    }
+    let printed = match operation {
+        Operation::ListClients { format, .. } => serde_json::from_value::<Vec<ClientSummary>>(response.data)
+            .map(|clients| ict_output::print_clients(&clients, *format)),
+        Operation::DescribeClient { format, .. } => serde_json::from_value::<ClientDetails>(response.data)
+            .map(|client| ict_output::print_client(&client, *format)),
+        Operation::Status => {
+            ict_output::print_json(&response.data);
+            Ok(())
+        }
+        _ => {
+            info!("Successful {:?} on the running server", request);
+            Ok(())
+        }
+    };
+    if let Err(e) = printed {
+        error!("Failed reading the running server's answer with {}", e);
+        std::process::exit(1);
+    }
+}
+
+// Loading the configuration already validated it, what is left are the paths
+fn check_config(settings: &Settings) {
+    let checks = check_paths(settings);
+    ict_output::print_path_checks(&checks);
+    println!();
+    ict_output::print_config_sources(&settings.sources);
+    println!();
+    ict_output::print_json(&settings.redacted());
+    if checks.iter().any(|check| check.problem.is_some()) {
+        std::process::exit(1);
+    }
+}
+
+// Runs outside of Db::new which would migrate on its own
+fn migrate_db(path: &str, dry_run: bool) {
+    let db = Db::open(path).unwrap_or_else(|e| {
+        error!("Failed to open DB with {}", e);
+        std::process::exit(1);
+    });
+    let version = db.schema_version().unwrap_or_else(|e| {
+        error!("Failed to read DB schema version with {}", e);
+        std::process::exit(1);
+    });
+    info!("DB schema version is {}, latest is {}", version, ict_migrations::latest_version());
+    match db.migrate(dry_run) {
+        Ok(migrations) if migrations.is_empty() => {
+            info!("DB schema is up to date");
+        }
+        Ok(migrations) if dry_run => {
+            for m in migrations {
+                info!("Would apply migration {}: {}", m.version, m.description);
+            }
+        }
+        Ok(_) => {
+            info!("Successful DB migration to version {}", ict_migrations::latest_version());
+        }
+        Err(e) => {
+            error!("Failed DB migration with {}", e);
+            std::process::exit(1);
         }
     }
 }
@@ -0,0 +1,91 @@
+use ict_server::{
+    ict_admin::{self, AdminRequest},
+    ict_db::Db,
+    ict_errors::ICTError,
+    ict_operations::register,
+};
+use rand::rngs::OsRng;
+use rsa::{pkcs8::{EncodePublicKey, LineEnding}, RsaPrivateKey, RsaPublicKey};
+use std::os::unix::fs::PermissionsExt;
+use uuid::Uuid;
+
+#[test]
+fn test_admin_socket() -> Result<(), ICTError> {
+    let dir = std::env::temp_dir().join(format!("ict_admin_{}", Uuid::new_v4()));
+    std::fs::create_dir(&dir)?;
+    let db_path = dir.join("ict.db").to_str().unwrap().to_string();
+    let socket = dir.join("ict.sock");
+
+    let db = Db::new(&db_path)?;
+    let id = Uuid::new_v4().to_string();
+    let private_key = RsaPrivateKey::new(&mut OsRng, 2048).expect("failed to generate a key");
+    let pem = RsaPublicKey::from(&private_key).to_public_key_pem(LineEnding::LF).unwrap();
+    register(&db, &id, &pem, None)?;
+
+    assert!(ict_admin::connect(&socket).is_none());
+    let backups = dir.join("backups");
+    let server = ict_admin::start(&socket, Some(db_path.clone()), backups.clone())?;
+    assert!(ict_admin::start(&socket, Some(db_path.clone()), backups.clone()).is_err());
+    assert_eq!(std::fs::metadata(&socket)?.permissions().mode() & 0o777, 0o600);
+    let stream = ict_admin::connect(&socket).expect("server should be listening");
+
+    // snapshots only go to the backup directory
+    let response = ict_admin::send(&stream, &AdminRequest::Snapshot { path: dir.join("copy.db") })?;
+    assert!(response.error.is_some());
+    assert!(!dir.join("copy.db").exists());
+    let response = ict_admin::send(&stream, &AdminRequest::Snapshot { path: backups.join("../copy.db") })?;
+    assert!(response.error.is_some());
+    let response = ict_admin::send(&stream, &AdminRequest::Snapshot { path: backups.join("copy.db") })?;
+    assert_eq!(response.exit_code, 0);
+    assert!(backups.join("copy.db").exists());
+
+    let response = ict_admin::send(&stream, &AdminRequest::Status)?;
+    assert_eq!(response.exit_code, 0);
+    assert_eq!(response.data["clients"], 1);
+
+    let response = ict_admin::send(&stream, &AdminRequest::Authorize { uuid: id.clone(), from: None, until: None, reason: None })?;
+    assert_eq!(response.exit_code, 0);
+    let response = ict_admin::send(&stream, &AdminRequest::Revoke { uuid: id.clone(), reason: Some("stolen".to_string()) })?;
+    assert_eq!(response.data["cancelled"], false);
+    let response = ict_admin::send(&stream, &AdminRequest::Authorize { uuid: id.clone(), from: None, until: None, reason: None })?;
+    assert_eq!(response.exit_code, 4);
+    assert!(response.error.is_some());
+
+    let response = ict_admin::send(&stream, &AdminRequest::DescribeClient { uuid: id.clone() })?;
+    assert_eq!(response.data["status"], "revoked");
+    let response = ict_admin::send(&stream, &AdminRequest::DescribeClient { uuid: Uuid::new_v4().to_string() })?;
+    assert_eq!(response.exit_code, 3);
+
+    // the configuration of clients goes through the server too
+    let requests = [
+        AdminRequest::SetClientInfo { uuid: id.clone(), name: Some("lobby".to_string()), owner: None, notes: None },
+        AdminRequest::SetQuota { uuid: id.clone(), per_hour: Some(5), per_day: None, total: None, min_interval: None },
+        AdminRequest::AddSchedule {
+            uuid: id.clone(),
+            relay: None,
+            days: "mon-fri".to_string(),
+            from: "08:00".to_string(),
+            until: "18:00".to_string(),
+            timezone: "UTC".to_string(),
+        },
+        AdminRequest::AddGroup { name: "staff".to_string() },
+        AdminRequest::GrantGroupRelay { name: "staff".to_string(), relay: 20 },
+        AdminRequest::AddGroupMember { name: "staff".to_string(), uuid: id.clone() },
+    ];
+    for request in requests {
+        assert_eq!(ict_admin::send(&stream, &request)?.exit_code, 0);
+    }
+    let uuid = Uuid::parse_str(&id)?;
+    assert_eq!(db.get_device(uuid)?.unwrap().display_name.as_deref(), Some("lobby"));
+    assert_eq!(db.get_quota(uuid)?.unwrap().max_per_hour, Some(5));
+    assert_eq!(db.get_schedules(Some(uuid))?.len(), 1);
+    assert_eq!(db.get_effective_relays(uuid)?, vec![20]);
+    let response = ict_admin::send(&stream, &AdminRequest::AddGroupMember { name: "nobody".to_string(), uuid: id.clone() })?;
+    assert!(response.error.is_some());
+
+    drop(stream);
+    drop(server);
+    assert!(!socket.exists());
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
@@ -0,0 +1,140 @@
+use ict_server::{
+    ict_config::{load_config, Webhook},
+    ict_bundle::{self, ImportMode, ImportSummary},
+    ict_db::{Db, Device, DeviceStatus, Quota},
+    ict_errors::ICTError,
+    ict_operations::{authorize, revoke, suspend},
+    ict_schedules::{Schedule, Weekdays},
+};
+use chrono::Utc;
+use rand::rngs::OsRng;
+use rsa::{RsaPrivateKey, RsaPublicKey};
+use std::os::unix::fs::PermissionsExt;
+use totp_rs::Secret;
+use uuid::Uuid;
+
+fn add_device(db: &Db, key: &RsaPrivateKey) -> Result<Device, ICTError> {
+    let mut device = Device::new(Uuid::new_v4(), RsaPublicKey::from(key), Secret::generate_secret());
+    device.display_name = Some("front door".to_string());
+    device.status = DeviceStatus::Authorized;
+    db.add_device(&device)?;
+    Ok(device)
+}
+
+#[test]
+fn test_export_import() -> Result<(), ICTError> {
+    let key = RsaPrivateKey::new(&mut OsRng, 2048).expect("failed to generate a key");
+    let db = Db::new_test_db()?;
+    let device = add_device(&db, &key)?;
+    db.add_relay(device.id, 16)?;
+    db.add_schedule(&Schedule::new(device.id, Some(16), Weekdays::parse("mon-fri")?, "08:00", "18:00", "Europe/Paris")?)?;
+    db.set_quota(&Quota {
+        device_id: device.id,
+        max_per_hour: Some(3),
+        max_per_day: None,
+        max_total: None,
+        min_interval: Some(10),
+        since: Utc::now(),
+    })?;
+    let staff = db.add_group("staff")?;
+    db.add_group_relay(staff, 20)?;
+    db.add_group_member(staff, device.id)?;
+
+    let mut settings = load_config("configs/ict_server.toml")?;
+    settings.mqtt.password = Some("broker-password".to_string());
+    settings.webhooks.push(Webhook {
+        name: "door".to_string(),
+        url: "http://127.0.0.1:9/".to_string(),
+        events: Vec::new(),
+        secret: Some("hmac-secret".to_string()),
+        template: None,
+        max_attempts: 10,
+    });
+    let plain = ict_bundle::to_json(&ict_bundle::export(&db, &settings)?, None)?;
+    let encrypted = ict_bundle::to_json(&ict_bundle::export(&db, &settings)?, Some("correct horse"))?;
+    assert!(!ict_bundle::is_encrypted(&plain));
+    assert!(ict_bundle::is_encrypted(&encrypted));
+    assert!(!encrypted.contains(&device.id.to_string()));
+    assert!(ict_bundle::from_json(&encrypted, None).is_err());
+    assert!(ict_bundle::from_json(&encrypted, Some("wrong")).is_err());
+
+    // replacing drops the clients that are not in the bundle
+    let restored = Db::new_test_db()?;
+    let stray = add_device(&restored, &key)?;
+    let bundle = ict_bundle::from_json(&encrypted, Some("correct horse"))?;
+    assert_eq!(ict_bundle::import(&restored, &bundle, ImportMode::Replace)?, ImportSummary { devices: 1, groups: 1 });
+    assert!(restored.get_device(stray.id)?.is_none());
+    let loaded = restored.get_device(device.id)?.unwrap();
+    assert_eq!(loaded.totp_secret, device.totp_secret);
+    assert_eq!(loaded.wrapped_pk, device.wrapped_pk);
+    assert_eq!(loaded.display_name.as_deref(), Some("front door"));
+    assert_eq!(loaded.status, DeviceStatus::Authorized);
+    assert_eq!(restored.get_effective_relays(device.id)?, vec![16, 20]);
+    assert_eq!(restored.get_schedules(Some(device.id))?.len(), 1);
+    assert_eq!(restored.get_quota(device.id)?.unwrap().min_interval, Some(10));
+
+    // merging keeps them and importing twice changes nothing
+    let merged = Db::new_test_db()?;
+    let kept = add_device(&merged, &key)?;
+    let bundle = ict_bundle::from_json(&plain, None)?;
+    ict_bundle::import(&merged, &bundle, ImportMode::Merge)?;
+    ict_bundle::import(&merged, &bundle, ImportMode::Merge)?;
+    assert!(merged.get_device(kept.id)?.is_some());
+    assert_eq!(merged.get_devices()?.len(), 2);
+    assert_eq!(merged.get_schedules(Some(device.id))?.len(), 1);
+    assert_eq!(merged.get_groups()?.len(), 1);
+
+    // the configuration travels along for reference
+    assert_eq!(bundle.settings.as_ref().map(|settings| &settings["pi"]["close_duration"]), Some(&settings.pi.close_duration.into()));
+    assert!(!plain.contains("broker-password") && !plain.contains("hmac-secret"));
+
+    // bundles are written for their owner only and never over another file
+    let path = std::env::temp_dir().join(format!("ict_bundle_{}.json", Uuid::new_v4()));
+    ict_bundle::write(&path, &plain)?;
+    assert_eq!(std::fs::metadata(&path)?.permissions().mode() & 0o777, 0o600);
+    assert!(ict_bundle::write(&path, &plain).is_err());
+    std::fs::remove_file(&path)?;
+    Ok(())
+}
+
+#[test]
+fn test_import_keeps_revoked() -> Result<(), ICTError> {
+    let key = RsaPrivateKey::new(&mut OsRng, 2048).expect("failed to generate a key");
+    let db = Db::new_test_db()?;
+    let device = add_device(&db, &key)?;
+    let paused = add_device(&db, &key)?;
+    let bundle = ict_bundle::export(&db, &load_config("configs/ict_server.toml")?)?;
+
+    // revoked and suspended after the export, the bundle still has them authorized
+    revoke(&db, &device.id.to_string(), Some("stolen"))?;
+    suspend(&db, &paused.id.to_string(), None)?;
+    for mode in [ImportMode::Merge, ImportMode::Replace] {
+        ict_bundle::import(&db, &bundle, mode)?;
+        let loaded = db.get_device(device.id)?.unwrap();
+        assert_eq!(loaded.status, DeviceStatus::Revoked);
+        assert_eq!(loaded.status_reason.as_deref(), Some("stolen"));
+        assert_eq!(db.get_device(paused.id)?.unwrap().status, DeviceStatus::Suspended);
+    }
+    assert!(authorize(&db, &device.id.to_string()).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_snapshot() -> Result<(), ICTError> {
+    let dir = std::env::temp_dir().join(format!("ict_snapshot_{}", Uuid::new_v4()));
+    std::fs::create_dir(&dir)?;
+    let key = RsaPrivateKey::new(&mut OsRng, 2048).expect("failed to generate a key");
+    let db = Db::new(dir.join("ict.db").to_str().unwrap())?;
+    let device = add_device(&db, &key)?;
+
+    let copy = dir.join("copy.db");
+    db.snapshot(&copy)?;
+    assert!(db.snapshot(&copy).is_err());
+    assert_eq!(std::fs::metadata(&copy)?.permissions().mode() & 0o777, 0o600);
+    let snapshot = Db::open(copy.to_str().unwrap())?;
+    assert_eq!(snapshot.schema_version()?, db.schema_version()?);
+    assert!(snapshot.get_device(device.id)?.is_some());
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
@@ -0,0 +1,61 @@
+// Helpers shared by the test binaries, each binary only uses some of them
+#![allow(dead_code)]
+
+use base64::{engine::general_purpose, Engine as _};
+use ict_server::{
+    ict_db::Db,
+    ict_operations::{register, OperationMessage},
+};
+use rand::rngs::OsRng;
+use rsa::pkcs1v15::{Pkcs1v15Encrypt, SigningKey};
+use rsa::pkcs8::{EncodePublicKey, LineEnding};
+use rsa::signature::{SignatureEncoding, Signer};
+use rsa::{RsaPrivateKey, RsaPublicKey};
+use serde_json::json;
+use sha2::Sha256;
+use totp_rs::{Secret, TOTP};
+use uuid::Uuid;
+
+// A registered client holding its private key and the TOTP secret the server sent back
+pub struct TestClient {
+    pub id: String,
+    pub signing_key: SigningKey<Sha256>,
+    pub totp: TOTP,
+}
+
+impl TestClient {
+    pub fn register(db: &Db) -> TestClient {
+        TestClient::register_named(db, Some("test client"))
+    }
+
+    pub fn register_named(db: &Db, name: Option<&str>) -> TestClient {
+        let id = Uuid::new_v4().to_string();
+        let private_key = RsaPrivateKey::new(&mut OsRng, 2048).expect("failed to generate a key");
+        let pem_public_key = RsaPublicKey::to_public_key_pem(&RsaPublicKey::from(&private_key), LineEnding::CR)
+            .expect("failed to format public key as string");
+        let encrypted_secret = general_purpose::STANDARD
+            .decode(register(db, &id, &pem_public_key, name).expect("failed to register"))
+            .unwrap();
+        let secret = private_key.decrypt(Pkcs1v15Encrypt, &encrypted_secret).unwrap();
+        let secret = Secret::Encoded(String::from_utf8(secret).unwrap());
+        let totp = TOTP::new(totp_rs::Algorithm::SHA256, 6, 1, 30, secret.to_bytes().unwrap()).unwrap();
+        TestClient { id, signing_key: SigningKey::<Sha256>::new(private_key), totp }
+    }
+
+    // The {token, salt} message of the current TOTP step with a fresh salt, and its base64 signature
+    pub fn signed_message(&self) -> (String, String) {
+        let message = serde_json::to_string(&OperationMessage {
+            token: self.totp.generate_current().unwrap(),
+            _salt: Uuid::new_v4().to_string(),
+        })
+        .unwrap();
+        let signature = general_purpose::STANDARD.encode(self.signing_key.sign(message.as_bytes()).to_bytes());
+        (message, signature)
+    }
+
+    // Body of POST /operate, also the payload of an MQTT operate command
+    pub fn operate_body(&self) -> serde_json::Value {
+        let (message, signature) = self.signed_message();
+        json!({ "id": self.id, "totp_message": message, "signature": signature })
+    }
+}
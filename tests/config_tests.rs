@@ -0,0 +1,165 @@
+use ict_server::ict_config::{check_paths, load_config, Settings, TotpAlgorithm};
+use ict_server::ict_reload::{LiveSettings, ReloadReport};
+use log::LevelFilter;
+use std::path::PathBuf;
+
+const BASE: &str = r#"
+[database]
+path = "DB_PATH"
+
+[totp]
+sha = "SHA512"
+
+[logs]
+level = "Debug"
+
+[pi]
+close_duration = 1000
+"#;
+
+// Writes the configuration to a directory of its own, returns the file
+fn write_config(name: &str, content: &str) -> String {
+    let dir = std::env::temp_dir().join(format!("ict_config_{}_{}", name, std::process::id()));
+    std::fs::create_dir_all(&dir).expect("failed to create the test directory");
+    let file = dir.join("ict_server.toml");
+    std::fs::write(&file, content.replace("DB_PATH", &dir.join("ict.db").to_string_lossy()))
+        .expect("failed to write the test configuration");
+    file.to_string_lossy().to_string()
+}
+
+fn load_content(name: &str, content: &str) -> Result<Settings, String> {
+    load_config(&write_config(name, content)).map_err(|e| e.to_string())
+}
+
+fn load(name: &str, extra: &str) -> Result<Settings, String> {
+    load_content(name, &(BASE.to_string() + extra))
+}
+
+#[test]
+fn test_shipped_configs() {
+    for path in ["configs/ict_server.toml", "configs/ict_server_sha1.toml", "tests/ict_server.toml"] {
+        assert!(load_config(path).is_ok(), "{} does not load", path);
+    }
+}
+
+#[test]
+fn test_typed_values() {
+    let settings = load("typed", "\n[web]\ntls_path = \"tls/\"\n").expect("valid configuration");
+    assert_eq!(settings.totp.sha, TotpAlgorithm::Sha512);
+    assert_eq!(settings.logs.level.filter(), LevelFilter::Debug);
+    assert_eq!(settings.web.tls_path.as_deref(), Some("tls/"));
+
+    let error = load_content("sha", &BASE.replace("SHA512", "md5")).expect_err("unknown algorithm");
+    assert!(error.contains("totp.sha \"md5\" is not supported"), "{}", error);
+    let error = load_content("level", &BASE.replace("Debug", "loud")).expect_err("unknown level");
+    assert!(error.contains("logs.level \"loud\" is not supported"), "{}", error);
+}
+
+#[test]
+fn test_rejected_configs() {
+    // a typo is an error instead of a silently ignored key
+    assert!(load("typo", "\n[web]\ntls_pth = \"tls/\"\n").is_err_and(|e| e.contains("tls_pth")));
+    assert!(load("section", "\n[webs]\n").is_err_and(|e| e.contains("webs")));
+
+    // all the problems are reported at once
+    let error = load("invalid", "\n[[relays]]\nid = 3\nquorum = 0\nmax_on = 500\n\n[[interlocks]]\nname = \"gate\"\nrelays = [20, 20]\n")
+        .expect_err("invalid configuration");
+    assert!(error.contains("relay 3: quorum must be at least 1"));
+    assert!(error.contains("relay 3: max_on must be at least pi.close_duration"), "{}", error);
+    assert!(error.contains("interlock gate: needs at least two distinct relays"));
+
+    let error = load_content("logs", &BASE.replace("level = \"Debug\"", "level = \"Debug\"\noutput = \"file\"")).expect_err("no log file");
+    assert!(error.contains("logs.file is required with the file output"), "{}", error);
+
+    let error = load(
+        "webhooks",
+        "\n[[webhooks]]\nname = \"chat\"\nurl = \"ftp://chat\"\ntemplate = '{\"text\": {{summary}}}'\n",
+    )
+    .expect_err("invalid webhook");
+    assert!(error.contains("webhook chat: url \"ftp://chat\" is not an http or https URL"), "{}", error);
+    assert!(error.contains("webhook chat: template does not render to JSON"), "{}", error);
+    assert!(load("event", "\n[[webhooks]]\nname = \"chat\"\nurl = \"http://chat\"\nevents = [\"opened\"]\n")
+        .is_err_and(|e| e.contains("opened")));
+
+    let error = load("mqtt", "\n[mqtt]\nhost = \"broker\"\ntopic_prefix = \"ict/#\"\npassword = \"x\"\nkeep_alive = 1\n")
+        .expect_err("invalid mqtt");
+    for problem in ["mqtt.topic_prefix \"ict/#\" is not a topic", "mqtt.password needs mqtt.username", "mqtt.keep_alive must be at least 5 s"] {
+        assert!(error.contains(problem), "{}", error);
+    }
+    // not checked while MQTT is disabled
+    assert!(load("no mqtt", "\n[mqtt]\ntopic_prefix = \"\"\n").is_ok());
+
+    let error = load(
+        "inputs",
+        "\n[[relays]]\nid = 16\n\n[[inputs]]\nname = \"door\"\npin = 16\n\n[[inputs]]\nname = \"door\"\npin = 5\npull = \"sideways\"\n",
+    )
+    .expect_err("invalid inputs");
+    assert!(error.contains("sideways"), "{}", error);
+    let error = load("inputs", "\n[[relays]]\nid = 16\n\n[[inputs]]\nname = \"door\"\npin = 16\n\n[[inputs]]\nname = \"door\"\npin = 16\n")
+        .expect_err("invalid inputs");
+    for problem in ["input door: pin 16 is a relay", "input door is defined more than once", "input door: pin 16 is used by another input"] {
+        assert!(error.contains(problem), "{}", error);
+    }
+    assert!(load("triggers", "\n[[inputs]]\nname = \"button\"\npin = 5\ntriggers = [20, 5]\n")
+        .is_err_and(|e| e.contains("input button: triggers pin 5 which is an input")));
+}
+
+#[test]
+fn test_check_paths() {
+    let settings = load("paths", "").expect("valid configuration");
+    let checks = check_paths(&settings);
+    assert!(checks.iter().all(|check| check.problem.is_none()), "{:?}", checks);
+    assert_eq!(checks.iter().map(|check| check.name).collect::<Vec<_>>(), ["database", "admin socket"]);
+
+    let settings = load("tls", "\n[web]\ntls_path = \"/nonexistent/tls\"\n\n[admin]\nsocket = \"/nonexistent/ict.sock\"\n")
+        .expect("valid configuration");
+    let failed: Vec<PathBuf> = check_paths(&settings)
+        .into_iter()
+        .filter(|check| check.problem.is_some())
+        .map(|check| check.path)
+        .collect();
+    assert_eq!(
+        failed,
+        [
+            PathBuf::from("/nonexistent/tls/cert.pem"),
+            PathBuf::from("/nonexistent/tls/key.pem"),
+            PathBuf::from("/nonexistent/ict.sock")
+        ]
+    );
+    assert_eq!(settings.redacted()["totp"]["sha"], "sha512");
+}
+
+#[test]
+fn test_reload() {
+    let path = write_config("reload", BASE);
+    let live = LiveSettings::new(&path, load_config(&path).expect("valid configuration"));
+    let database = live.current().database.path.clone();
+
+    write_config("reload", &BASE.replace("1000", "400").replace("DB_PATH", "/elsewhere/ict.db"));
+    let report = live.reload().expect("valid configuration");
+    assert_eq!(
+        report,
+        ReloadReport { changed: vec!["pi.close_duration".to_string()], restart_required: vec!["database.path".to_string()] }
+    );
+    assert_eq!(live.current().pi.close_duration, 400);
+    assert_eq!(live.current().database.path, database);
+
+    // an invalid configuration leaves the running one untouched
+    write_config("reload", &BASE.replace("1000", "0"));
+    assert!(live.reload().is_err());
+    assert_eq!(live.current().pi.close_duration, 400);
+
+    // the whole mqtt section waits for a restart
+    write_config("reload", &format!("{}\n[mqtt]\nhost = \"broker\"\nport = 8883\n", BASE.replace("1000", "400")));
+    let report = live.reload().expect("valid configuration");
+    assert_eq!(report.restart_required, ["mqtt.host", "mqtt.port"]);
+    assert_eq!(live.current().mqtt.host, None);
+
+    // a file that is valid alone is refused when it clashes with the running inputs
+    let path = write_config("reload_inputs", &format!("{}\n[[inputs]]\nname = \"door\"\npin = 5\n", BASE));
+    let live = LiveSettings::new(&path, load_config(&path).expect("valid configuration"));
+    write_config("reload_inputs", &format!("{}\n[[relays]]\nid = 5\n", BASE));
+    assert!(load_config(&path).is_ok());
+    assert!(live.reload().is_err_and(|e| e.to_string().contains("input door: pin 5 is a relay")));
+    assert!(live.current().relays.iter().all(|relay| relay.id != 5));
+}
@@ -1,10 +1,12 @@
 use ict_server::{
-    ict_db::{Db, Device},
+    ict_db::{Db, Device, DeviceStatus, GrantSource, Group, RelayGrant},
     ict_errors::ICTError,
+    ict_migrations,
     ict_operations::OperationMessage,
 };
+use chrono::Utc;
 use rand::rngs::OsRng;
-use rsa::{ RsaPrivateKey, RsaPublicKey};
+use rsa::{pkcs8::EncodePublicKey, RsaPrivateKey, RsaPublicKey};
 use std::{thread};
 use std::time::Duration;
 use totp_rs::{Secret, TOTP};
@@ -26,12 +28,7 @@ fn test_device() -> Result<(), ICTError> {
     let private_key = RsaPrivateKey::new(&mut rng, 2048).expect("failed to generate a key");
     let public_key = RsaPublicKey::from(&private_key);
 
-    let device = Device {
-        id: Uuid::new_v4(),
-        wrapped_pk: public_key,
-        totp_secret: Secret::generate_secret(),
-        authorized: 0,
-    };
+    let device = Device::new(Uuid::new_v4(), public_key, Secret::generate_secret());
 
     db.add_device(&device).unwrap();
     db.print_all_devices().unwrap();
@@ -75,7 +72,7 @@ fn test_device() -> Result<(), ICTError> {
 
     let received_signature = &Signature::try_from(signature_bytes.as_slice()).unwrap();
     assert_eq!(signature,received_signature.clone());
-    verifying_key.verify(&message.as_bytes(),received_signature ).unwrap();
+    verifying_key.verify(message.as_bytes(),received_signature ).unwrap();
 
     // unpack json message {token,salt}
     let parsed: OperationMessage = serde_json::from_str(&message)
@@ -113,12 +110,7 @@ fn test_relays() -> Result<(), ICTError> {
     let private_key = RsaPrivateKey::new(&mut rng, 2048).expect("failed to generate a key");
     let public_key = RsaPublicKey::from(&private_key);
 
-    let device = Device {
-        id: Uuid::new_v4(),
-        wrapped_pk: public_key,
-        totp_secret: Secret::generate_secret(),
-        authorized: 0,
-    };
+    let device = Device::new(Uuid::new_v4(), public_key, Secret::generate_secret());
 
     db.add_device(&device).unwrap();
     db.print_all_devices().unwrap();
@@ -140,3 +132,129 @@ fn test_relays() -> Result<(), ICTError> {
     assert!(db.get_relays(device.id)?.is_empty());
     Ok(())
 }
+
+#[test]
+fn test_authorization_window() -> Result<(), ICTError> {
+    let db = Db::new_test_db()?;
+
+    let private_key = RsaPrivateKey::new(&mut OsRng, 2048).expect("failed to generate a key");
+    let device = Device::new(Uuid::new_v4(), RsaPublicKey::from(&private_key), Secret::generate_secret());
+    db.add_device(&device)?;
+
+    let now = Utc::now();
+    assert_eq!(db.get_device(device.id)?.unwrap().status_at(now), DeviceStatus::Pending);
+
+    let from = now - chrono::Duration::hours(1);
+    let until = now + chrono::Duration::hours(1);
+    db.set_device_status(device.id, DeviceStatus::Authorized, Some("guest"), now)?;
+    db.set_validity_on_device(device.id, Some(from), Some(until))?;
+    let loaded = db.get_device(device.id)?.unwrap();
+    assert_eq!(loaded.valid_from.unwrap().timestamp(), from.timestamp());
+    assert_eq!(loaded.valid_until.unwrap().timestamp(), until.timestamp());
+    assert_eq!(loaded.status_reason.as_deref(), Some("guest"));
+    assert_eq!(loaded.status_changed_at.unwrap().timestamp(), now.timestamp());
+    assert_eq!(loaded.status_at(now), DeviceStatus::Authorized);
+    assert_eq!(loaded.status_at(now + chrono::Duration::hours(2)), DeviceStatus::Expired);
+
+    db.set_validity_on_device(device.id, None, None)?;
+    assert_eq!(db.get_device(device.id)?.unwrap().status_at(now + chrono::Duration::days(3650)), DeviceStatus::Authorized);
+    Ok(())
+}
+
+#[test]
+fn test_groups() -> Result<(), ICTError> {
+    let db = Db::new_test_db()?;
+
+    let private_key = RsaPrivateKey::new(&mut OsRng, 2048).expect("failed to generate a key");
+    let device = Device::new(Uuid::new_v4(), RsaPublicKey::from(&private_key), Secret::generate_secret());
+    db.add_device(&device)?;
+    db.add_relay(device.id, 16)?;
+
+    let staff = db.add_group("staff")?;
+    let cleaning = db.add_group("cleaning")?;
+    assert!(db.add_group("staff").is_err());
+    db.add_group_relay(staff, 16)?;
+    db.add_group_relay(staff, 20)?;
+    db.add_group_relay(cleaning, 21)?;
+
+    // no membership, only the direct grant
+    assert_eq!(db.get_effective_relays(device.id)?, vec![16]);
+
+    db.add_group_member(staff, device.id)?;
+    db.add_group_member(cleaning, device.id)?;
+    assert_eq!(db.get_effective_relays(device.id)?, vec![16, 21, 20]);
+    let grants = db.get_relay_grants(device.id)?;
+    assert_eq!(grants.len(), 4);
+    assert_eq!(grants[0], RelayGrant { relay_id: 16, source: GrantSource::Direct });
+    assert_eq!(grants[1], RelayGrant { relay_id: 21, source: GrantSource::Group("cleaning".to_string()) });
+    assert_eq!(grants[3].source.to_string(), "group staff");
+
+    assert!(db.remove_group_member(cleaning, device.id)?);
+    assert!(!db.remove_group_member(cleaning, device.id)?);
+    db.delete_group(staff)?;
+    assert!(db.get_group("staff")?.is_none());
+    assert_eq!(db.get_groups()?, vec![Group { id: cleaning, name: "cleaning".to_string() }]);
+    assert_eq!(db.get_effective_relays(device.id)?, vec![16]);
+
+    // memberships go away with their device
+    db.add_group_member(cleaning, device.id)?;
+    db.remove_relays(device.id)?;
+    db.delete_device(device.id)?;
+    assert!(db.get_group_members(cleaning)?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn test_migrations() -> Result<(), ICTError> {
+    let path = std::env::temp_dir().join(format!("ict_migrations_{}.db", Uuid::new_v4()));
+    let path = path.to_str().unwrap();
+
+    // a database created before migrations existed, with duplicated and orphaned relays
+    let private_key = RsaPrivateKey::new(&mut OsRng, 2048).expect("failed to generate a key");
+    let device_id = Uuid::new_v4();
+    {
+        let conn = rusqlite::Connection::open(path)?;
+        conn.execute_batch(
+            "PRAGMA foreign_keys = OFF;
+             CREATE TABLE registered_devices (id BLOB PRIMARY KEY, wrapped_pk BLOB NOT NULL, totp_secret BLOB NOT NULL, authorized INTEGER NOT NULL);
+             CREATE TABLE relays (device_id BLOB NOT NULL, relay_id INTEGER NOT NULL, FOREIGN KEY(device_id) REFERENCES registered_devices(id));",
+        )?;
+        conn.execute(
+            "INSERT INTO registered_devices VALUES (?1, ?2, ?3, 1)",
+            rusqlite::params![
+                device_id.as_bytes(),
+                RsaPublicKey::from(&private_key).to_public_key_der().unwrap().as_bytes(),
+                Secret::generate_secret().to_bytes().unwrap()
+            ],
+        )?;
+        for relay in [16, 16, 20] {
+            conn.execute("INSERT INTO relays VALUES (?1, ?2)", rusqlite::params![device_id.as_bytes(), relay])?;
+        }
+        conn.execute("INSERT INTO relays VALUES (?1, 5)", rusqlite::params![Uuid::new_v4().as_bytes()])?;
+    }
+
+    let db = Db::open(path)?;
+    assert_eq!(db.schema_version()?, 0);
+    assert_eq!(db.pending_migrations()?.len(), ict_migrations::MIGRATIONS.len());
+    assert_eq!(db.migrate(true)?.len(), ict_migrations::MIGRATIONS.len());
+    assert_eq!(db.schema_version()?, 0);
+    drop(db);
+
+    let db = Db::new(path)?;
+    assert_eq!(db.schema_version()?, ict_migrations::latest_version());
+    assert!(db.pending_migrations()?.is_empty());
+    assert!(db.migrate(false)?.is_empty());
+    assert_eq!(db.get_device(device_id)?.unwrap().status, DeviceStatus::Authorized);
+    assert_eq!(db.get_relays(device_id)?, vec![16, 20]);
+
+    // duplicates are no longer possible and deleting a device takes its relays along
+    db.add_relay(device_id, 20)?;
+    assert_eq!(db.get_relays(device_id)?, vec![16, 20]);
+    assert!(db.add_relay(Uuid::new_v4(), 1).is_err());
+    db.delete_device(device_id)?;
+    assert!(db.get_relays(device_id)?.is_empty());
+
+    drop(db);
+    std::fs::remove_file(path)?;
+    Ok(())
+}
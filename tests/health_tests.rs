@@ -0,0 +1,48 @@
+use chrono::{TimeZone, Utc};
+use ict_server::{
+    ict_config::load_config,
+    ict_db::Db,
+    ict_errors::ICTError,
+    ict_health::{self, HealthStatus},
+};
+use std::time::Instant;
+
+#[test]
+fn test_certificate_expiry() {
+    // the sample certificate of the repository is valid until 2022-07-31
+    let valid = ict_health::certificate(Some("tls"), Utc.with_ymd_and_hms(2022, 1, 1, 0, 0, 0).unwrap());
+    assert!(valid.is_ok_and(|detail| detail.contains("valid until 2022-07-31")));
+    let expiring = ict_health::certificate(Some("tls"), Utc.with_ymd_and_hms(2022, 7, 25, 0, 0, 0).unwrap());
+    assert!(expiring.is_err_and(|detail| detail.contains("in 6 days")));
+    let expired = ict_health::certificate(Some("tls"), Utc::now());
+    assert!(expired.is_err_and(|detail| detail.contains("expired")));
+    assert!(ict_health::certificate(None, Utc::now()).is_ok());
+    assert!(ict_health::certificate(Some("missing"), Utc::now()).is_err());
+}
+
+#[test]
+fn test_ready() -> Result<(), ICTError> {
+    let mut settings = load_config("configs/ict_server.toml")?;
+    settings.web.tls_path = None;
+    let path = std::env::temp_dir().join(format!("ict_health_{}.db", std::process::id()));
+    let path = path.to_string_lossy().to_string();
+    let _ = std::fs::remove_file(&path);
+    let started = Instant::now();
+
+    // a missing database is not created by the check
+    let health = ict_health::ready(Some(&path), &settings, started);
+    assert_eq!(health.status, HealthStatus::Fail);
+    assert_eq!(health.checks["database"].status, HealthStatus::Fail);
+    assert!(!std::path::Path::new(&path).exists());
+
+    Db::new(&path)?;
+    let health = ict_health::ready(Some(&path), &settings, started);
+    assert_eq!(health.checks["database"].status, HealthStatus::Ok);
+    assert_eq!(health.checks["relays"].status, HealthStatus::Ok);
+    assert_eq!(health.checks["certificate"].status, HealthStatus::Ok);
+    assert!(health.checks.contains_key("clock"));
+
+    assert_eq!(ict_health::live(started).status, HealthStatus::Ok);
+    std::fs::remove_file(&path)?;
+    Ok(())
+}
@@ -0,0 +1,112 @@
+mod common;
+
+use chrono::Utc;
+use ict_server::{
+    ict_config::{load_config, InputConfig, Level, Pull, TotpAlgorithm},
+    ict_db::Db,
+    ict_errors::ICTError,
+    ict_inputs::{self, InputMonitor, InputReport},
+    ict_operations::{associate_relay, authorize, describe_client, operate},
+    ict_relays::simulate_input,
+    ict_reload::LiveSettings,
+};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use common::TestClient;
+
+fn input(name: &str, pin: u8, relay: Option<u8>) -> InputConfig {
+    InputConfig { name: name.to_string(), pin, pull: Pull::Up, active: Level::Low, debounce: 50, relay, triggers: Vec::new() }
+}
+
+#[test]
+fn test_debounce() {
+    let monitor = InputMonitor::default();
+    let start = Instant::now();
+    let at = |ms: u64| start + Duration::from_millis(ms);
+    monitor.configure(&[input("front door", 5, Some(16)), input("back door", 6, None)], &[true, false], start);
+    let states = monitor.states();
+    assert_eq!((states[0].active, states[1].active), (false, true));
+
+    // a bounce shorter than the debounce is ignored
+    assert!(monitor.sample(&[false, false], at(10)).is_empty());
+    assert!(monitor.sample(&[true, false], at(30)).is_empty());
+    assert!(monitor.sample(&[false, false], at(40)).is_empty());
+    assert!(monitor.sample(&[false, false], at(80)).is_empty());
+    let since = Utc::now();
+    let changed = monitor.sample(&[false, false], at(90));
+    assert_eq!(changed.len(), 1);
+    assert_eq!((changed[0].name.as_str(), changed[0].active), ("front door", true));
+    assert!(monitor.sample(&[false, false], at(200)).is_empty());
+
+    assert_eq!(
+        monitor.report(&[16, 20], since),
+        [InputReport { name: "front door".to_string(), active: true, activated: true }]
+    );
+    assert!(monitor.report(&[16], Utc::now())[0].active);
+    assert!(!monitor.report(&[16], Utc::now())[0].activated);
+    assert!(monitor.report(&[20], since).is_empty());
+}
+
+#[test]
+fn test_monitored_inputs() -> Result<(), ICTError> {
+    let mut door = input("front door", 5, Some(16));
+    door.debounce = 10;
+    let mut button = input("exit button", 6, None);
+    button.debounce = 10;
+    button.triggers = vec![20, 21];
+    let mut settings = load_config("configs/ict_server.toml")?;
+    settings.pi.close_duration = 50;
+    settings.inputs = vec![door, button.clone()];
+    let path = std::env::temp_dir().join(format!("ict_inputs_{}.db", Uuid::new_v4())).to_string_lossy().to_string();
+    ict_inputs::start(Arc::new(LiveSettings::new("configs/ict_server.toml", settings.clone())), Some(path.clone()))?;
+    assert_eq!(ict_inputs::status(&settings.inputs), Ok("front door inactive, exit button inactive".to_string()));
+
+    let db = Db::new(&path)?;
+    let client = TestClient::register(&db);
+    let id = client.id.clone();
+    authorize(&db, &id)?;
+    associate_relay(&db, &id, &16)?;
+    let (message, signature) = client.signed_message();
+
+    // the reed switch opens while the relay is energized
+    let opener = thread::spawn(|| {
+        thread::sleep(Duration::from_millis(100));
+        simulate_input(5, false);
+    });
+    let status = operate(&db, &id, &message, &signature, TotpAlgorithm::Sha256, &500, None)?;
+    opener.join().unwrap();
+    assert_eq!(status.inputs, [InputReport { name: "front door".to_string(), active: true, activated: true }]);
+
+    let history = describe_client(&db, &id)?.history;
+    let last = history.last().expect("operate audit entry");
+    assert_eq!((last.event.as_str(), last.detail.as_str()), ("operate", "relays 16, input front door active (activated)"));
+
+    // pressing the exit button pulses its relays from the polling thread
+    simulate_input(6, false);
+    let local_triggers = || -> Result<Vec<String>, ICTError> {
+        Ok(db.get_audit(None)?.into_iter().filter(|e| e.event == "local-trigger").map(|e| e.detail).collect())
+    };
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while local_triggers()?.is_empty() && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(20));
+    }
+    assert_eq!(local_triggers()?, ["exit button: relays 20 21"]);
+
+    ict_inputs::set_lockout(&db, true)?;
+    assert!(!ict_inputs::trigger(&db, &button, 1)?);
+    // a restart finds the lockout in the database
+    ict_inputs::monitor().set_locked_out(false);
+    assert!(ict_inputs::restore_lockout(&Db::new(&path)?)?);
+    assert!(ict_inputs::monitor().locked_out());
+    ict_inputs::set_lockout(&db, false)?;
+    assert!(!ict_inputs::restore_lockout(&db)?);
+    assert!(ict_inputs::trigger(&db, &button, 1)?);
+    assert_eq!(local_triggers()?, ["exit button: relays 20 21", "exit button: relays 20 21 locked out", "exit button: relays 20 21"]);
+    let lockouts: Vec<String> = db.get_audit(None)?.into_iter().filter(|e| e.event == "lockout").map(|e| e.detail).collect();
+    assert_eq!(lockouts, ["on", "off"]);
+    let _ = std::fs::remove_file(&path);
+    Ok(())
+}
@@ -0,0 +1,36 @@
+// The environment is shared by the tests of a process, this one gets a process of its own
+use ict_server::ict_config::load_config;
+use log::LevelFilter;
+
+const BASE: &str = r#"
+[database]
+path = "DB_PATH"
+
+[totp]
+sha = "sha256"
+
+[logs]
+level = "info"
+
+[pi]
+close_duration = 1000
+"#;
+
+#[test]
+fn test_layered_sources() {
+    let dir = std::env::temp_dir().join(format!("ict_config_layered_{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("conf.d")).expect("failed to create the test directory");
+    std::fs::write(dir.join("conf.d/20-host.toml"), "[logs]\nlevel = \"warn\"\n[pi]\nclose_duration = 300\n").unwrap();
+    std::fs::write(dir.join("conf.d/10-site.toml"), "[logs]\nlevel = \"error\"\n").unwrap();
+    std::fs::write(dir.join("conf.d/notes.txt"), "not a config").unwrap();
+    std::fs::write(dir.join("ict_server.toml"), BASE.replace("DB_PATH", &dir.join("ict.db").to_string_lossy())).unwrap();
+    std::env::set_var("ICT_PI__CLOSE_DURATION", "250");
+    std::env::set_var("ICT_BUNDLE_PASSPHRASE", "not a setting");
+
+    let settings = load_config(&dir.join("ict_server.toml").to_string_lossy()).expect("valid configuration");
+    assert_eq!(settings.logs.level.filter(), LevelFilter::Warn);
+    assert_eq!(settings.pi.close_duration, 250);
+    assert_eq!(settings.sources["pi.close_duration"], "ICT_PI__CLOSE_DURATION");
+    assert!(settings.sources["logs.level"].ends_with("20-host.toml"));
+    assert!(settings.sources["totp.sha"].ends_with("ict_server.toml"));
+}
@@ -0,0 +1,32 @@
+use ict_server::ict_logging::RotatingFile;
+use std::io::Write;
+use std::time::Duration;
+
+#[test]
+fn test_rotation() -> std::io::Result<()> {
+    let dir = std::env::temp_dir().join(format!("ict_logging_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir)?;
+    let path = dir.join("ict.log");
+    let line = [b'x'; 99].iter().chain(b"\n").copied().collect::<Vec<u8>>();
+
+    // 250 bytes hold two lines, the third one goes to a new file
+    let mut file = RotatingFile::open(&path, 250, Duration::from_secs(3600), 2)?;
+    for _ in 0..7 {
+        file.write_all(&line)?;
+    }
+    file.flush()?;
+    let size = |name: &str| std::fs::metadata(dir.join(name)).map(|m| m.len()).ok();
+    assert_eq!(size("ict.log"), Some(100));
+    assert_eq!(size("ict.log.1"), Some(200));
+    assert_eq!(size("ict.log.2"), Some(200));
+    assert_eq!(size("ict.log.3"), None);
+
+    // an old file is rotated on the next write whatever its size
+    let mut file = RotatingFile::open(&path, 250, Duration::ZERO, 2)?;
+    file.write_all(&line)?;
+    assert_eq!(size("ict.log"), Some(100));
+    assert_eq!(size("ict.log.1"), Some(100));
+
+    std::fs::remove_dir_all(&dir)
+}
@@ -0,0 +1,67 @@
+use ict_server::{
+    ict_db::{Db, Device, DeviceStatus},
+    ict_errors::ICTError,
+    ict_metrics::{metrics, Metrics},
+    ict_relays::RelayController,
+};
+use rand::rngs::OsRng;
+use rsa::{RsaPrivateKey, RsaPublicKey};
+use std::thread;
+use std::time::Duration;
+use totp_rs::Secret;
+use uuid::Uuid;
+
+#[test]
+fn test_render() -> Result<(), ICTError> {
+    let db = Db::new_test_db()?;
+    let key = RsaPrivateKey::new(&mut OsRng, 2048).expect("failed to generate a key");
+    db.add_device(&Device::new(Uuid::new_v4(), RsaPublicKey::from(&key), Secret::generate_secret()))?;
+    let mut authorized = Device::new(Uuid::new_v4(), RsaPublicKey::from(&key), Secret::generate_secret());
+    authorized.status = DeviceStatus::Authorized;
+    db.add_device(&authorized)?;
+
+    let metrics = Metrics::default();
+    metrics.record_request("operate", 200, Duration::from_millis(30));
+    metrics.record_request("operate", 429, Duration::from_millis(3));
+    metrics.record_operate(Ok("success"));
+    metrics.record_operate(Err(&ICTError::QuotaExceeded("3 per hour".to_string())));
+    metrics.record_operate(Err(&ICTError::Sqlite(rusqlite::Error::InvalidQuery)));
+    metrics.record_actuation(16, Duration::from_millis(1500));
+    metrics.record_actuation(16, Duration::from_millis(500));
+
+    let text = metrics.render(&db);
+    for line in [
+        "# TYPE ict_http_requests_total counter",
+        "ict_http_requests_total{route=\"operate\",status=\"429\"} 1",
+        "ict_http_request_duration_seconds_bucket{route=\"operate\",le=\"0.005\"} 1",
+        "ict_http_request_duration_seconds_bucket{route=\"operate\",le=\"0.05\"} 2",
+        "ict_http_request_duration_seconds_count{route=\"operate\"} 2",
+        "ict_operate_total{outcome=\"success\"} 1",
+        "ict_operate_total{outcome=\"quota_exceeded\"} 1",
+        "ict_operate_total{outcome=\"database\"} 1",
+        "ict_relay_actuations_total{relay=\"16\"} 2",
+        "ict_relay_on_seconds_total{relay=\"16\"} 2",
+        "ict_pending_registrations 1",
+        "ict_quota_exceeded_total 1",
+        "ict_db_errors_total 1",
+    ] {
+        assert!(text.lines().any(|l| l == line), "missing {} in\n{}", line, text);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_overlapping_actuations() -> Result<(), ICTError> {
+    // the second pulse finds relay 40 already energized and does not count it
+    let controller = RelayController::new(Vec::new(), Vec::new());
+    thread::scope(|scope| {
+        let first = scope.spawn(|| controller.pulse(&[40], 300, "first"));
+        thread::sleep(Duration::from_millis(100));
+        controller.pulse(&[40, 41], 50, "second")?;
+        first.join().unwrap()
+    })?;
+    let text = metrics().render(&Db::new_test_db()?);
+    assert!(text.lines().any(|l| l == "ict_relay_actuations_total{relay=\"40\"} 1"), "{}", text);
+    assert!(text.lines().any(|l| l == "ict_relay_actuations_total{relay=\"41\"} 1"), "{}", text);
+    Ok(())
+}
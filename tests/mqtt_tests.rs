@@ -0,0 +1,78 @@
+mod common;
+
+use ict_server::{
+    ict_config::{load_config, EventKind, Mqtt, RelayConfig, TotpAlgorithm},
+    ict_db::Db,
+    ict_errors::ICTError,
+    ict_mqtt::{self, discovery, handle_command, CommandResult, OperateCommand, Publisher},
+    ict_operations::{associate_relay, authorize},
+};
+use std::sync::{Arc, Mutex};
+
+use common::TestClient;
+
+#[test]
+fn test_discovery() {
+    let mut mqtt = Mqtt { discovery_prefix: Some("homeassistant".to_string()), ..Mqtt::default() };
+    let mut relay = RelayConfig::new(16);
+    assert_eq!(discovery(&mqtt, &relay), None);
+
+    relay.name = Some("server room".to_string());
+    let (topic, config) = discovery(&mqtt, &relay).expect("named relay");
+    assert_eq!(topic, "homeassistant/binary_sensor/ict_server_relay_16/config");
+    assert_eq!(config["name"], "server room");
+    assert_eq!(config["state_topic"], "ict/relay/16/state");
+    assert_eq!(config["availability_topic"], "ict/status");
+
+    mqtt.discovery_prefix = None;
+    assert_eq!(discovery(&mqtt, &relay), None);
+}
+
+#[test]
+fn test_commands() -> Result<(), ICTError> {
+    // (topic, retained, payload) of each message published
+    let published: Arc<Mutex<Vec<(String, bool, String)>>> = Arc::new(Mutex::new(Vec::new()));
+    let log = published.clone();
+    let mqtt = Mqtt { host: Some("localhost".to_string()), events: vec![EventKind::Operate], ..Mqtt::default() };
+    ict_mqtt::install(Publisher::new(
+        &mqtt,
+        Box::new(move |topic, retain, payload| {
+            log.lock().unwrap().push((topic.to_string(), retain, String::from_utf8(payload).unwrap()));
+        }),
+    ))?;
+    assert!(ict_mqtt::install(Publisher::new(&mqtt, Box::new(|_, _, _| {}))).is_err());
+
+    let mut settings = load_config("configs/ict_server.toml")?;
+    settings.totp.sha = TotpAlgorithm::Sha256;
+    settings.pi.close_duration = 1;
+
+    let db = Db::new_test_db()?;
+    let client = TestClient::register(&db);
+    let id = client.id.clone();
+    authorize(&db, &id)?;
+    associate_relay(&db, &id, &9)?;
+    let (totp_message, signature) = client.signed_message();
+
+    assert_eq!(handle_command(&db, b"{\"id\": 1}", &settings).outcome, "invalid_json");
+    let forged = OperateCommand { id: id.clone(), totp_message: totp_message.clone(), signature: "bm90IHNpZ25lZA==".to_string() };
+    assert_eq!(handle_command(&db, &serde_json::to_vec(&forged).unwrap(), &settings).outcome, "refused");
+
+    let command = OperateCommand { id: id.clone(), totp_message, signature };
+    assert_eq!(
+        handle_command(&db, &serde_json::to_vec(&command).unwrap(), &settings),
+        CommandResult { id: Some(id.clone()), outcome: "success".to_string(), operated: vec![9], pending: Vec::new(), inputs: Vec::new() }
+    );
+    // the same signed message seen again on the broker is not operated twice
+    let replayed = handle_command(&db, &serde_json::to_vec(&command).unwrap(), &settings);
+    assert_eq!((replayed.outcome.as_str(), replayed.operated.len()), ("refused", 0));
+
+    // the register and status_change events are not in the events of the settings
+    let published = published.lock().unwrap().clone();
+    let topics: Vec<(&str, bool, &str)> = published.iter().map(|(topic, retain, payload)| (topic.as_str(), *retain, payload.as_str())).collect();
+    assert_eq!(&topics[..2], [("ict/relay/9/state", true, "ON"), ("ict/relay/9/state", true, "OFF")]);
+    assert_eq!(topics.len(), 3);
+    assert_eq!(topics[2].0, "ict/events/operate");
+    let event: serde_json::Value = serde_json::from_str(topics[2].2).unwrap();
+    assert_eq!((event["device"].as_str(), &event["data"]["relays"]), (Some(id.as_str()), &serde_json::json!([9])));
+    Ok(())
+}
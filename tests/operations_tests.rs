@@ -1,9 +1,16 @@
+mod common;
+
 use base64::{engine::general_purpose, Engine as _};
 use ict_server::{
-    ict_db::Db,
+    ict_db::{Db, DeviceStatus},
     ict_errors::ICTError,
-    ict_operations::{associate_relay, authorize, operate, register},
-    ict_operations::OperationMessage,
+    ict_operations::{associate_relay, authorize, authorize_between, operate, operate_with, register, revoke, set_client_info, set_quota, suspend},
+    ict_operations::{add_group, add_group_member, grant_group_relay, list_groups, remove_group_member},
+    ict_operations::{add_schedule, list_schedules, remove_schedule},
+    ict_operations::{describe_client, list_clients, review_client, ClientFilter, ReviewDecision},
+    ict_operations::{OperateStatus, OperationMessage, PendingRelay, MAX_DISPLAY_NAME},
+    ict_config::{Interlock, InterlockPolicy, RelayConfig, TotpAlgorithm},
+    ict_relays::{self, RelayController},
 };
 use rand::rngs::OsRng;
 use rsa::{
@@ -14,7 +21,8 @@ use sha2::Sha256;
 use totp_rs::{Secret, TOTP};
 use uuid::Uuid;
 use rsa::signature::Verifier;
-use rsa::pkcs1v15::Signature;
+
+use common::TestClient;
 
 #[test]
 fn test_happy_path() -> Result<(), ICTError> {
@@ -37,7 +45,7 @@ fn test_happy_path() -> Result<(), ICTError> {
 
     //2 register client and collect secret
     let secret_string =
-        register(&db, &id.to_string(), &pem_public_key).expect("failed to register");
+        register(&db, &id.to_string(), &pem_public_key, None).expect("failed to register");
     println!("encrypted secret generated (encoded) {}", &secret_string);
 
     let encrypted_secret = general_purpose::STANDARD.decode(secret_string).unwrap();
@@ -70,16 +78,16 @@ fn test_happy_path() -> Result<(), ICTError> {
     let signature = signing_key.sign(message.as_bytes());
 
     let verifying_key = VerifyingKey::<Sha256>::new(public_key);
-    let _res = verifying_key.verify(&message.as_bytes(), &Signature::try_from(signature.clone()).unwrap());
+    let _res = verifying_key.verify(message.as_bytes(), &signature);
 
     // Step 4: Print base64-encoded signature
-    let signature_base64 = general_purpose::STANDARD.encode(&signature.to_bytes());
+    let signature_base64 = general_purpose::STANDARD.encode(signature.to_bytes());
     println!("Signature (base64): {}", signature_base64);
 
     //5 operate relays, should fail
-    match operate(&db, &id.to_string(), &message, &signature_base64,"sha256".to_string(),&1) {
+    match operate(&db, &id.to_string(), &message, &signature_base64,TotpAlgorithm::Sha256,&1,None) {
         Ok(result) => {
-            assert!(!result);
+            assert!(result.operated.is_empty());
         }
         Err(e) => {
             println!("expected err {}", e);
@@ -102,7 +110,339 @@ fn test_happy_path() -> Result<(), ICTError> {
         .check_current(&token)
         .expect("totp internal check failed")); //internal check
     //9. actual successful call to operate!! leave relays close for 10 seconds to test electronigs
-    assert!(operate(&db, &id.to_string(), &message, &signature_base64,"sha256".to_string(), &10000).expect("failed to operate"));
+    assert!(operate(&db, &id.to_string(), &message, &signature_base64,TotpAlgorithm::Sha256, &10000, None).expect("failed to operate").is_complete());
+
+    Ok(())
+}
+
+fn signed_operate(db: &Db, client: &TestClient) -> Result<OperateStatus, ICTError> {
+    signed_operate_with(ict_relays::controller(), db, client)
+}
+
+fn signed_operate_with(relays: &RelayController, db: &Db, client: &TestClient) -> Result<OperateStatus, ICTError> {
+    let (message, signature) = client.signed_message();
+    operate_with(relays, db, &client.id, &message, &signature, TotpAlgorithm::Sha256, &1, Some("192.0.2.1"))
+}
+
+#[test]
+fn test_quota() -> Result<(), ICTError> {
+    let db = Db::new_test_db()?;
+    let client = TestClient::register(&db);
+    associate_relay(&db, &client.id, &16)?;
+    authorize(&db, &client.id)?;
+
+    // a two openings pass
+    set_quota(&db, &client.id, None, None, Some(2), None)?;
+    assert!(signed_operate(&db, &client)?.is_complete());
+    assert!(signed_operate(&db, &client)?.is_complete());
+    assert!(matches!(signed_operate(&db, &client), Err(ICTError::QuotaExceeded(_))));
+
+    // setting a new quota restarts the total count, the cooldown still applies
+    set_quota(&db, &client.id, Some(10), None, None, Some(3600))?;
+    assert!(matches!(signed_operate(&db, &client), Err(ICTError::QuotaExceeded(_))));
+
+    set_quota(&db, &client.id, Some(2), None, None, None)?;
+    assert!(matches!(signed_operate(&db, &client), Err(ICTError::QuotaExceeded(_))));
+
+    assert!(set_quota(&db, &client.id, None, None, None, None).is_err());
+
+    // operations no quota looks at any more are pruned
+    let uuid = Uuid::parse_str(&client.id)?;
+    db.record_operation(uuid, chrono::Utc::now() - chrono::Duration::days(3))?;
+    db.record_operation(uuid, chrono::Utc::now() - chrono::Duration::days(2))?;
+    assert_eq!(db.prune_operations(chrono::Utc::now() - chrono::Duration::days(1))?, 2);
+    assert_eq!(db.count_operations_since(uuid, chrono::DateTime::UNIX_EPOCH)?, 2);
+    Ok(())
+}
+
+#[test]
+fn test_concurrent_quota() -> Result<(), ICTError> {
+    let path = std::env::temp_dir().join(format!("ict_quota_{}.db", Uuid::new_v4())).to_string_lossy().to_string();
+    let db = Db::new(&path)?;
+    let client = TestClient::register(&db);
+    authorize(&db, &client.id)?;
+    set_quota(&db, &client.id, Some(1), None, None, None)?;
+
+    // each request has a connection of its own, only one of them gets the operation
+    let results: Vec<Result<OperateStatus, ICTError>> = std::thread::scope(|scope| {
+        let requests: Vec<_> = (0..4)
+            .map(|_| scope.spawn(|| signed_operate(&Db::new(&path)?, &client)))
+            .collect();
+        requests.into_iter().map(|request| request.join().unwrap()).collect()
+    });
+    assert_eq!(results.iter().filter(|result| result.is_ok()).count(), 1);
+    assert!(results.iter().all(|result| matches!(result, Ok(_) | Err(ICTError::QuotaExceeded(_)))));
+    assert_eq!(describe_client(&db, &client.id)?.summary.operation_count, 1);
+    let _ = std::fs::remove_file(&path);
+    Ok(())
+}
+
+#[test]
+fn test_quorum() -> Result<(), ICTError> {
+    let db = Db::new_test_db()?;
+    let mut server_room = RelayConfig::new(30);
+    server_room.quorum = 2;
+    // the other tests of this binary use the process wide controller
+    let controller = RelayController::new(
+        vec![server_room],
+        vec![Interlock { name: "hall".to_string(), relays: vec![30, 32], policy: InterlockPolicy::Reject, dead_time: 0, queue_timeout: 0 }],
+    );
+
+    let first = TestClient::register(&db);
+    let second = TestClient::register(&db);
+    for client in [&first, &second] {
+        associate_relay(&db, &client.id, &30)?;
+        associate_relay(&db, &client.id, &31)?;
+        authorize(&db, &client.id)?;
+    }
+
+    // the relay without quorum operates right away, the other one waits for a second device
+    let status = signed_operate_with(&controller, &db, &first)?;
+    assert_eq!(status.operated, vec![31]);
+    assert_eq!(status.pending.len(), 1);
+    assert_eq!((status.pending[0].relay, status.pending[0].approvals, status.pending[0].quorum), (30, 1, 2));
+    assert!(status.pending[0].expires_in > 0 && status.pending[0].expires_in <= 60);
+
+    // the same device approving again does not count twice
+    assert!(matches!(signed_operate_with(&controller, &db, &first)?.pending.as_slice(), [PendingRelay { approvals: 1, .. }]));
+
+    let status = signed_operate_with(&controller, &db, &second)?;
+    assert!(status.is_complete());
+    assert_eq!(status.operated, vec![30, 31]);
+
+    // approvals are consumed by the actuation
+    assert!(!signed_operate_with(&controller, &db, &second)?.is_complete());
+
+    // they are kept when the interlock refuses the pulse
+    std::thread::scope(|scope| {
+        let hall = scope.spawn(|| controller.pulse(&[32], 500, "hall"));
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        assert!(matches!(signed_operate_with(&controller, &db, &first), Err(ICTError::Interlock(_))));
+        hall.join().unwrap()
+    })?;
+    assert_eq!(signed_operate_with(&controller, &db, &first)?.operated, vec![30, 31]);
+
+    // an approval alone does not use the quota, nor counts once its device is suspended
+    let third = TestClient::register(&db);
+    associate_relay(&db, &third.id, &30)?;
+    authorize(&db, &third.id)?;
+    let third_id = Uuid::parse_str(&third.id)?;
+    assert!(!signed_operate_with(&controller, &db, &third)?.is_complete());
+    assert_eq!(db.get_device(third_id)?.unwrap().operation_count, 0);
+    assert_eq!(db.count_operations_since(third_id, chrono::Utc::now() - chrono::Duration::hours(1))?, 0);
+    suspend(&db, &third.id, None)?;
+    assert!(matches!(signed_operate_with(&controller, &db, &first)?.pending.as_slice(), [PendingRelay { approvals: 1, .. }]));
+    Ok(())
+}
+
+#[test]
+fn test_group_grants() -> Result<(), ICTError> {
+    let db = Db::new_test_db()?;
+    let client = TestClient::register(&db);
+    authorize(&db, &client.id)?;
+    add_group(&db, "staff")?;
+    grant_group_relay(&db, "staff", &22)?;
+
+    // the relay comes from the group only while the client is a member
+    assert!(signed_operate(&db, &client)?.operated.is_empty());
+    add_group_member(&db, "staff", &client.id)?;
+    assert_eq!(signed_operate(&db, &client)?.operated, vec![22]);
+    let groups = list_groups(&db)?;
+    assert_eq!((groups[0].name.as_str(), &groups[0].relays, &groups[0].members), ("staff", &vec![22], &vec![Uuid::parse_str(&client.id)?]));
+
+    remove_group_member(&db, "staff", &client.id)?;
+    assert!(signed_operate(&db, &client)?.operated.is_empty());
+    assert!(list_groups(&db)?[0].members.is_empty());
+    Ok(())
+}
+
+#[test]
+fn test_schedule() -> Result<(), ICTError> {
+    let db = Db::new_test_db()?;
+    let client = TestClient::register(&db);
+    associate_relay(&db, &client.id, &16)?;
+    authorize(&db, &client.id)?;
+
+    // an hour long window every day that starts in two hours never includes now
+    let now = chrono::Utc::now();
+    let from = (now + chrono::Duration::hours(2)).format("%H:%M").to_string();
+    let until = (now + chrono::Duration::hours(3)).format("%H:%M").to_string();
+    let id = add_schedule(&db, &client.id, None, "mon-sun", &from, &until, "UTC")?;
+    let schedules = list_schedules(&db, Some(&client.id))?;
+    assert_eq!((schedules.len(), schedules[0].id, schedules[0].from.as_str()), (1, id, from.as_str()));
+    assert!(matches!(signed_operate(&db, &client), Err(ICTError::OutsideSchedule(_))));
+    assert_eq!(describe_client(&db, &client.id)?.summary.operation_count, 0);
+
+    remove_schedule(&db, id)?;
+    assert!(signed_operate(&db, &client)?.is_complete());
+    Ok(())
+}
+
+#[test]
+fn test_lifecycle() -> Result<(), ICTError> {
+    let db = Db::new_test_db()?;
+    let client = TestClient::register(&db);
+    let id = Uuid::parse_str(&client.id)?;
+    associate_relay(&db, &client.id, &16)?;
+    assert_eq!(db.get_device(id)?.unwrap().status, DeviceStatus::Pending);
+    assert!(signed_operate(&db, &client).is_err());
+    assert!(matches!(suspend(&db, &client.id, None), Err(ICTError::StatusChange(_))));
+
+    authorize_between(&db, &client.id, None, None, Some("new hire"))?;
+    assert!(signed_operate(&db, &client)?.is_complete());
+
+    suspend(&db, &client.id, Some("lost phone"))?;
+    let device = db.get_device(id)?.unwrap();
+    assert_eq!(device.status, DeviceStatus::Suspended);
+    assert_eq!(device.status_reason.as_deref(), Some("lost phone"));
+    assert!(signed_operate(&db, &client).is_err());
+
+    // revoked is final, only a new registration brings the client back
+    authorize(&db, &client.id)?;
+    revoke(&db, &client.id, Some("left the company"))?;
+    assert!(matches!(authorize(&db, &client.id), Err(ICTError::StatusChange(_))));
+    assert!(matches!(suspend(&db, &client.id, None), Err(ICTError::StatusChange(_))));
+    assert!(signed_operate(&db, &client).is_err());
+
+    let details: Vec<String> = db.get_audit(Some(id))?.into_iter().map(|e| e.detail).collect();
+    assert_eq!(
+        details,
+        vec![
+            "pending",
+            "pending -> authorized: new hire",
+            "relays 16",
+            "authorized -> suspended: lost phone",
+            "suspended -> authorized",
+            "authorized -> revoked: left the company",
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn test_expiry() -> Result<(), ICTError> {
+    let db = Db::new_test_db()?;
+    let client = TestClient::register(&db);
+    let id = Uuid::parse_str(&client.id)?;
+    associate_relay(&db, &client.id, &16)?;
+    authorize(&db, &client.id)?;
+
+    // the window ended since the authorization, the expiry is recorded by operate
+    let ended = chrono::Utc::now() - chrono::Duration::minutes(1);
+    db.set_validity_on_device(id, None, Some(ended))?;
+    assert!(signed_operate(&db, &client).is_err());
+    let device = db.get_device(id)?.unwrap();
+    assert_eq!(device.status, DeviceStatus::Expired);
+    assert_eq!(device.status_changed_at.unwrap().timestamp(), ended.timestamp());
+
+    // expired clients can be authorized again
+    authorize(&db, &client.id)?;
+    assert!(signed_operate(&db, &client)?.is_complete());
+    Ok(())
+}
+
+#[test]
+fn test_client_info() -> Result<(), ICTError> {
+    let db = Db::new_test_db()?;
+    let client = TestClient::register(&db);
+    let id = Uuid::parse_str(&client.id)?;
+    let device = db.get_device(id)?.unwrap();
+    assert_eq!(device.display_name.as_deref(), Some("test client"));
+    assert!(device.created_at.is_some());
+    assert!(device.last_seen_at.is_none());
+    assert_eq!(device.operation_count, 0);
+
+    set_client_info(&db, &client.id, None, Some("alice"), Some("front door tablet"))?;
+    set_client_info(&db, &client.id, Some(""), None, None)?;
+    let device = db.get_device(id)?.unwrap();
+    assert_eq!(device.display_name, None);
+    assert_eq!(device.owner.as_deref(), Some("alice"));
+    assert_eq!(device.notes.as_deref(), Some("front door tablet"));
+
+    // operating updates the activity
+    associate_relay(&db, &client.id, &16)?;
+    authorize(&db, &client.id)?;
+    signed_operate(&db, &client)?;
+    signed_operate(&db, &client)?;
+    let device = db.get_device(id)?.unwrap();
+    assert!(device.last_seen_at.is_some());
+    assert_eq!(device.last_remote_addr.as_deref(), Some("192.0.2.1"));
+    assert_eq!(device.operation_count, 2);
+
+    // the name given at registration is cleaned up and capped
+    let other = Uuid::new_v4();
+    let private_key = RsaPrivateKey::new(&mut OsRng, 2048).expect("failed to generate a key");
+    let pem = RsaPublicKey::to_public_key_pem(&RsaPublicKey::from(&private_key), LineEnding::LF).unwrap();
+    register(&db, &other.to_string(), &pem, Some(&format!(" \u{1b}[1;31mlobby\u{1b}[0m\n{}", "x".repeat(100))))?;
+    let name = db.get_device(other)?.unwrap().display_name.unwrap();
+    assert_eq!(name.chars().count(), MAX_DISPLAY_NAME);
+    assert_eq!(name, format!("lobby{}", "x".repeat(MAX_DISPLAY_NAME - 5)));
+
+    // escape sequences go as a whole, not only their ESC
+    let gate = TestClient::register_named(&db, Some("\u{1b}]0;owned\u{7}gate \u{1b}(Bside\u{9b}2J"));
+    assert_eq!(db.get_device(Uuid::parse_str(&gate.id)?)?.unwrap().display_name.as_deref(), Some("gate side"));
+    Ok(())
+}
+
+#[test]
+fn test_list_and_describe() -> Result<(), ICTError> {
+    let db = Db::new_test_db()?;
+    let first = TestClient::register(&db);
+    let second = TestClient::register(&db);
+    associate_relay(&db, &first.id, &16)?;
+    authorize(&db, &first.id)?;
+    set_quota(&db, &first.id, Some(5), None, None, None)?;
+
+    assert_eq!(list_clients(&db, &ClientFilter::default())?.len(), 2);
+    let pending = list_clients(&db, &ClientFilter { status: Some(DeviceStatus::Pending), relay: None })?;
+    assert_eq!(pending.len(), 1);
+    assert_eq!(pending[0].id.to_string(), second.id);
+    let with_relay = list_clients(&db, &ClientFilter { status: None, relay: Some(16) })?;
+    assert_eq!(with_relay.len(), 1);
+    assert_eq!(with_relay[0].relays, vec![16]);
+    assert!(list_clients(&db, &ClientFilter { status: Some(DeviceStatus::Pending), relay: Some(16) })?.is_empty());
+
+    let details = describe_client(&db, &first.id)?;
+    assert_eq!(details.summary.status, DeviceStatus::Authorized);
+    assert_eq!(details.grants.len(), 1);
+    assert_eq!(details.quota.as_ref().unwrap().max_per_hour, Some(5));
+    assert_eq!(details.history.len(), 2);
+    let json = serde_json::to_value(&details).unwrap();
+    assert_eq!(json["status"], "authorized");
+    assert_eq!(json["display_name"], "test client");
+
+    assert!(matches!(describe_client(&db, &Uuid::new_v4().to_string()), Err(ICTError::NotFound(_))));
+    Ok(())
+}
+
+#[test]
+fn test_review() -> Result<(), ICTError> {
+    let db = Db::new_test_db()?;
+    let approved = TestClient::register(&db);
+    let rejected = TestClient::register(&db);
+    let skipped = TestClient::register(&db);
+
+    review_client(&db, &approved.id, &ReviewDecision::Approve(vec![16, 20]))?;
+    review_client(&db, &rejected.id, &ReviewDecision::Reject)?;
+    review_client(&db, &skipped.id, &ReviewDecision::Skip)?;
+
+    let details = describe_client(&db, &approved.id)?;
+    assert_eq!(details.summary.status, DeviceStatus::Authorized);
+    assert_eq!(details.summary.relays, vec![16, 20]);
+    assert!(details.fingerprint.starts_with("SHA256:"));
+    assert!(signed_operate(&db, &approved)?.is_complete());
+    assert!(db.get_device(Uuid::parse_str(&rejected.id)?)?.is_none());
+    assert_eq!(describe_client(&db, &skipped.id)?.summary.status, DeviceStatus::Pending);
+
+    // decisions are kept in the audit trail, even for the deleted client
+    let events = |id: &str| -> Result<Vec<String>, ICTError> {
+        Ok(db.get_audit(Some(Uuid::parse_str(id)?))?.into_iter().filter(|e| e.event == "review").map(|e| e.detail).collect())
+    };
+    assert_eq!(events(&approved.id)?, vec!["approved with relays [16, 20]"]);
+    assert_eq!(events(&rejected.id)?, vec!["rejected and deleted"]);
+    assert_eq!(events(&skipped.id)?, vec!["skipped"]);
 
+    // only pending clients are reviewed
+    assert!(matches!(review_client(&db, &approved.id, &ReviewDecision::Reject), Err(ICTError::StatusChange(_))));
     Ok(())
 }
@@ -0,0 +1,131 @@
+use ict_server::{
+    ict_config::{Interlock, InterlockPolicy, RelayConfig},
+    ict_errors::ICTError,
+    ict_relays::{self, RelayController},
+};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+fn gate(policy: InterlockPolicy, dead_time: u64) -> Arc<RelayController> {
+    Arc::new(RelayController::new(Vec::new(), vec![Interlock {
+        name: "gate".to_string(),
+        relays: vec![20, 21],
+        policy,
+        dead_time,
+        queue_timeout: 2000,
+    }]))
+}
+
+#[test]
+fn test_interlock_reject() -> Result<(), ICTError> {
+    let controller = gate(InterlockPolicy::Reject, 0);
+
+    // both relays of a group in the same request can never be honored
+    assert!(matches!(controller.pulse(&[20, 21], 10, "test"), Err(ICTError::Interlock(_))));
+
+    let c = controller.clone();
+    let open = thread::spawn(move || c.pulse(&[20], 500, "open"));
+    thread::sleep(Duration::from_millis(100));
+    assert!(matches!(controller.pulse(&[21], 10, "close"), Err(ICTError::Interlock(_))));
+    // relays outside of the group are not affected
+    controller.pulse(&[16], 10, "other")?;
+    open.join().unwrap()?;
+
+    controller.pulse(&[21], 10, "close")?;
+    Ok(())
+}
+
+#[test]
+fn test_interlock_queue_and_dead_time() -> Result<(), ICTError> {
+    let controller = gate(InterlockPolicy::Queue, 300);
+
+    let start = Instant::now();
+    let c = controller.clone();
+    let open = thread::spawn(move || c.pulse(&[20], 500, "open"));
+    thread::sleep(Duration::from_millis(100));
+    controller.pulse(&[21], 10, "close")?;
+    open.join().unwrap()?;
+
+    // the close pulse had to wait for the open pulse and the dead time
+    assert!(start.elapsed() >= Duration::from_millis(800));
+    Ok(())
+}
+
+#[test]
+fn test_cancel_pulse() -> Result<(), ICTError> {
+    let controller = Arc::new(RelayController::new(Vec::new(), Vec::new()));
+    assert!(!controller.cancel("idle"));
+
+    let c = controller.clone();
+    let started = Instant::now();
+    let pulse = thread::spawn(move || c.pulse(&[16], 5000, "revoked"));
+    thread::sleep(Duration::from_millis(100));
+    assert!(controller.cancel("revoked"));
+    pulse.join().unwrap()?;
+    assert!(started.elapsed() < Duration::from_millis(2000));
+
+    // the cancellation does not outlive the pulse
+    let started = Instant::now();
+    controller.pulse(&[16], 200, "revoked")?;
+    assert!(started.elapsed() >= Duration::from_millis(200));
+    Ok(())
+}
+
+#[test]
+fn test_overlapping_pulses() -> Result<(), ICTError> {
+    let controller = Arc::new(RelayController::new(Vec::new(), Vec::new()));
+    let c = controller.clone();
+    let long = thread::spawn(move || c.pulse(&[16], 600, "long"));
+    thread::sleep(Duration::from_millis(100));
+    controller.pulse(&[16], 100, "short")?;
+    // the short pulse ending does not re-open the relay the long one still holds
+    assert!(controller.is_energized(16));
+    long.join().unwrap()?;
+    assert!(!controller.is_energized(16));
+    Ok(())
+}
+
+#[test]
+fn test_watchdog_and_reset() -> Result<(), ICTError> {
+    let mut relay = RelayConfig::new(16);
+    relay.max_on = 200;
+    let controller = Arc::new(RelayController::new(vec![relay, RelayConfig::new(20)], Vec::new()));
+
+    let c = controller.clone();
+    let pulse = thread::spawn(move || c.pulse(&[16, 21], 1000, "stuck"));
+    thread::sleep(Duration::from_millis(100));
+    assert!(controller.is_energized(16) && controller.is_energized(21));
+    assert!(controller.watch(Instant::now()).is_empty());
+    // relays without a definition get the default max_on
+    assert_eq!(controller.watch(Instant::now() + Duration::from_millis(300)), [16]);
+    assert!(!controller.is_energized(16) && controller.is_energized(21));
+
+    assert_eq!(controller.reset("test"), [21]);
+    assert!(!controller.is_energized(21));
+    assert!(controller.reset("test").is_empty());
+    pulse.join().unwrap()?;
+    Ok(())
+}
+
+#[test]
+fn test_panic_hook() {
+    ict_relays::install_panic_hook();
+    let pulse = thread::spawn(|| ict_relays::controller().pulse(&[16], 1000, "panicking"));
+    thread::sleep(Duration::from_millis(100));
+    assert!(ict_relays::controller().is_energized(16));
+    assert!(thread::spawn(|| panic!("request handler bug")).join().is_err());
+    assert!(!ict_relays::controller().is_energized(16));
+    pulse.join().unwrap().unwrap();
+
+    // the hook never waits on the outputs, a free controller is reset like any other time
+    let controller = Arc::new(RelayController::new(Vec::new(), Vec::new()));
+    let pulsing = controller.clone();
+    let pulse = thread::spawn(move || pulsing.pulse(&[18], 1000, "panicking"));
+    thread::sleep(Duration::from_millis(100));
+    let started = Instant::now();
+    controller.reset_on_panic();
+    assert!(started.elapsed() < Duration::from_millis(500));
+    assert!(!controller.is_energized(18));
+    pulse.join().unwrap().unwrap();
+}
@@ -0,0 +1,96 @@
+use chrono::{TimeZone, Utc};
+use ict_server::{
+    ict_db::{Db, Device},
+    ict_errors::ICTError,
+    ict_schedules::{parse_datetime, Schedule, Weekdays},
+};
+use rand::rngs::OsRng;
+use rsa::{RsaPrivateKey, RsaPublicKey};
+use totp_rs::Secret;
+use uuid::Uuid;
+
+#[test]
+fn test_weekdays() -> Result<(), ICTError> {
+    assert_eq!(Weekdays::parse("mon-fri")?, Weekdays(0b0011111));
+    assert_eq!(Weekdays::parse("sat,sun")?, Weekdays(0b1100000));
+    assert_eq!(Weekdays::parse("Monday,wed-fri")?.to_string(), "mon,wed,thu,fri");
+    assert!(Weekdays::parse("fri-mon").is_err());
+    assert!(Weekdays::parse("someday").is_err());
+    assert!(Weekdays::parse("monkey").is_err());
+    assert!(Weekdays::parse("sat-sunday")?.contains(6));
+    Ok(())
+}
+
+#[test]
+fn test_schedule_window() -> Result<(), ICTError> {
+    let id = Uuid::new_v4();
+    // 2025-06-02 is a monday, Paris is UTC+2 in june
+    let evenings = Schedule::new(id, None, Weekdays::parse("mon-fri")?, "18:00", "21:00", "Europe/Paris")?;
+    assert!(evenings.allows(Utc.with_ymd_and_hms(2025, 6, 2, 16, 0, 0).unwrap()));
+    assert!(evenings.allows(Utc.with_ymd_and_hms(2025, 6, 2, 18, 59, 0).unwrap()));
+    assert!(!evenings.allows(Utc.with_ymd_and_hms(2025, 6, 2, 19, 0, 0).unwrap()));
+    assert!(!evenings.allows(Utc.with_ymd_and_hms(2025, 6, 2, 15, 59, 0).unwrap()));
+    assert!(!evenings.allows(Utc.with_ymd_and_hms(2025, 6, 7, 17, 0, 0).unwrap()));
+
+    // wraps past midnight, friday night belongs to friday
+    let nights = Schedule::new(id, Some(16), Weekdays::parse("fri")?, "22:00", "06:00", "UTC")?;
+    assert!(nights.allows(Utc.with_ymd_and_hms(2025, 6, 6, 23, 0, 0).unwrap()));
+    assert!(nights.allows(Utc.with_ymd_and_hms(2025, 6, 7, 5, 0, 0).unwrap()));
+    assert!(!nights.allows(Utc.with_ymd_and_hms(2025, 6, 6, 5, 0, 0).unwrap()));
+    assert!(!nights.allows(Utc.with_ymd_and_hms(2025, 6, 7, 23, 0, 0).unwrap()));
+
+    // the same start and end is the whole day
+    let mondays = Schedule::new(id, None, Weekdays::parse("mon")?, "00:00", "00:00", "UTC")?;
+    assert!(mondays.allows(Utc.with_ymd_and_hms(2025, 6, 2, 0, 0, 0).unwrap()));
+    assert!(mondays.allows(Utc.with_ymd_and_hms(2025, 6, 2, 23, 59, 0).unwrap()));
+    assert!(!mondays.allows(Utc.with_ymd_and_hms(2025, 6, 3, 0, 0, 0).unwrap()));
+
+    assert!(Schedule::new(id, None, Weekdays(1), "25:00", "06:00", "UTC").is_err());
+    assert!(Schedule::new(id, None, Weekdays(1), "22:00", "06:00", "Mars/Olympus").is_err());
+    Ok(())
+}
+
+#[test]
+fn test_parse_datetime() -> Result<(), ICTError> {
+    assert_eq!(
+        parse_datetime("2025-06-02T18:00:00+02:00")?,
+        Utc.with_ymd_and_hms(2025, 6, 2, 16, 0, 0).unwrap()
+    );
+    // local formats are only checked for consistency since they depend on the host timezone
+    assert_eq!(parse_datetime("2025-06-02 18:00")?, parse_datetime("2025-06-02T18:00:00")?);
+    assert!(parse_datetime("2025-06-02")? < parse_datetime("2025-06-02 00:01")?);
+    assert!(parse_datetime("tomorrow").is_err());
+    Ok(())
+}
+
+#[test]
+fn test_schedules_db() -> Result<(), ICTError> {
+    let db = Db::new_test_db()?;
+    let private_key = RsaPrivateKey::new(&mut OsRng, 2048).expect("failed to generate a key");
+    let device = Device::new(Uuid::new_v4(), RsaPublicKey::from(&private_key), Secret::generate_secret());
+    let other = Device::new(Uuid::new_v4(), RsaPublicKey::from(&private_key), Secret::generate_secret());
+    db.add_device(&device)?;
+    db.add_device(&other)?;
+
+    let schedule = Schedule::new(device.id, Some(16), Weekdays::parse("mon-fri")?, "18:00", "21:00", "Europe/Paris")?;
+    let id = db.add_schedule(&schedule)?;
+    db.add_schedule(&Schedule::new(other.id, None, Weekdays(1), "08:00", "09:00", "UTC")?)?;
+
+    assert_eq!(db.get_schedules(None)?.len(), 2);
+    let loaded = db.get_schedules(Some(device.id))?;
+    assert_eq!(loaded.len(), 1);
+    assert_eq!(loaded[0].id, id);
+    assert_eq!(loaded[0].relay_id, Some(16));
+    assert_eq!(loaded[0].weekdays, schedule.weekdays);
+    assert_eq!(loaded[0].start_as_string(), "18:00");
+    assert_eq!(loaded[0].timezone, chrono_tz::Europe::Paris);
+
+    assert!(db.remove_schedule(id)?);
+    assert!(!db.remove_schedule(id)?);
+    assert!(db.get_schedules(Some(device.id))?.is_empty());
+
+    // schedules go away with their device
+    db.delete_device(other.id)?;
+    assert!(db.get_schedules(None)?.is_empty());
+    Ok(())
+}
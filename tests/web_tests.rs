@@ -0,0 +1,95 @@
+mod common;
+
+use ict_server::{
+    ict_config::{load_config, TotpAlgorithm},
+    ict_db::Db,
+    ict_errors::ICTError,
+    ict_operations::{add_schedule, associate_relay, authorize},
+    ict_relays,
+    ict_reload::LiveSettings,
+    ict_web::start_web_server,
+};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use common::TestClient;
+
+// Body of POST /operate for a new authorized client of relay 16
+fn operate_body(db: &Db) -> Result<serde_json::Value, ICTError> {
+    let client = TestClient::register(db);
+    authorize(db, &client.id)?;
+    associate_relay(db, &client.id, &16)?;
+    Ok(client.operate_body())
+}
+
+#[test]
+fn test_graceful_shutdown() -> Result<(), ICTError> {
+    let path = std::env::temp_dir().join(format!("ict_web_{}.db", Uuid::new_v4())).to_string_lossy().to_string();
+    let db = Db::new(&path)?;
+    let body = operate_body(&db)?;
+    let mut settings = load_config("configs/ict_server.toml")?;
+    settings.totp.sha = TotpAlgorithm::Sha256;
+    settings.pi.close_duration = 500;
+
+    // the pulse of an operate in flight completes before the server stops
+    let live = Arc::new(LiveSettings::new("configs/ict_server.toml", settings.clone()));
+    let web = start_web_server(&0, &db, live)?;
+    let url = format!("http://{}", web.addr());
+    assert_eq!(ureq::get(&format!("{}/health/live", url)).call().map(|r| r.status()).ok(), Some(200));
+    let (operate_url, operate) = (format!("{}/operate", url), body.clone());
+    let request = thread::spawn(move || ureq::post(&operate_url).set("Content-Type", "application/json").send_string(&operate.to_string()).map(|r| r.status()).map_err(|e| e.to_string()));
+    thread::sleep(Duration::from_millis(200));
+    let started = Instant::now();
+    assert!(web.shutdown(Duration::from_secs(5)));
+    assert!(started.elapsed() >= Duration::from_millis(200));
+    assert_eq!(request.join().unwrap(), Ok(200));
+    assert!(ureq::get(&format!("{}/health/live", url)).call().is_err());
+
+    // past the deadline the pulse is cut short, the request is still answered
+    settings.pi.close_duration = 10_000;
+    let live = Arc::new(LiveSettings::new("configs/ict_server.toml", settings));
+    let web = start_web_server(&0, &db, live)?;
+    let operate_url = format!("http://{}/operate", web.addr());
+    let request = thread::spawn(move || ureq::post(&operate_url).set("Content-Type", "application/json").send_string(&body.to_string()).map(|r| r.status()).map_err(|e| e.to_string()));
+    thread::sleep(Duration::from_millis(200));
+    assert!(ict_relays::controller().is_energized(16));
+    let started = Instant::now();
+    assert!(!web.shutdown(Duration::from_millis(300)));
+    assert!(started.elapsed() < Duration::from_secs(5));
+    assert!(!ict_relays::controller().is_energized(16));
+    assert_eq!(request.join().unwrap(), Ok(200));
+    let _ = std::fs::remove_file(&path);
+    Ok(())
+}
+
+#[test]
+fn test_operate_outside_schedule() -> Result<(), ICTError> {
+    let path = std::env::temp_dir().join(format!("ict_web_{}.db", Uuid::new_v4())).to_string_lossy().to_string();
+    let db = Db::new(&path)?;
+    let client = TestClient::register(&db);
+    authorize(&db, &client.id)?;
+    associate_relay(&db, &client.id, &16)?;
+    let from = (chrono::Utc::now() + chrono::Duration::hours(2)).format("%H:%M").to_string();
+    let until = (chrono::Utc::now() + chrono::Duration::hours(3)).format("%H:%M").to_string();
+    add_schedule(&db, &client.id, None, "mon-sun", &from, &until, "UTC")?;
+
+    let mut settings = load_config("configs/ict_server.toml")?;
+    settings.totp.sha = TotpAlgorithm::Sha256;
+    let live = Arc::new(LiveSettings::new("configs/ict_server.toml", settings));
+    let web = start_web_server(&0, &db, live)?;
+    let response = ureq::post(&format!("http://{}/operate", web.addr()))
+        .set("Content-Type", "application/json")
+        .send_string(&client.operate_body().to_string());
+    match response {
+        Err(ureq::Error::Status(status, response)) => {
+            assert_eq!(status, 403);
+            assert_eq!(response.into_string().unwrap(), "Operate Outside Schedule");
+        }
+        other => panic!("expected a 403, got {:?}", other.map(|r| r.status())),
+    }
+    web.shutdown(Duration::from_secs(5));
+    let _ = std::fs::remove_file(&path);
+    Ok(())
+}
@@ -0,0 +1,132 @@
+mod common;
+
+use chrono::{Duration, Utc};
+use ict_server::{
+    ict_config::{EventKind, Events, TotpAlgorithm, Webhook},
+    ict_db::Db,
+    ict_errors::ICTError,
+    ict_events::{self, backoff, deliver_due, render, signature, Event},
+    ict_operations::{associate_relay, authorize, operate},
+};
+use serde_json::{json, Value};
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use common::TestClient;
+
+// (path, X-ICT-Event, X-ICT-Signature, body) of each request received
+type Received = Arc<Mutex<Vec<(String, String, Option<String>, String)>>>;
+
+// Accepts posts to /ok and fails the ones to /down
+fn start_receiver() -> (String, Received) {
+    let received: Received = Arc::new(Mutex::new(Vec::new()));
+    let log = received.clone();
+    let server = rouille::Server::new("127.0.0.1:0", move |request| {
+        let mut body = String::new();
+        request.data().expect("body").read_to_string(&mut body).expect("UTF-8 body");
+        log.lock().unwrap().push((
+            request.url(),
+            request.header("X-ICT-Event").unwrap_or_default().to_string(),
+            request.header("X-ICT-Signature").map(str::to_string),
+            body,
+        ));
+        match request.url().as_str() {
+            "/ok" => rouille::Response::empty_204(),
+            _ => rouille::Response::text("down").with_status_code(503),
+        }
+    })
+    .expect("receiver");
+    let url = format!("http://{}", server.server_addr());
+    thread::spawn(move || server.run());
+    (url, received)
+}
+
+fn webhook(name: &str, url: String, events: Vec<EventKind>) -> Webhook {
+    Webhook { name: name.to_string(), url, events, secret: None, template: None, max_attempts: 2 }
+}
+
+#[test]
+fn test_render() {
+    let event = json!({ "summary": "door \"front\"", "data": { "relays": [1, 2], "remote_addr": null } });
+    let rendered = render("{\"text\": \"{{summary}} {{ data.relays }}{{data.remote_addr}}{{missing}}\"}", &event);
+    assert_eq!(rendered, "{\"text\": \"door \\\"front\\\" [1,2]\"}");
+    assert_eq!(render("{{summary", &event), "{{summary");
+
+    assert_eq!(backoff(1), Duration::seconds(5));
+    assert_eq!(backoff(3), Duration::seconds(20));
+    assert_eq!(backoff(40), Duration::hours(1));
+}
+
+#[test]
+fn test_webhooks() -> Result<(), ICTError> {
+    let (url, received) = start_receiver();
+    let mut chat = webhook(
+        "chat",
+        format!("{}/ok", url),
+        vec![EventKind::Register, EventKind::Operate, EventKind::OperateFailures],
+    );
+    chat.secret = Some("s3cret".to_string());
+    chat.template = Some("{\"text\": \"{{summary}}\"}".to_string());
+    ict_events::configure(
+        vec![chat, webhook("audit", format!("{}/down", url), vec![EventKind::StatusChange])],
+        Events { failure_threshold: 3, failure_window: 600, ..Events::default() },
+    );
+
+    let db = Db::new_test_db()?;
+    let client = TestClient::register_named(&db, Some("front door"));
+    let id = client.id.clone();
+    authorize(&db, &id)?;
+    associate_relay(&db, &id, &7)?;
+    let (message, signature_base64) = client.signed_message();
+    for _ in 0..3 {
+        assert!(operate(&db, &id, &message, "bm90IHNpZ25lZA==", TotpAlgorithm::Sha256, &1, None).is_err());
+    }
+    operate(&db, &id, &message, &signature_base64, TotpAlgorithm::Sha256, &1, Some("192.0.2.1"))?;
+
+    // nothing is posted before the worker delivers the outbox
+    assert!(received.lock().unwrap().is_empty());
+    assert_eq!(db.get_outbox(None, 100)?.len(), 4);
+    assert_eq!(deliver_due(&db, Utc::now())?, 4);
+
+    let received = received.lock().unwrap().clone();
+    let events: Vec<&str> = received.iter().map(|(_, event, _, _)| event.as_str()).collect();
+    assert_eq!(events, ["register", "status_change", "operate_failures", "operate"]);
+    for (path, _, signed, body) in &received[..] {
+        if path == "/ok" {
+            assert_eq!(signed.as_deref(), Some(signature("s3cret", body).as_str()));
+            assert!(serde_json::from_str::<Value>(body).is_ok(), "{}", body);
+        } else {
+            assert_eq!(signed, &None);
+        }
+    }
+    assert_eq!(received[0].3, format!("{{\"text\": \"front door ({}) registered and waits for review\"}}", id));
+    assert_eq!(received[3].3, format!("{{\"text\": \"front door ({}) operated relays 7\"}}", id));
+    let status_change: Event = serde_json::from_str(&received[1].3).expect("event without a template");
+    assert_eq!(status_change.data, json!({ "from": "pending", "to": "authorized", "reason": null }));
+
+    // the failed delivery waits for its backoff, then gives up after max_attempts
+    let pending = db.get_outbox(None, 100)?;
+    assert_eq!(pending.len(), 1);
+    assert_eq!((pending[0].webhook.as_str(), pending[0].attempts), ("audit", 1));
+    assert!(pending[0].last_error.as_ref().is_some_and(|e| e.contains("503")));
+    assert_eq!(deliver_due(&db, Utc::now())?, 0);
+    assert_eq!(deliver_due(&db, Utc::now() + Duration::minutes(1))?, 1);
+    assert!(db.get_outbox(None, 100)?.is_empty());
+    assert_eq!(db.prune_outbox(Utc::now() + Duration::minutes(1))?, 4);
+
+    // a stopping server does not wait on a webhook that never answers past its deadline
+    let stuck = std::net::TcpListener::bind("127.0.0.1:0")?;
+    ict_events::configure(
+        vec![webhook("stuck", format!("http://{}/", stuck.local_addr()?), vec![EventKind::Register])],
+        Events::default(),
+    );
+    for _ in 0..3 {
+        TestClient::register(&db);
+    }
+    let started = std::time::Instant::now();
+    assert_eq!(ict_events::flush(&db, started + std::time::Duration::from_millis(300))?, 1);
+    assert!(started.elapsed() < std::time::Duration::from_secs(2));
+    assert_eq!(db.get_outbox(None, 100)?.len(), 3);
+    Ok(())
+}